@@ -115,8 +115,10 @@ async function sendPrompt(prompt : string , owner : Principal,id : bigint){
       const output = await response.json();
       console.log("Output from AI : ", JSON.stringify(output));
       const result = await tokenCanister?.store_output(JSON.stringify(output , (_ , v)=> typeof v ==='bigint' ? v.toString() : v),BigInt(id));
-      if(result){
-        console.log("Prompt send and output stored", result);
+      if(result && 'Err' in result){
+        console.log("Failed to store the output : ", result.Err);
+      }else if(result){
+        console.log("Prompt send and output stored");
       }
     }
 
@@ -12,6 +12,7 @@ export async function sendPrompt(prompt: string, owner: Principal, id: bigint) {
 
     if (!response.ok) {
       console.error("❌ Failed to send prompt:", response.statusText);
+      await tokenCanister?.store_output(`Prompt failed: ${response.status} ${response.statusText}`, id, { Failed: null });
       return;
     }
 
@@ -23,7 +24,9 @@ export async function sendPrompt(prompt: string, owner: Principal, id: bigint) {
       id
     );
 
-    if (result) {
+    if (result && "Err" in result) {
+      console.error("❌ Failed to store output for agent", id.toString(), result.Err);
+    } else if (result) {
       console.log("✅ Output stored for agent", id.toString());
     }
   } catch (error) {
@@ -100,7 +100,7 @@ const mint_token_tool = {
     description: "Mints new tokens to the specified account",
     parameters: {
       type: "object",
-      required: ["to", "amount", "symbol", "owner"],
+      required: ["to", "amount", "symbol"],
       properties: {
         to: {
           type: "object",
@@ -126,17 +126,38 @@ const mint_token_tool = {
             "Amount of tokens to mint (passed as a string, will be converted to BigInt)",
         },
         symbol: { type: "string", description: "Symbol of the token" },
-        owner: {
-          type: "string",
-          description: "Principal of the minting account",
-        },
       },
       examples: [
         {
           to: { owner: "w7x7r-cok77-xa", subaccount: null },
           amount: "1000",
           symbol: "ABC",
-          owner: "aaaaa-aa",
+        },
+      ],
+    },
+  },
+};
+
+const burn_token_tool = {
+  type: "function",
+  function: {
+    name: "icrc2_burn",
+    description: "Burns tokens from the caller's account, reducing the total supply",
+    parameters: {
+      type: "object",
+      required: ["amount", "symbol"],
+      properties: {
+        amount: {
+          type: "string",
+          description:
+            "Amount of tokens to burn (passed as a string, will be converted to BigInt)",
+        },
+        symbol: { type: "string", description: "Symbol of the token" },
+      },
+      examples: [
+        {
+          amount: "500",
+          symbol: "ABC",
         },
       ],
     },
@@ -261,6 +282,7 @@ export async function runTokenCanisterTool(
     get_token_metadata: tokenCanister?.get_token_metadata.bind(tokenCanister),
     get_all_tokens: tokenCanister?.get_all_tokens.bind(tokenCanister),
     icrc2_mint: tokenCanister?.mint_token.bind(tokenCanister),
+    icrc2_burn: tokenCanister?.burn_token.bind(tokenCanister),
     icrc2_transfer: tokenCanister?.transfer_token.bind(tokenCanister),
     icrc2_balance_of: tokenCanister?.icrc2_balance_of.bind(tokenCanister),
   };
@@ -336,6 +358,14 @@ Available Tools :
     - "Check my balance of GLD"
     - "How many MTK tokens does principal abcde-12345 have?"
     - "Get balance of USDT for my account"
+6. icrc2_burn
+  • Description: Burns tokens from the caller's account, reducing the total supply. Burns are fee-free but must be at least the token's transfer fee.
+  • Arguments:
+    - amount (nat as string): The amount of tokens to burn.
+    - symbol (string): The symbol of the token.
+  • Example prompts:
+    - "Burn 500 GLD"
+    - "Destroy 1000 of my MTK tokens"
 
 Whenever a user asks something, explain which tool you'll use and show them how it works with their input before executing.
 `,
@@ -347,6 +377,7 @@ Whenever a user asks something, explain which tool you'll use and show them how
       token_metadata_tool,
       get_all_tokens_tool,
       mint_token_tool,
+      burn_token_tool,
       transfer_token_tool,
       balance_tool,
     ],
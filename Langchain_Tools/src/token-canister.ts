@@ -1,21 +1,22 @@
 import { Actor, ActorSubclass, HttpAgent } from "@dfinity/agent";
 import {idlFactory} from "../../src/declarations/ai_agent_icp_backend/index.js";
 import { Principal } from "@dfinity/principal";
-import { Account, AgentSchedule, APIResponse, BalanceTokenArgs, CreateTokenArgs, GetAllAgentsResponse, GetTokenMetadataArgs, MintTokenArgs, UserAgents } from "./types/tool-types.js";
+import { Account, AgentConfig, AgentSchedule, ApiResult, BalanceTokenArgs, CreateTokenArgs, GetAllAgentsResponse, BurnTokenArgs, GetTokenMetadataArgs, MintTokenArgs, OutputStatus, TransferTokenArgs, UserAgents } from "./types/tool-types.js";
 
 
 
 
 export interface TokenCanister {
-  icrc2_init : (name : string , symbol : string , decimals : number ,description : [string] | [],logo : [string] | [] , total_supply : bigint, owner : Principal,fee : bigint)=>Promise<boolean>;
-  icrc2_metadata :(symbol : string) =>Promise<APIResponse>;
-  icrc2_get_all_records : () => Promise<APIResponse>;
-  icrc2_mint: (to: { owner: Principal; subaccount: [] | [Uint8Array]}, amount: bigint, symbol: string,owner : Principal) => Promise<APIResponse>;
-  create_agent : (name : string , description : string ,schedule : AgentSchedule ,  created_at : number , prompt : string ,owner : Principal ) =>Promise<string>;
+  icrc2_init : (name : string , symbol : string , decimals : number ,description : [string] | [],logo : [string] | [] , total_supply : bigint, owner : Principal,fee : bigint)=>Promise<ApiResult<bigint>>;
+  icrc2_metadata :(symbol : string) =>Promise<ApiResult<[string, string][]>>;
+  icrc2_get_all_records : () => Promise<[string, string][]>;
+  icrc2_mint: (to: { owner: Principal; subaccount: [] | [Uint8Array]}, amount: bigint, symbol: string) => Promise<ApiResult<bigint>>;
+  icrc2_burn: (symbol: string, args: { from_subaccount: [] | [Uint8Array]; amount: bigint; memo: [] | [Uint8Array]; created_at_time: [] | [bigint] }) => Promise<ApiResult<bigint>>;
+  create_agent : (name : string , description : string ,schedule : AgentSchedule ,  created_at : number , prompt : string ,owner : Principal ) =>Promise<ApiResult<AgentConfig>>;
   get_all_agents : ()=> Promise<GetAllAgentsResponse | undefined>;
-  transfer_token: (tokenId: string, to: Principal, amount: bigint) => Promise<boolean>;
-  icrc2_balance_of: (account: Account, symbol: string) => Promise<bigint>;
-  store_output : (output : string , id : bigint)=>Promise<string>;
+  icrc2_transfer: (symbol: string, args: { from_subaccount: [] | [Uint8Array]; to: { owner: Principal; subaccount: [] | [Uint8Array] }; amount: bigint; fee: [] | [bigint]; memo: [] | [Uint8Array]; created_at_time: [] | [bigint] }) => Promise<ApiResult<bigint>>;
+  icrc2_balance_of: (account: Account, symbol: string) => Promise<ApiResult<bigint>>;
+  store_output : (output : string , id : bigint, status : [] | [OutputStatus])=>Promise<ApiResult<null>>;
   get_user_agents : (owner : Principal)=>Promise<UserAgents | undefined>;
 }
 
@@ -49,7 +50,7 @@ export class TokenCanisterClient{
     async create_token(args : CreateTokenArgs){
         if(args.schedule?.type === 'Interval' || args.schedule?.type === 'Cron'){
              
-            const agent = this.actor.create_agent(
+            const agent = await this.actor.create_agent(
                 "Create Token Agent",
                 "This agent is used to create tokens and schedule token creation on regular intervals",
                 args.schedule.type==='Interval' ? { Interval : {interval_seconds : BigInt(args.schedule.interval_seconds)}} : {Cron : {expression : args.schedule.expression}},
@@ -58,7 +59,11 @@ export class TokenCanisterClient{
                 `Create token with name ${args.name}, symbol ${args.symbol}, decimals ${args.decimals}, description ${args.description}, logo ${args.logo}, total supply ${args.initial_supply}, owner ${args.owner} and fee ${args.fee} `,
                Principal.fromText(args.owner)
             );
-            console.log("Created agent for token creation", agent);
+            if('Ok' in agent){
+              console.log(`Created agent ${agent.Ok.public_id} for token creation`);
+            }else{
+              console.log("Failed to create agent for token creation", agent.Err);
+            }
 
         }
         return await this.actor.icrc2_init(args.name , args.symbol,args.decimals,args.description ? [args.description] : [],args.logo? [args.logo] : [],BigInt(args.initial_supply),Principal.fromText(args.owner),BigInt(args.fee) , );
@@ -76,8 +81,10 @@ export class TokenCanisterClient{
                 `Get the details of token having symbol ${args.symbol}`,
                Principal.fromText(args.owner)
             );
-        if(agent){
-          console.log("Created agent for token metadata retrieval", agent);
+        if('Ok' in agent){
+          console.log(`Created agent ${agent.Ok.public_id} for token metadata retrieval`);
+        }else{
+          console.log("Failed to create agent for token metadata retrieval", agent.Err);
         }
       }
         return await this.actor.icrc2_metadata(args.symbol);
@@ -86,8 +93,19 @@ export class TokenCanisterClient{
     async get_all_tokens(){
         return await this.actor.icrc2_get_all_records();
     }
-    async transfer_token(tokenId: string, to: Principal, amount: bigint): Promise<boolean> {
-        return await this.actor.transfer_token(tokenId, to, amount);
+    async transfer_token(args : TransferTokenArgs) {
+      const to: { owner: Principal; subaccount: [] | [Uint8Array] } = {
+        owner: typeof args.to.owner === 'string' ? Principal.fromText(args.to.owner) : args.to.owner,
+        subaccount: args.to.subaccount ? [Uint8Array.from(args.to.subaccount)] : [],
+      };
+      return await this.actor.icrc2_transfer(args.symbol, {
+        from_subaccount: [],
+        to,
+        amount: BigInt(args.amount),
+        fee: [],
+        memo: [],
+        created_at_time: [],
+      });
     }
     async mint_token(args : MintTokenArgs) {
       if(args.to?.subaccount && Array.isArray(args.to.subaccount)){
@@ -102,9 +120,17 @@ export class TokenCanisterClient{
         owner: args.to.owner,
         subaccount: args.to.subaccount ? [args.to.subaccount] as [Uint8Array] : [],
     };
-    return await this.actor.icrc2_mint(formattedTo, BigInt(args.amount), args.symbol,Principal.fromText(args.owner));
+    return await this.actor.icrc2_mint(formattedTo, BigInt(args.amount), args.symbol);
 }
-    async create_agent(name : string , description : string ,schedule : AgentSchedule ,  created_at : number ,prompt : string,owner : Principal) : Promise<string>{
+    async burn_token(args : BurnTokenArgs) {
+      return await this.actor.icrc2_burn(args.symbol, {
+        from_subaccount: [],
+        amount: BigInt(args.amount),
+        memo: [],
+        created_at_time: [],
+      });
+    }
+    async create_agent(name : string , description : string ,schedule : AgentSchedule ,  created_at : number ,prompt : string,owner : Principal) : Promise<ApiResult<AgentConfig>>{
         return await this.actor.create_agent(name , description ,schedule  , created_at , prompt, owner );
     }
 
@@ -112,7 +138,7 @@ export class TokenCanisterClient{
         return await this.actor.get_all_agents();
     }
 
-    async icrc2_balance_of(args: BalanceTokenArgs): Promise<bigint> {
+    async icrc2_balance_of(args: BalanceTokenArgs): Promise<ApiResult<bigint>> {
   const principal = Principal.fromText(args.owner);
 
   let subaccount: Uint8Array | null = null;
@@ -134,8 +160,8 @@ export class TokenCanisterClient{
 }
 
 
-    async store_output(output : string , id : bigint ){
-      return await this.actor.store_output(output,id);
+    async store_output(output : string , id : bigint, status? : OutputStatus){
+      return await this.actor.store_output(output,id, status ? [status] : []);
     }
 
 
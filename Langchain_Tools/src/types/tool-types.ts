@@ -4,7 +4,17 @@ export interface MintTokenArgs{
   to : Account;
   amount : string;
   symbol : string;
-  owner : string;
+}
+
+export interface TransferTokenArgs{
+  to : Account;
+  amount : string;
+  symbol : string;
+}
+
+export interface BurnTokenArgs{
+  amount : string;
+  symbol : string;
 }
 
 export interface BalanceTokenArgs {
@@ -39,24 +49,52 @@ export interface CreateTokenArgs{
 export type Schedule = 
   | { type: 'Interval'; interval_seconds: number }
   | { type: 'Cron'; expression: string };
-export interface APIResponse {
-  Text : string;
-  PairList : [string, string];
-}
+// Error half of every fallible canister endpoint (`ApiError` in token.did)
+export type ApiError =
+  | { NotFound: string }
+  | { Unauthorized: string }
+  | { AlreadyExists: string }
+  | { InvalidArgument: string }
+  | { QuotaExceeded: string }
+  | { InvalidState: string }
+  | { CallFailed: string }
+  | { Transfer: any }
+  | { Moved: Principal }; // the token now lives in this ledger canister
+
+export type ApiResult<T> = { Ok: T } | { Err: ApiError };
 
 export interface Account {
   owner: Principal;
   subaccount?: Uint8Array | null;
 }
 export interface AgentConfig {
+  agent_id : bigint;
+  public_id : string;
   name: string;
   description: string;
   schedule: AgentSchedule;
   created_at: bigint; 
-  prompts : string;
+  prompt : string;
   owner : Principal;
+  outputs : Outputs[];
+  next_run_id : bigint;
+  last_triggered : [] | [bigint];
+  status : AgentStatus;
 }
 
+export type OutputStatus = { Succeeded: null } | { Failed: null };
+
+// One stored run result; the content is fetched with get_outputs(content_hash)
+export interface Outputs {
+  run_id : bigint;
+  timestamp : bigint;
+  status : OutputStatus;
+  content_hash : string;
+}
+
+// Only Active agents are returned by get_all_agents
+export type AgentStatus = { Active: null } | { Paused: null } | { Archived: null };
+
 export type AgentSchedule = 
   | { Interval: { interval_seconds: bigint } }
   | { Cron: { expression: string } };
@@ -0,0 +1,88 @@
+use candid::CandidType;
+use ic_cdk::api::{accept_message, msg_arg_data, msg_caller, msg_method_name};
+use ic_cdk_macros::inspect_message;
+use serde::{Deserialize, Serialize};
+
+// What a principal may do on an agent besides its owner
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub enum Role {
+    Owner,    // everything, including managing roles and retiring the agent
+    Operator, // manages and runs tasks, except for setting up token tasks
+    Viewer,   // reads tasks and their runs
+    Executor, // service principal that only runs due tasks
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    View,
+    Manage,
+    Execute,
+    Administer,
+}
+
+impl Role {
+    pub fn allows(self, permission: Permission) -> bool {
+        match self {
+            Role::Owner => true,
+            Role::Operator => permission != Permission::Administer,
+            Role::Executor => matches!(permission, Permission::View | Permission::Execute),
+            Role::Viewer => permission == Permission::View,
+        }
+    }
+}
+
+// Which agent an endpoint's first argument points at
+enum Target {
+    Agent,
+    Task,
+    AgentConfig, // agent::AGENTS, which may have no task queue yet
+    Output,      // store_output, whose agent is its second argument
+}
+
+// Update endpoints that act on an agent, with what the caller needs for them
+fn required_permission(method: &str) -> Option<(Target, Permission)> {
+    let required = match method {
+        // Token tasks take Administer; endpoints that accept any kind of task check that themselves
+        "create_task" | "create_task_complete" | "create_token_operation_task" => (Target::Agent, Permission::Manage),
+        "create_token_init_task" | "create_token_transfer_task" | "create_token_mint_task" | "create_token_burn_task" => {
+            (Target::Agent, Permission::Administer)
+        }
+        "update_task" | "delete_task" | "set_task_cron" | "set_task_retry_policy" | "retry_dead_letter_task" => {
+            (Target::Task, Permission::Manage)
+        }
+        "execute_tasks" => (Target::Agent, Permission::Execute),
+        "retire_agent" | "add_controller_role" | "revoke_role" => (Target::Agent, Permission::Administer),
+        "update_agent" | "pause_agent" | "resume_agent" => (Target::AgentConfig, Permission::Manage),
+        "delete_agent" => (Target::AgentConfig, Permission::Administer),
+        "store_output" => (Target::Output, Permission::Execute),
+        _ => return None,
+    };
+    Some(required)
+}
+
+// Drops unauthorized ingress to agent endpoints before it is executed (and paid for).
+// The endpoints check again, as inspection doesn't run for inter-canister calls.
+#[inspect_message]
+fn inspect_message() {
+    let allowed = match required_permission(&msg_method_name()) {
+        None => true,
+        Some((target, permission)) => {
+            let arg = msg_arg_data();
+            let id = candid::de::IDLDeserialize::new(&arg).and_then(|mut de| match target {
+                Target::Output => de.get_value::<String>().and_then(|_| de.get_value::<u64>()),
+                _ => de.get_value::<u64>(),
+            });
+            match (id, target) {
+                (Ok(agent_id), Target::Agent) => crate::agent_core::has_permission(agent_id, msg_caller(), permission),
+                (Ok(task_id), Target::Task) => crate::agent_core::agent_of_task(task_id)
+                    .is_some_and(|agent_id| crate::agent_core::has_permission(agent_id, msg_caller(), permission)),
+                (Ok(agent_id), Target::AgentConfig) => crate::agent::has_permission(agent_id, msg_caller(), permission),
+                (Ok(agent_id), Target::Output) => crate::agent::may_store_output(agent_id, msg_caller()),
+                (Err(_), _) => false,
+            }
+        }
+    };
+    if allowed {
+        accept_message();
+    }
+}
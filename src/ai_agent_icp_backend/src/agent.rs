@@ -1,65 +1,422 @@
-use std::{cell::RefCell, collections::{BTreeMap, HashMap}};
+use std::{cell::RefCell, collections::{BTreeMap, BTreeSet, HashMap, HashSet}};
 
 use candid::{CandidType, Principal};
-use ic_cdk::{query, update};
+use ic_cdk::{api::{is_controller, msg_caller}, query, update};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};   
+use sha2::{Digest, Sha256};
 
-use crate::agent_config::{AgentConfig, Schedule};
+use crate::access::Permission;
+use crate::agent_config::{AgentConfig, AgentStatus, OutputStatus, Outputs, Schedule};
+use crate::error::ApiError;
+use crate::scheduler::Job;
 
+const MAX_OUTPUT_PAGE_SIZE : u32 = 100;
+const MAX_OUTPUT_BYTES : u64 = 1024 * 1024; // ceiling for OutputLimits::max_output_bytes
+const MAX_TOTAL_OUTPUT_BYTES : u64 = 2 * 1024 * 1024 * 1024; // ceiling for OutputLimits::max_total_bytes
+const MAX_AGENTS_PER_OWNER : usize = 100;
+const MAX_OUTPUTS_PER_AGENT : u32 = 10_000;
+const OUTPUT_PRUNING_INTERVAL_SECS : u64 = 60 * 60;
 
-#[derive(Clone,CandidType,Deserialize,Serialize)]  
-pub struct Output(String);
+// Caps on what store_output keeps on the heap, set by controllers
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct OutputLimits {
+    pub max_output_bytes : u64,    // size of a single output
+    pub max_bytes_per_agent : u64, // distinct output contents an agent's records point at
+    // Same, across every agent of one owner
+    #[serde(default = "default_max_bytes_per_owner")]
+    pub max_bytes_per_owner : u64,
+    // Every output content in the canister
+    #[serde(default = "default_max_total_bytes")]
+    pub max_total_bytes : u64,
+}
+
+fn default_max_bytes_per_owner() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_max_total_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+impl Default for OutputLimits {
+    fn default() -> Self {
+        OutputLimits {
+            max_output_bytes : 64 * 1024,
+            max_bytes_per_agent : 4 * 1024 * 1024,
+            max_bytes_per_owner : default_max_bytes_per_owner(),
+            max_total_bytes : default_max_total_bytes(),
+        }
+    }
+}
+
+// How many output records each agent keeps; content nothing points at any more is freed
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, PartialEq)]
+pub struct OutputRetention {
+    pub max_outputs_per_agent : u32,
+    pub max_age_seconds : Option<u64>,
+}
+
+impl Default for OutputRetention {
+    fn default() -> Self {
+        OutputRetention { max_outputs_per_agent : 1_000, max_age_seconds : None }
+    }
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct OutputUsage {
+    pub outputs : u64, // output records
+    pub bytes : u64,   // distinct contents they point at
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct OutputStats {
+    pub blobs : u64,
+    pub stored_bytes : u64, // every content once, however many records share it
+    pub per_agent : BTreeMap<u64, OutputUsage>,
+    pub per_owner : BTreeMap<Principal, OutputUsage>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct OutputPage {
+    pub outputs : Vec<Outputs>,   // newest first
+    pub next_cursor : Option<u64>, // pass back to get the following (older) page
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AgentOutput {
+    pub output : Outputs,
+    pub content : String,
+}
 
 thread_local! {
-    static OUTPUTS : RefCell<HashMap<String,String>> = RefCell::new(HashMap::new());
-    static AGENTS : RefCell<BTreeMap<u64, AgentConfig>> = RefCell::new(BTreeMap::new());
-    static USER_AGENTS : RefCell<BTreeMap<Principal,Vec<u64>>> = RefCell::new(BTreeMap::new());
+    static AGENTS : RefCell<BTreeMap<u64, AgentConfig>> = const { RefCell::new(BTreeMap::new()) };
+    static USER_AGENTS : RefCell<BTreeMap<Principal,Vec<u64>>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_AGENT_ID : RefCell<u64> = const { RefCell::new(0) };
+    static OUTPUT_LIMITS : RefCell<OutputLimits> = RefCell::new(OutputLimits::default());
+    static OUTPUT_RETENTION : RefCell<OutputRetention> = RefCell::new(OutputRetention::default());
+    // Service principals (e.g. the off-chain scheduler) that may store outputs for any agent
+    static EXECUTORS : RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
 }
 
+#[derive(Default, Serialize, Deserialize)]
+pub struct StableAgents {
+    #[serde(default)]
+    pub agents : BTreeMap<u64, AgentConfig>,
+    #[serde(default)]
+    pub user_agents : BTreeMap<Principal, Vec<u64>>,
+    // Output contents of snapshots written before the blob store; read only
+    #[serde(default, skip_serializing)]
+    pub outputs : HashMap<String, String>,
+    #[serde(default)]
+    pub blobs : BTreeMap<String, crate::output_store::Blob>,
+    #[serde(default)]
+    pub next_agent_id : u64,
+    #[serde(default)]
+    pub output_limits : OutputLimits,
+    #[serde(default)]
+    pub executors : BTreeSet<Principal>,
+    #[serde(default)]
+    pub output_retention : OutputRetention,
+}
 
-#[update]
-pub fn create_agent(name : String , description : String , schedule :Schedule,created_at : i128,prompt : String, owner : Principal) -> String{
-    let agent_id = AGENTS.with(|agents| {
-        let agents = agents.borrow();
-        let mut id = agents.len() as u64;
-        // Ensure no collision (e.g. after deletion)
-        while agents.contains_key(&id) {
-            id += 1;
+pub(crate) fn take_stable_state() -> StableAgents {
+    StableAgents {
+        agents : AGENTS.with(|agents| std::mem::take(&mut *agents.borrow_mut())),
+        user_agents : USER_AGENTS.with(|user_agents| std::mem::take(&mut *user_agents.borrow_mut())),
+        outputs : HashMap::new(),
+        blobs : crate::output_store::take_stable_state(),
+        next_agent_id : NEXT_AGENT_ID.with(|next| std::mem::take(&mut *next.borrow_mut())),
+        output_limits : OUTPUT_LIMITS.with(|limits| std::mem::take(&mut *limits.borrow_mut())),
+        executors : EXECUTORS.with(|executors| std::mem::take(&mut *executors.borrow_mut())),
+        output_retention : OUTPUT_RETENTION.with(|retention| std::mem::take(&mut *retention.borrow_mut())),
+    }
+}
+
+pub(crate) fn restore_stable_state(mut state : StableAgents) {
+    // Snapshots written before the counter was persisted only have the agents to go by
+    let after_last = state.agents.keys().next_back().map_or(0, |id| id + 1);
+    NEXT_AGENT_ID.with(|next| *next.borrow_mut() = state.next_agent_id.max(after_last));
+    for agent in state.agents.values_mut() {
+        if agent.public_id.is_empty() {
+            agent.public_id = public_id(agent.agent_id, agent.owner);
         }
-        id
+        agent.next_run_id = agent.next_run_id.max(agent.outputs.last().map_or(0, |output| output.run_id));
+    }
+    let mut refs : HashMap<String, u64> = HashMap::new();
+    for output in state.agents.values().flat_map(|agent| agent.outputs.iter()) {
+        *refs.entry(output.content_hash.clone()).or_default() += 1;
+    }
+    crate::output_store::restore_stable_state(state.blobs, state.outputs, refs);
+    AGENTS.with(|agents| *agents.borrow_mut() = state.agents);
+    USER_AGENTS.with(|user_agents| *user_agents.borrow_mut() = state.user_agents);
+    OUTPUT_LIMITS.with(|limits| *limits.borrow_mut() = state.output_limits);
+    EXECUTORS.with(|executors| *executors.borrow_mut() = state.executors);
+    OUTPUT_RETENTION.with(|retention| *retention.borrow_mut() = state.output_retention);
+}
+
+
+// The owner creates its own agents; executors and controllers can create them for anyone
+#[update]
+pub fn create_agent(name : String , description : String , schedule :Schedule,created_at : i128,prompt : String, owner : Principal) -> Result<AgentConfig, ApiError>{
+    let caller = msg_caller();
+    if owner == Principal::anonymous() {
+        return Err(ApiError::InvalidArgument("The anonymous principal can't own an agent".to_string()));
+    }
+    if caller != owner && !EXECUTORS.with(|executors| executors.borrow().contains(&caller)) && !is_controller(&caller) {
+        return Err(ApiError::Unauthorized("Agents can only be created by their owner".to_string()));
+    }
+    schedule.validate().map_err(ApiError::InvalidArgument)?;
+    let owned = USER_AGENTS.with(|user_agents| user_agents.borrow().get(&owner).map_or(0, Vec::len));
+    if owned >= MAX_AGENTS_PER_OWNER {
+        return Err(ApiError::QuotaExceeded(format!("{} already owns {} agents", owner, MAX_AGENTS_PER_OWNER)));
+    }
+    let agent_id = next_agent_id();
+    let agent = AgentConfig {
+        agent_id,
+        public_id : public_id(agent_id, owner),
+        name,
+        description,
+        owner,
+        schedule,
+        created_at,
+        prompt,
+        outputs : Vec::new(),
+        next_run_id : 0,
+        last_triggered : None,
+        status : AgentStatus::Active,
+    };
+    AGENTS.with(|agents| agents.borrow_mut().insert(agent_id, agent.clone()));
+    USER_AGENTS.with(|user_agents|{
+        let mut user_agents = user_agents.borrow_mut();
+        user_agents.entry(owner).or_default().push(agent_id);
     });
+    crate::scheduler::schedule(Job::Agent(agent_id), agent.schedule.next_after(now()));
+    Ok(agent)
+}
+
+// IDs only ever go up, so the ID of a deleted agent never points at a newer one
+fn next_agent_id() -> u64 {
+    NEXT_AGENT_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        let id = *next;
+        *next += 1;
+        id
+    })
+}
+
+// Short ID to hand out instead of the sequential one; it doesn't reveal how many
+// agents exist and stays the same for the life of the agent
+fn public_id(agent_id : u64, owner : Principal) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"agent");
+    hasher.update(agent_id.to_be_bytes());
+    hasher.update(owner.as_slice());
+    hex::encode(&hasher.finalize()[..8])
+}
+
+#[query]
+pub fn get_agent_by_public_id(public_id : String) -> Result<AgentConfig, ApiError> {
     AGENTS.with(|agents| {
-        let mut agents = agents.borrow_mut();
-        
-        if agents.contains_key(&agent_id){
-            return format!("Agent with ID {} already exists", agent_id);
+        agents.borrow().values().find(|agent| agent.public_id == public_id).cloned()
+    }).ok_or_else(|| ApiError::NotFound(format!("No agent with public ID {}", public_id)))
+}
+
+pub(crate) fn owner_of(agent_id : u64) -> Option<Principal> {
+    AGENTS.with(|agents| agents.borrow().get(&agent_id).map(|agent| agent.owner))
+}
+
+// The agent's owner, or whoever holds a role on its task queue that grants `permission`
+pub(crate) fn has_permission(agent_id : u64, caller : Principal, permission : Permission) -> bool {
+    caller != Principal::anonymous()
+        && (owner_of(agent_id) == Some(caller) || crate::agent_core::has_permission(agent_id, caller, permission))
+}
+
+fn authorize(agent_id : u64, permission : Permission) -> Result<(), ApiError> {
+    if owner_of(agent_id).is_none() {
+        return Err(ApiError::NotFound(format!("Agent {} does not exist", agent_id)));
+    }
+    if !has_permission(agent_id, msg_caller(), permission) {
+        return Err(ApiError::Unauthorized("Not authorized".to_string()));
+    }
+    Ok(())
+}
+
+fn with_agent<R>(agent_id : u64, f : impl FnOnce(&mut AgentConfig) -> Result<R, ApiError>) -> Result<R, ApiError> {
+    AGENTS.with(|agents| match agents.borrow_mut().get_mut(&agent_id) {
+        Some(agent) => f(agent),
+        None => Err(ApiError::NotFound(format!("Agent {} does not exist", agent_id))),
+    })
+}
+
+// Schedules the agent's next run after `after`, or takes it off the schedule unless it is active
+fn reschedule_agent(agent : &AgentConfig, after : u64) {
+    let due = match agent.status {
+        AgentStatus::Active => agent.schedule.next_after(after),
+        AgentStatus::Paused | AgentStatus::Archived => None,
+    };
+    crate::scheduler::schedule(Job::Agent(agent.agent_id), due);
+}
+
+// Changes the given settings of an agent and keeps the rest
+#[update]
+pub fn update_agent(agent_id : u64, name : Option<String>, description : Option<String>, schedule : Option<Schedule>, prompt : Option<String>) -> Result<AgentConfig, ApiError> {
+    authorize(agent_id, Permission::Manage)?;
+    if let Some(schedule) = &schedule {
+        schedule.validate().map_err(ApiError::InvalidArgument)?;
+    }
+    let agent = with_agent(agent_id, |agent| {
+        if agent.status == AgentStatus::Archived {
+            return Err(ApiError::InvalidState(format!("Agent {} is archived", agent_id)));
         }
-        agents.insert(agent_id, 
-            AgentConfig { 
-            agent_id, 
-            name: name.clone(), 
-            description: description.clone(), 
-            owner: owner, 
-            schedule: schedule.clone(), 
-            created_at: created_at.clone(), 
-            prompt: prompt.clone(),
+        if let Some(name) = name {
+            agent.name = name;
+        }
+        if let Some(description) = description {
+            agent.description = description;
+        }
+        if let Some(schedule) = schedule {
+            agent.schedule = schedule;
+        }
+        if let Some(prompt) = prompt {
+            agent.prompt = prompt;
+        }
+        Ok(agent.clone())
+    })?;
+    reschedule_agent(&agent, agent.last_triggered.unwrap_or(now()));
+    Ok(agent)
+}
+
+// Stops the agent's schedule and its task queue until `resume_agent`
+#[update]
+pub fn pause_agent(agent_id : u64) -> Result<(), ApiError> {
+    authorize(agent_id, Permission::Manage)?;
+    let agent = with_agent(agent_id, |agent| {
+        if agent.status != AgentStatus::Active {
+            return Err(ApiError::InvalidState(format!("Agent {} is not active", agent_id)));
+        }
+        agent.status = AgentStatus::Paused;
+        Ok(agent.clone())
+    })?;
+    reschedule_agent(&agent, now());
+    crate::agent_core::set_agent_active(agent_id, false);
+    Ok(())
+}
+
+// Puts a paused agent back on its schedule. Runs missed while paused are skipped.
+#[update]
+pub fn resume_agent(agent_id : u64) -> Result<(), ApiError> {
+    authorize(agent_id, Permission::Manage)?;
+    let agent = with_agent(agent_id, |agent| {
+        if agent.status != AgentStatus::Paused {
+            return Err(ApiError::InvalidState(format!("Agent {} is not paused", agent_id)));
+        }
+        agent.status = AgentStatus::Active;
+        Ok(agent.clone())
+    })?;
+    reschedule_agent(&agent, now());
+    crate::agent_core::set_agent_active(agent_id, true);
+    Ok(())
+}
+
+// Removes the agent with its task queue and every output no other agent also produced
+#[update]
+pub fn delete_agent(agent_id : u64) -> Result<(), ApiError> {
+    authorize(agent_id, Permission::Administer)?;
+    let Some(agent) = AGENTS.with(|agents| agents.borrow_mut().remove(&agent_id)) else {
+        return Err(ApiError::NotFound(format!("Agent {} does not exist", agent_id)));
+    };
+    USER_AGENTS.with(|user_agents| {
+        let mut user_agents = user_agents.borrow_mut();
+        if let Some(agent_ids) = user_agents.get_mut(&agent.owner) {
+            agent_ids.retain(|id| *id != agent_id);
+            if agent_ids.is_empty() {
+                user_agents.remove(&agent.owner);
+            }
+        }
+    });
+    for output in &agent.outputs {
+        crate::output_store::release(&output.content_hash);
+    }
+    crate::scheduler::schedule(Job::Agent(agent_id), None);
+    crate::agent_core::remove_task_queue(agent_id);
+    ic_cdk::api::debug_print(format!("Agent {} deleted", agent_id));
+    Ok(())
+}
+
+// Called once the agent's task queue is retired
+pub(crate) fn archive_agent(agent_id : u64) {
+    let archived = with_agent(agent_id, |agent| {
+        agent.status = AgentStatus::Archived;
+        Ok(agent.clone())
+    });
+    if let Ok(agent) = archived {
+        reschedule_agent(&agent, now());
+    }
+}
+
+// Registers the task agent of a pre-multi-tenant snapshot, which had no AgentConfig
+pub(crate) fn register_legacy_agent(owner : Principal, created_at : u64) -> u64 {
+    let agent_id = next_agent_id();
+    AGENTS.with(|agents| {
+        agents.borrow_mut().insert(agent_id, AgentConfig {
+            agent_id,
+            public_id : public_id(agent_id, owner),
+            name : "Task agent".to_string(),
+            description : "Task queue migrated from the single canister-wide agent".to_string(),
+            owner,
+            schedule : Schedule::Interval { interval_seconds : 0 },
+            created_at : created_at as i128 * 1000,
+            prompt : String::new(),
             outputs : Vec::new(),
-           });
-        USER_AGENTS.with(|user_agents|{
-            let mut user_agents = user_agents.borrow_mut();
-            user_agents.entry(owner).or_default().push(agent_id);
+            next_run_id : 0,
+            last_triggered : None,
+            status : AgentStatus::Active,
         });
+    });
+    USER_AGENTS.with(|user_agents| user_agents.borrow_mut().entry(owner).or_default().push(agent_id));
+    agent_id
+}
+
+fn now() -> u64 {
+    ic_cdk::api::time() / 1_000_000_000
+}
 
-        "agent created successfully".to_string()
+// Re-arms every agent schedule, e.g. after an upgrade
+pub(crate) fn reschedule_agents() {
+    let now = now();
+    let entries: Vec<(Job, Option<u64>)> = AGENTS.with(|agents| {
+        agents
+            .borrow()
+            .values()
+            .filter(|agent| agent.status == AgentStatus::Active)
+            .map(|agent| (Job::Agent(agent.agent_id), agent.schedule.next_after(agent.last_triggered.unwrap_or(now))))
+            .collect()
+    });
+    crate::scheduler::reset(|job| matches!(job, Job::Agent(_)), entries);
+}
+
+// Called by the scheduler when an agent's schedule fires. Returns when it fires next.
+pub(crate) fn run_scheduled_agent(agent_id : u64, now : u64) -> Option<u64> {
+    AGENTS.with(|agents| {
+        let mut agents = agents.borrow_mut();
+        let agent = agents.get_mut(&agent_id).filter(|agent| agent.status == AgentStatus::Active)?;
+        agent.last_triggered = Some(now);
+        ic_cdk::api::debug_print(format!("Agent {} triggered at {}", agent_id, now));
+        agent.schedule.next_after(now)
     })
 }
 
 
+// Agents the off-chain scheduler should run, i.e. the active ones
 #[query]
 pub fn get_all_agents()-> BTreeMap<u64, AgentConfig> {
     AGENTS.with(|agents| {
-        agents.borrow().clone()
+        agents
+            .borrow()
+            .iter()
+            .filter(|(_, agent)| agent.status == AgentStatus::Active)
+            .map(|(id, agent)| (*id, agent.clone()))
+            .collect()
     })
 }
 
@@ -82,42 +439,332 @@ pub fn get_user_agents(owner : Principal) -> Vec<AgentConfig> {
     })
 }
 
+// Owners, principals with the Execute permission on the agent and registered executors
+pub(crate) fn may_store_output(agent_id : u64, caller : Principal) -> bool {
+    has_permission(agent_id, caller, Permission::Execute)
+        || (owner_of(agent_id).is_some() && EXECUTORS.with(|executors| executors.borrow().contains(&caller)))
+}
 
-#[update]
-pub fn store_output(output : String , id : u64)-> String{
-    let h = hash_output(&output);
-    OUTPUTS.with(|outputs|{
-        let mut outputs = outputs.borrow_mut();
-        outputs.insert(h.clone(), output)
+// Bytes of the given distinct contents
+fn bytes_of<'a>(hashes : impl IntoIterator<Item = &'a String>) -> u64 {
+    hashes.into_iter().filter_map(|hash| crate::output_store::size_of(hash)).sum()
+}
+
+// Bytes of the distinct contents the agent's output records point at
+fn stored_bytes(agent : &AgentConfig) -> u64 {
+    bytes_of(agent.outputs.iter().map(|output| &output.content_hash).collect::<HashSet<_>>())
+}
+
+// Output content is readable by whoever may view an agent whose output records point at it
+pub(crate) fn may_view_output(hash : &str, caller : Principal) -> bool {
+    let agent_ids : Vec<u64> = AGENTS.with(|agents| {
+        agents
+            .borrow()
+            .values()
+            .filter(|agent| agent.outputs.iter().any(|output| output.content_hash == hash))
+            .map(|agent| agent.agent_id)
+            .collect()
     });
+    agent_ids.into_iter().any(|agent_id| has_permission(agent_id, caller, Permission::View))
+}
 
-    AGENTS.with(|agent|{
+// Forbidden content looks the same as missing content, so hashes can't be used to
+// find out whether something was stored
+pub(crate) fn authorize_output(hash : &str) -> Result<(), ApiError> {
+    if !may_view_output(hash, msg_caller()) {
+        return Err(ApiError::NotFound(format!("No output with hash {}", hash)));
+    }
+    Ok(())
+}
 
-        if let Some(my_agent) = agent.borrow_mut().get_mut(&id){
-            if !my_agent.outputs.contains(&h){
+// Contents the output records of any of the owner's agents point at
+fn owner_hashes(owner : Principal) -> HashSet<String> {
+    AGENTS.with(|agents| {
+        agents
+            .borrow()
+            .values()
+            .filter(|agent| agent.owner == owner)
+            .flat_map(|agent| agent.outputs.iter().map(|output| output.content_hash.clone()))
+            .collect()
+    })
+}
 
-                my_agent.outputs.push(h.clone());
-            }
+// Fails if storing `size` more bytes takes `scope` past `max`. Content `scope` already
+// holds is stored once, so it adds nothing.
+fn check_quota(scope : &str, used : u64, already_held : bool, size : u64, max : u64) -> Result<(), ApiError> {
+    let added = if already_held { 0 } else { size };
+    if used + added > max {
+        return Err(ApiError::QuotaExceeded(format!("{} stores {} of {} output bytes", scope, used, max)));
+    }
+    Ok(())
+}
+
+// Drops the records the retention policy no longer covers, oldest first
+fn prune_outputs(agent : &mut AgentConfig, retention : &OutputRetention, now : u64) {
+    let max_outputs = retention.max_outputs_per_agent.min(MAX_OUTPUTS_PER_AGENT) as usize;
+    let cutoff = retention.max_age_seconds.map(|max_age| now.saturating_sub(max_age));
+    let over = agent.outputs.len().saturating_sub(max_outputs);
+    let expired = agent.outputs[over..].iter().take_while(|output| cutoff.is_some_and(|cutoff| output.timestamp < cutoff)).count();
+    for output in agent.outputs.drain(..over + expired) {
+        crate::output_store::release(&output.content_hash);
+    }
+}
+
+fn prune_all_outputs(now : u64) {
+    let retention = get_output_retention();
+    AGENTS.with(|agents| {
+        for agent in agents.borrow_mut().values_mut() {
+            prune_outputs(agent, &retention, now);
         }
     });
-    "Output Stored".to_string()
 }
 
+// Frees outputs that aged out of the retention policy between stores
+pub(crate) fn start_output_pruning() {
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(OUTPUT_PRUNING_INTERVAL_SECS), || {
+        prune_all_outputs(now());
+    });
+}
 
+// Appends an output to the agent's history. `status` defaults to Succeeded. Identical
+// contents are stored once but every run keeps its own record.
+#[update]
+pub fn store_output(output : String , id : u64, status : Option<OutputStatus>)-> Result<(), ApiError>{
+    let Some(owner) = owner_of(id) else {
+        return Err(ApiError::NotFound(format!("Agent {} does not exist", id)));
+    };
+    if !may_store_output(id, msg_caller()) {
+        return Err(ApiError::Unauthorized("Not authorized to store outputs for this agent".to_string()));
+    }
+    let limits = get_output_limits();
+    let size = output.len() as u64;
+    if size > limits.max_output_bytes {
+        return Err(ApiError::InvalidArgument(format!("Output is {} bytes, at most {} are accepted", size, limits.max_output_bytes)));
+    }
+    let h = crate::output_store::hash(&output);
+    let (_, total_bytes) = crate::output_store::totals();
+    check_quota("The canister", total_bytes, crate::output_store::size_of(&h).is_some(), size, limits.max_total_bytes)?;
+    let held_by_owner = owner_hashes(owner);
+    check_quota(&owner.to_string(), bytes_of(&held_by_owner), held_by_owner.contains(&h), size, limits.max_bytes_per_owner)?;
 
+    with_agent(id, |my_agent| {
+        let held = my_agent.outputs.iter().any(|output| output.content_hash == h);
+        check_quota(&format!("Agent {}", id), stored_bytes(my_agent), held, size, limits.max_bytes_per_agent)?;
+        let now = now();
+        my_agent.next_run_id += 1;
+        my_agent.outputs.push(Outputs {
+            run_id : my_agent.next_run_id,
+            timestamp : now,
+            status : status.unwrap_or(OutputStatus::Succeeded),
+            content_hash : crate::output_store::put(output),
+        });
+        prune_outputs(my_agent, &get_output_retention(), now);
+        Ok(())
+    })
+}
 
-fn hash_output(output : &String) -> String{
-    let mut hasher = Sha256::new();
-    hasher.update(output.as_bytes());
-    let result = hasher.finalize();
-    hex::encode(result)
+#[query]
+pub fn get_output_limits() -> OutputLimits {
+    OUTPUT_LIMITS.with(|limits| limits.borrow().clone())
+}
+
+// Controllers only; agents already over a lowered quota keep their outputs but can't add new ones
+#[update]
+pub fn set_output_limits(limits : OutputLimits) -> Result<(), ApiError> {
+    if !is_controller(&msg_caller()) {
+        return Err(ApiError::Unauthorized("Only controllers can change the output limits".to_string()));
+    }
+    if limits.max_output_bytes > MAX_OUTPUT_BYTES {
+        return Err(ApiError::InvalidArgument(format!("Outputs can be at most {} bytes", MAX_OUTPUT_BYTES)));
+    }
+    if limits.max_total_bytes > MAX_TOTAL_OUTPUT_BYTES {
+        return Err(ApiError::InvalidArgument(format!("Outputs can take at most {} bytes in total", MAX_TOTAL_OUTPUT_BYTES)));
+    }
+    OUTPUT_LIMITS.with(|current| *current.borrow_mut() = limits);
+    Ok(())
+}
+
+#[query]
+pub fn get_output_retention() -> OutputRetention {
+    OUTPUT_RETENTION.with(|retention| retention.borrow().clone())
+}
+
+// Controllers only, as the policy covers every agent; applies to existing outputs too
+#[update]
+pub fn set_output_retention(retention : OutputRetention) -> Result<(), ApiError> {
+    if !is_controller(&msg_caller()) {
+        return Err(ApiError::Unauthorized("Only controllers can change the output retention".to_string()));
+    }
+    if retention.max_outputs_per_agent > MAX_OUTPUTS_PER_AGENT {
+        return Err(ApiError::InvalidArgument(format!("At most {} outputs per agent can be kept", MAX_OUTPUTS_PER_AGENT)));
+    }
+    OUTPUT_RETENTION.with(|current| *current.borrow_mut() = retention);
+    prune_all_outputs(now());
+    Ok(())
 }
 
+#[query]
+pub fn get_executors() -> Vec<Principal> {
+    EXECUTORS.with(|executors| executors.borrow().iter().copied().collect())
+}
+
+#[update]
+pub fn register_executor(executor : Principal) -> Result<(), ApiError> {
+    if !is_controller(&msg_caller()) {
+        return Err(ApiError::Unauthorized("Only controllers can register executors".to_string()));
+    }
+    if executor == Principal::anonymous() {
+        return Err(ApiError::InvalidArgument("The anonymous principal can't be an executor".to_string()));
+    }
+    if !EXECUTORS.with(|executors| executors.borrow_mut().insert(executor)) {
+        return Err(ApiError::AlreadyExists(format!("{} is already an executor", executor)));
+    }
+    Ok(())
+}
 
+#[update]
+pub fn deregister_executor(executor : Principal) -> Result<(), ApiError> {
+    if !is_controller(&msg_caller()) {
+        return Err(ApiError::Unauthorized("Only controllers can deregister executors".to_string()));
+    }
+    if !EXECUTORS.with(|executors| executors.borrow_mut().remove(&executor)) {
+        return Err(ApiError::NotFound(format!("{} is not an executor", executor)));
+    }
+    Ok(())
+}
 
+// Output records of an agent, newest first, optionally only those stored at or after
+// `since` (seconds since epoch). `cursor` is the `next_cursor` of the previous page.
 #[query]
-pub fn get_outputs(hash : String)-> Option<String>{
-    OUTPUTS.with(|output|{
-        output.borrow().get(&hash).cloned()
+pub fn get_agent_outputs(agent_id : u64, since : Option<u64>, cursor : Option<u64>, limit : u32) -> Result<OutputPage, ApiError> {
+    authorize(agent_id, Permission::View)?;
+    AGENTS.with(|agents| {
+        let agents = agents.borrow();
+        let agent = agents.get(&agent_id).ok_or_else(|| ApiError::NotFound(format!("Agent {} does not exist", agent_id)))?;
+        // Outputs are stored in order, so everything past the first one before `since` is older too
+        let newest_first = agent.outputs.iter().rev().take_while(|output| since.is_none_or(|since| output.timestamp >= since));
+        let (outputs, next_cursor) =
+            crate::pagination::page(newest_first, |output| output.run_id, cursor, limit.min(MAX_OUTPUT_PAGE_SIZE) as usize);
+        Ok(OutputPage { outputs, next_cursor })
     })
 }
+
+// The agent's most recent output with its content, if it has stored any
+#[query]
+pub fn get_latest_output(agent_id : u64) -> Result<Option<AgentOutput>, ApiError> {
+    authorize(agent_id, Permission::View)?;
+    let latest = AGENTS.with(|agents| agents.borrow().get(&agent_id).map(|agent| agent.outputs.last().cloned()))
+        .ok_or_else(|| ApiError::NotFound(format!("Agent {} does not exist", agent_id)))?;
+    let Some(output) = latest else {
+        return Ok(None);
+    };
+    let content = crate::output_store::get(&output.content_hash)
+        .ok_or_else(|| ApiError::NotFound(format!("No output with hash {}", output.content_hash)))?;
+    Ok(Some(AgentOutput { output, content }))
+}
+
+
+
+
+#[query]
+pub fn get_outputs(hash : String)-> Result<String, ApiError>{
+    authorize_output(&hash)?;
+    crate::output_store::get(&hash).ok_or_else(|| ApiError::NotFound(format!("No output with hash {}", hash)))
+}
+
+#[query]
+pub fn get_output_stats() -> OutputStats {
+    let (blobs, total_bytes) = crate::output_store::totals();
+    let mut per_agent = BTreeMap::new();
+    let mut owner_hashes : BTreeMap<Principal, (u64, HashSet<String>)> = BTreeMap::new();
+    AGENTS.with(|agents| {
+        for agent in agents.borrow().values() {
+            per_agent.insert(agent.agent_id, OutputUsage { outputs : agent.outputs.len() as u64, bytes : stored_bytes(agent) });
+            let (outputs, hashes) = owner_hashes.entry(agent.owner).or_default();
+            *outputs += agent.outputs.len() as u64;
+            hashes.extend(agent.outputs.iter().map(|output| output.content_hash.clone()));
+        }
+    });
+    // Content shared between an owner's agents counts once for the owner
+    let per_owner = owner_hashes
+        .into_iter()
+        .map(|(owner, (outputs, hashes))| (owner, OutputUsage { outputs, bytes : bytes_of(&hashes) }))
+        .collect();
+    OutputStats { blobs, stored_bytes : total_bytes, per_agent, per_owner }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(agent_id : u64, owner : Principal, contents : &[&str]) -> AgentConfig {
+        let outputs = contents
+            .iter()
+            .enumerate()
+            .map(|(run, content)| Outputs {
+                run_id : run as u64 + 1,
+                timestamp : 0,
+                status : OutputStatus::Succeeded,
+                content_hash : crate::output_store::put(content.to_string()),
+            })
+            .collect();
+        AgentConfig {
+            agent_id,
+            public_id : public_id(agent_id, owner),
+            name : String::new(),
+            description : String::new(),
+            owner,
+            schedule : Schedule::Interval { interval_seconds : 0 },
+            created_at : 0,
+            prompt : String::new(),
+            outputs,
+            next_run_id : contents.len() as u64,
+            last_triggered : None,
+            status : AgentStatus::Active,
+        }
+    }
+
+    #[test]
+    fn quotas_count_held_content_once() {
+        assert!(check_quota("Agent 1", 90, false, 10, 100).is_ok());
+        assert!(matches!(check_quota("Agent 1", 90, false, 11, 100), Err(ApiError::QuotaExceeded(_))));
+        // Content already stored doesn't add to the usage, but a lowered quota still applies
+        assert!(check_quota("Agent 1", 100, true, 50, 100).is_ok());
+        assert!(check_quota("Agent 1", 120, true, 50, 100).is_err());
+    }
+
+    #[test]
+    fn owner_usage_spans_every_agent_of_the_owner() {
+        let owner = Principal::from_slice(&[1]);
+        let first = agent(1, owner, &["aaaa", "bb"]);
+        assert_eq!(stored_bytes(&first), 6);
+        AGENTS.with(|agents| {
+            let mut agents = agents.borrow_mut();
+            agents.insert(1, first);
+            agents.insert(2, agent(2, owner, &["aaaa", "c", "c"]));
+            agents.insert(3, agent(3, Principal::from_slice(&[2]), &["dddddddd"]));
+        });
+        let hashes = owner_hashes(owner);
+        assert_eq!(hashes.len(), 3);
+        assert_eq!(bytes_of(&hashes), 7);
+        assert!(hashes.contains(&crate::output_store::hash("c")));
+        assert!(!hashes.contains(&crate::output_store::hash("dddddddd")));
+    }
+
+    #[test]
+    fn outputs_are_readable_by_viewers_of_an_agent_pointing_at_them() {
+        let (owner, other_owner, stranger) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]), Principal::from_slice(&[3]));
+        AGENTS.with(|agents| {
+            let mut agents = agents.borrow_mut();
+            agents.insert(1, agent(1, owner, &["shared", "private"]));
+            agents.insert(2, agent(2, other_owner, &["shared"]));
+        });
+        let (shared, private) = (crate::output_store::hash("shared"), crate::output_store::hash("private"));
+        assert!(may_view_output(&private, owner));
+        assert!(may_view_output(&shared, owner) && may_view_output(&shared, other_owner));
+        assert!(!may_view_output(&private, other_owner));
+        assert!(!may_view_output(&private, stranger));
+        assert!(!may_view_output(&private, Principal::anonymous()));
+        assert!(!may_view_output(&crate::output_store::hash("missing"), owner));
+    }
+}
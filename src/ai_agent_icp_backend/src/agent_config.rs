@@ -1,18 +1,38 @@
 
 use candid::{CandidType, Principal};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 
 #[derive(Debug,Serialize,Deserialize,CandidType,Clone)]
 pub struct AgentConfig{
     pub agent_id : u64,
+    #[serde(default)]
+    pub public_id : String, // hex, derived from the ID and owner
     pub name : String,
     pub description : String,
     pub owner : Principal,
     pub schedule : Schedule,
     pub created_at : i128,
     pub prompt : String,
-    pub outputs : Vec<String>,
+    #[serde(deserialize_with = "deserialize_outputs")]
+    pub outputs : Vec<Outputs>, // oldest first
+    #[serde(default)]
+    pub next_run_id : u64,
+    // Seconds since epoch the schedule last fired
+    #[serde(default)]
+    pub last_triggered : Option<u64>,
+    #[serde(default)]
+    pub status : AgentStatus,
+}
+
+// Only active agents are scheduled, here and by the off-chain scheduler. Archived ones
+// had their task queue retired and are kept read-only for their outputs.
+#[derive(Debug, Default, Serialize, Deserialize, CandidType, Clone, Copy, PartialEq, Eq)]
+pub enum AgentStatus {
+    #[default]
+    Active,
+    Paused,
+    Archived,
 }
 
 #[derive(Debug, Serialize, Deserialize,CandidType,Clone)]
@@ -20,10 +40,64 @@ pub enum Schedule {
     Interval{interval_seconds : u64},
     Cron{expression : String}
 }
+
+impl Schedule {
+    // When the schedule next fires after `after` (seconds since epoch)
+    pub fn next_after(&self, after : u64) -> Option<u64> {
+        match self {
+            Schedule::Interval { interval_seconds: 0 } => None,
+            Schedule::Interval { interval_seconds } => after.checked_add(*interval_seconds),
+            Schedule::Cron { expression } => expression.parse::<crate::cron::CronExpr>().ok()?.next_after(after),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Schedule::Interval { .. } => Ok(()),
+            Schedule::Cron { expression } => crate::cron::validate(expression).map(|_| ()),
+        }
+    }
+}
+#[derive(Debug, Serialize, Deserialize, CandidType, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStatus {
+    Succeeded,
+    Failed,
+}
+
+// One stored result of an agent run; the content is in output_store under `content_hash`
 #[derive(Debug, Serialize, Deserialize,CandidType,Clone) ]
 pub struct Outputs{
-    pub output : String,
-    pub timestamp : i128
+    pub run_id : u64,
+    pub timestamp : u64, // seconds since epoch, 0 if stored before outputs were timestamped
+    pub status : OutputStatus,
+    pub content_hash : String,
+}
+
+// Agents persisted before output records kept the bare content hashes
+fn deserialize_outputs<'de, D>(deserializer : D) -> Result<Vec<Outputs>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Record(Outputs),
+        Hash(String),
+    }
+    let stored = Vec::<Stored>::deserialize(deserializer)?;
+    Ok(stored
+        .into_iter()
+        .enumerate()
+        .map(|(i, output)| match output {
+            Stored::Record(output) => output,
+            Stored::Hash(content_hash) => Outputs {
+                run_id : i as u64 + 1,
+                timestamp : 0,
+                status : OutputStatus::Succeeded,
+                content_hash,
+            },
+        })
+        .collect())
 }
 
 
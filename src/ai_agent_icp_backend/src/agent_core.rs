@@ -1,768 +1,1236 @@
-use ic_cdk::api::{ msg_caller, time};
+#[cfg(not(test))]
+use ic_cdk::api::{debug_print, time};
+use ic_cdk::api::msg_caller;
 use ic_cdk_macros::{ query, update};
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use candid::{Principal, CandidType, Nat};
- 
-use serde_json;
-   
-// Import only what we need from token
-use crate::token::{Account};
+
+use crate::token2::{Account, BurnArgs, Metadata, Transaction, TransferArgs};
+use crate::access::{Permission, Role};
+use crate::error::ApiError;
+use crate::retry::{RetryPolicy, TaskErrorKind};
+use ic_cdk::management_canister::{HttpHeader, HttpMethod};
+use crate::scheduler::Job;
+
+// What a task does when it runs
+#[derive(Clone, Debug, Serialize, Deserialize, CandidType)]
+pub enum TaskAction {
+    TokenInit {
+        name: String,
+        symbol: String,
+        decimals: u8,
+        description: Option<String>,
+        logo: Option<String>,
+        initial_supply: Nat,
+        fee: Nat,
+    },
+    // Token operations run as the agent owner against the token2 ledger of `symbol`.
+    // Tasks stored before they named a symbol target the migrated legacy token.
+    Transfer {
+        #[serde(default)]
+        symbol: String,
+        to: Account,
+        amount: Nat,
+        memo: Option<Vec<u8>>,
+    },
+    Mint {
+        #[serde(default)]
+        symbol: String,
+        to: Account,
+        amount: Nat,
+    },
+    Burn {
+        #[serde(default)]
+        symbol: String,
+        from: Account,
+        amount: Nat,
+    },
+    HttpRequest {
+        url: String,
+        #[serde(default)]
+        method: HttpMethod,
+        #[serde(default)]
+        headers: Vec<HttpHeader>,
+        #[serde(default)]
+        body: Option<Vec<u8>>,
+        #[serde(default)]
+        max_response_bytes: Option<u64>,
+        #[serde(default)]
+        max_cycles: Option<u128>, // budget per run, 1B cycles if None; the request is not sent if it costs more
+    },
+    Custom { data: String },
+}
+
+impl TaskAction {
+    // Name used to filter tasks by kind, e.g. "token_mint"
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TaskAction::TokenInit { .. } => "token_init",
+            TaskAction::Transfer { .. } => "token_transfer",
+            TaskAction::Mint { .. } => "token_mint",
+            TaskAction::Burn { .. } => "token_burn",
+            TaskAction::HttpRequest { .. } => "http_request",
+            TaskAction::Custom { .. } => "custom",
+        }
+    }
+
+    // Token tasks run as the agent's owner, so setting one up takes Administer
+    fn required_permission(&self) -> Permission {
+        match self {
+            TaskAction::TokenInit { .. } | TaskAction::Transfer { .. } | TaskAction::Mint { .. } | TaskAction::Burn { .. } => {
+                Permission::Administer
+            }
+            TaskAction::HttpRequest { .. } | TaskAction::Custom { .. } => Permission::Manage,
+        }
+    }
+}
+
+// Outcome of the last run of a task
+#[derive(Clone, Debug, Serialize, Deserialize, CandidType, PartialEq)]
+pub enum TaskResult {
+    Success { tx_id: Option<Nat> },
+    Failed {
+        error: String,
+        #[serde(default)]
+        kind: TaskErrorKind,
+    },
+}
+
+// Failed tasks stop running until they are put back with `retry_dead_letter_task`
+#[derive(Clone, Debug, Default, Serialize, Deserialize, CandidType, PartialEq)]
+pub enum TaskStatus {
+    #[default]
+    Active,
+    Failed { failed_at: u64 },
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize, CandidType)]
 pub struct Task {
     pub id: u64,
-    pub data: String,
+    pub action: TaskAction,
     pub frequency: u64, // seconds
     pub last_run: u64,  // timestamp
-    pub url: Option<String>, // Optional URL for HTTP outbound calls
-    pub action_type: String, // Type of action: "http_request", "custom", "token", etc.
     pub enabled: bool, // Whether this task is active
+    pub last_result: Option<TaskResult>,
+    // When set, the task runs on this cron schedule (UTC) instead of every `frequency` seconds
+    #[serde(default)]
+    pub cron: Option<String>,
+    #[serde(default)]
+    pub created_at: u64, // timestamp
+    #[serde(default)]
+    pub status: TaskStatus,
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    #[serde(default)]
+    pub attempts: u32, // consecutive failed runs
+    #[serde(default)]
+    pub retry_at: Option<u64>, // timestamp of the pending retry, if any
+}
+
+impl Task {
+    pub(crate) fn new(id: u64, action: TaskAction, frequency: u64, created_at: u64) -> Self {
+        Task {
+            id,
+            action,
+            frequency,
+            last_run: 0,
+            enabled: true,
+            last_result: None,
+            cron: None,
+            created_at,
+            status: TaskStatus::Active,
+            retry_policy: RetryPolicy::default(),
+            attempts: 0,
+            retry_at: None,
+        }
+    }
+}
+
+// Task as stored before actions were typed: JSON in `data`, the kind in `action_type`
+#[derive(Deserialize)]
+struct LegacyTask {
+    id: u64,
+    data: String,
+    frequency: u64,
+    last_run: u64,
+    url: Option<String>,
+    action_type: String,
+    enabled: bool,
+}
+
+impl From<LegacyTask> for Task {
+    fn from(legacy: LegacyTask) -> Self {
+        let json: serde_json::Value = serde_json::from_str(&legacy.data).unwrap_or(serde_json::Value::Null);
+        let text = |key: &str| json[key].as_str().map(|s| s.to_string());
+        let nat = |key: &str| json[key].as_str().and_then(|s| s.parse::<Nat>().ok());
+        let account = |key: &str| {
+            let owner = Principal::from_text(json[key].as_str()?).ok()?;
+            Some(Account { owner, subaccount: None })
+        };
+        let parsed = match legacy.action_type.as_str() {
+            "token_init" => (|| {
+                Some(TaskAction::TokenInit {
+                    name: text("name")?,
+                    symbol: text("symbol")?,
+                    decimals: json["decimals"].as_u64().unwrap_or(8) as u8,
+                    description: text("description"),
+                    logo: text("logo"),
+                    initial_supply: nat("initial_supply")?,
+                    fee: nat("fee")?,
+                })
+            })(),
+            "token_transfer" => (|| {
+                Some(TaskAction::Transfer {
+                    symbol: String::new(),
+                    to: account("to")?,
+                    amount: nat("amount")?,
+                    memo: text("memo").filter(|m| !m.is_empty()).map(String::into_bytes),
+                })
+            })(),
+            "token_mint" => (|| {
+                Some(TaskAction::Mint { symbol: String::new(), to: account("to")?, amount: nat("amount")? })
+            })(),
+            "token_burn" => (|| {
+                Some(TaskAction::Burn { symbol: String::new(), from: account("from")?, amount: nat("amount")? })
+            })(),
+            "http_request" => legacy.url.clone().map(|url| TaskAction::HttpRequest {
+                url,
+                method: HttpMethod::GET,
+                headers: Vec::new(),
+                body: None,
+                max_response_bytes: None,
+                max_cycles: None,
+            }),
+            _ => Some(TaskAction::Custom { data: legacy.data.clone() }),
+        };
+        let last_result = match json["status"].as_str() {
+            Some("success") => Some(TaskResult::Success { tx_id: nat("tx_id") }),
+            Some(_) => Some(TaskResult::Failed { error: text("error").unwrap_or_default(), kind: TaskErrorKind::Other }),
+            None => None,
+        };
+        match parsed {
+            Some(action) => Task {
+                last_run: legacy.last_run,
+                enabled: legacy.enabled,
+                last_result,
+                ..Task::new(legacy.id, action, legacy.frequency, 0)
+            },
+            // Keep whatever we couldn't make sense of, but never run it
+            None => Task {
+                last_run: legacy.last_run,
+                enabled: false,
+                last_result: Some(TaskResult::Failed {
+                    error: format!("Could not migrate {} task", legacy.action_type),
+                    kind: TaskErrorKind::InvalidTask,
+                }),
+                ..Task::new(legacy.id, TaskAction::Custom { data: legacy.data }, legacy.frequency, 0)
+            },
+        }
+    }
+}
+
+// Rewrites the tasks of a version 1 snapshot (`agent.tasks`) into typed tasks
+pub(crate) fn migrate_v1_tasks(state: &mut ciborium::Value) -> Result<(), String> {
+    let Some(entries) = state.as_map_mut() else {
+        return Ok(());
+    };
+    let Some((_, agent)) = entries.iter_mut().find(|(key, _)| key.as_text() == Some("agent")) else {
+        return Ok(());
+    };
+    let Some(agent) = agent.as_map_mut() else {
+        return Ok(());
+    };
+    let Some((_, tasks)) = agent.iter_mut().find(|(key, _)| key.as_text() == Some("tasks")) else {
+        return Ok(());
+    };
+    let Some(tasks) = tasks.as_array_mut() else {
+        return Ok(());
+    };
+    for task in tasks.iter_mut() {
+        let legacy: LegacyTask = task.deserialized().map_err(|e| format!("Failed to read legacy task: {}", e))?;
+        *task = ciborium::Value::serialized(&Task::from(legacy)).map_err(|e| format!("Failed to migrate task: {}", e))?;
+    }
+    Ok(())
 }
 
+// Task queue of one agent in agent::AGENTS, under the same ID
 #[derive(Clone, Debug, Serialize, Deserialize, CandidType)]
 pub struct Agent {
     pub owner: Principal,
     pub tasks: VecDeque<Task>,
     pub active: bool,
     pub created_at: u64,
+    #[serde(default)]
+    pub roles: BTreeMap<Principal, Role>, // granted by the owner, who doesn't need one
 }
 
 thread_local! {
-    static AGENT: RefCell<Option<Agent>> = RefCell::new(None);
+    static AGENTS: RefCell<BTreeMap<u64, Agent>> = const { RefCell::new(BTreeMap::new()) };
+    // http_request tasks waiting for their response; they don't run again until it's in
+    static HTTP_IN_FLIGHT: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+    // ID `add_task` hands out next; never reused
+    static NEXT_TASK_ID: RefCell<u64> = const { RefCell::new(1) };
 }
 
-// Internal counter for task IDs
-thread_local! {
-    static NEXT_TASK_ID: RefCell<u64> = RefCell::new(0);
+// The agents and the next task ID
+pub(crate) fn take_stable_state() -> (BTreeMap<u64, Agent>, u64) {
+    (
+        AGENTS.with(|a| std::mem::take(&mut *a.borrow_mut())),
+        NEXT_TASK_ID.with(|next| *next.borrow()),
+    )
+}
+
+// `legacy` is the single canister-wide agent of snapshots written before agents
+// were per owner; it becomes an agent of its own. Restore agent::AGENTS first.
+// `legacy_symbol` is where token2 put the legacy token, which token tasks without
+// a symbol used to act on.
+pub(crate) fn restore_stable_state(
+    mut agents: BTreeMap<u64, Agent>,
+    next_task_id: u64,
+    legacy: Option<Agent>,
+    legacy_symbol: Option<String>,
+) {
+    if let Some(agent) = legacy {
+        let agent_id = crate::agent::register_legacy_agent(agent.owner, agent.created_at);
+        debug_print(format!("Migrated the task agent of {} to agent {}", agent.owner, agent_id));
+        agents.insert(agent_id, agent);
+    }
+    if let Some(legacy_symbol) = legacy_symbol {
+        for task in agents.values_mut().flat_map(|agent| agent.tasks.iter_mut()) {
+            if let TaskAction::Transfer { symbol, .. } | TaskAction::Mint { symbol, .. } | TaskAction::Burn { symbol, .. } =
+                &mut task.action
+            {
+                if symbol.is_empty() {
+                    *symbol = legacy_symbol.clone();
+                }
+            }
+        }
+    }
+    // Snapshots written before the counter was persisted only have the tasks to go by
+    let after_last =
+        agents.values().flat_map(|agent| agent.tasks.iter()).map(|task| task.id.saturating_add(1)).max().unwrap_or(1);
+    NEXT_TASK_ID.with(|next| *next.borrow_mut() = next_task_id.max(after_last));
+    AGENTS.with(|a| *a.borrow_mut() = agents);
 }
 
-// Helper to get next task ID
-/*fn get_next_task_id() -> u64 {
-    NEXT_TASK_ID.with(|id| {
-        let next_id = *id.borrow();
-        *id.borrow_mut() += 1;
-        next_id
+// Hands out the next task ID. The counter only moves forward, so the ID of a deleted
+// task is never handed out again.
+fn claim_task_id() -> Result<u64, ApiError> {
+    NEXT_TASK_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        let id = *next;
+        *next = id.checked_add(1).ok_or_else(|| ApiError::QuotaExceeded("No task IDs are left".to_string()))?;
+        Ok(id)
     })
-}*/
+}
 
-#[update]
-pub fn initialize_agent() {
-    let agent = Agent {
-        owner: ic_cdk::api::msg_caller(),
-        tasks: VecDeque::new(),
-        active: true,
-        created_at: time() / 1_000_000_000,
-    };
-    AGENT.with(|a| *a.borrow_mut() = Some(agent));
-    ic_cdk::api::debug_print(format!("Agent initialized with owner: {}", ic_cdk::api::msg_caller().to_string()));
+pub(crate) fn has_permission(agent_id: u64, caller: Principal, permission: Permission) -> bool {
+    if caller == Principal::anonymous() {
+        return false;
+    }
+    AGENTS.with(|a| {
+        a.borrow().get(&agent_id).is_some_and(|agent| {
+            agent.owner == caller || agent.roles.get(&caller).is_some_and(|role| role.allows(permission))
+        })
+    })
 }
 
-#[update]
-pub fn create_task(id : u64,data: String, frequency: u64)  {
-    
-    AGENT.with(|a| {
-        if let Some(agent) = &mut *a.borrow_mut() {
-            // Check for duplicate ID
-            if agent.tasks.iter().any(|t| t.id == id) {
-                ic_cdk::trap("Task with this ID already exists");
-            }
-            agent.tasks.push_back(Task { 
-                id, 
-                data, 
-                frequency, 
-                last_run: 0,
-                url: None,
-                action_type: "custom".to_string(),
-                enabled: true 
-            });
-            ic_cdk::api::debug_print(format!("Task created with ID: {}", id));
+// Guard of every agent endpoint: fails unless the caller may do `permission` on `agent_id`
+fn authorize(agent_id: u64, permission: Permission) -> Result<(), ApiError> {
+    authorize_as(msg_caller(), agent_id, permission)
+}
+
+fn authorize_as(caller: Principal, agent_id: u64, permission: Permission) -> Result<(), ApiError> {
+    if !AGENTS.with(|a| a.borrow().contains_key(&agent_id)) {
+        return Err(ApiError::NotFound(format!("Agent {} not initialized", agent_id)));
+    }
+    if !has_permission(agent_id, caller, permission) {
+        debug_print(format!("Authorization failed: {} can't {:?} agent {}",
+              caller, permission, agent_id));
+        return Err(ApiError::Unauthorized("Not authorized".to_string()));
+    }
+    Ok(())
+}
+
+pub(crate) fn agent_of_task(task_id: u64) -> Option<u64> {
+    AGENTS.with(|a| {
+        a.borrow()
+            .iter()
+            .find(|(_, agent)| agent.tasks.iter().any(|task| task.id == task_id))
+            .map(|(agent_id, _)| *agent_id)
+    })
+}
+
+// Same as `authorize`, for the agent a task belongs to
+pub(crate) fn authorize_task(task_id: u64, permission: Permission) -> Result<(), ApiError> {
+    authorize_task_as(msg_caller(), task_id, permission)
+}
+
+fn authorize_task_as(caller: Principal, task_id: u64, permission: Permission) -> Result<(), ApiError> {
+    match agent_of_task(task_id) {
+        Some(agent_id) => authorize_as(caller, agent_id, permission),
+        None => Err(ApiError::NotFound(format!("Task {} not found", task_id))),
+    }
+}
+
+// What it takes to change a task, and to turn it into `action` if given
+fn change_permission(task_id: u64, action: Option<&TaskAction>) -> Permission {
+    let current = with_task(task_id, |task| task.action.required_permission()).unwrap_or(Permission::Manage);
+    match action.map(TaskAction::required_permission) {
+        Some(Permission::Administer) => Permission::Administer,
+        _ => current,
+    }
+}
+
+fn with_task<R>(task_id: u64, f: impl FnOnce(&mut Task) -> R) -> Option<R> {
+    AGENTS.with(|a| {
+        a.borrow_mut()
+            .values_mut()
+            .find_map(|agent| agent.tasks.iter_mut().find(|task| task.id == task_id))
+            .map(f)
+    })
+}
+
+// Adds a task to an agent the caller owns and returns its ID. Task IDs are unique
+// across agents and no task, even a deleted one, has had the same ID before.
+fn add_task(caller: Principal, agent_id: u64, action: TaskAction, frequency: u64) -> Result<u64, ApiError> {
+    authorize_as(caller, agent_id, action.required_permission())?;
+    let mut task = Task::new(0, action, frequency, time() / 1_000_000_000);
+    check_http_schedule(&task)?;
+    let id = claim_task_id()?;
+    task.id = id;
+    debug_print(format!("Task created successfully on agent {}: {:?}", agent_id, task));
+    AGENTS.with(|a| {
+        if let Some(agent) = a.borrow_mut().get_mut(&agent_id) {
+            agent.tasks.push_back(task);
         }
     });
+    reschedule(id);
+    Ok(id)
 }
 
+// Gives an agent made with `create_agent` its task queue. Only the agent's owner
+// can do this, and it has to happen before any task is added.
 #[update]
-pub fn create_task_complete(id: u64, data: String, frequency: u64, url: Option<String>, action_type: String) -> u64 {
-    let actual_id = if id == 0 {
-        // Auto-generate ID by finding the max ID and adding 1
-        AGENT.with(|a| {
-            ensure_agent_initialized();
-            
-            if let Some(agent) = &*a.borrow() {
-                let max_id = agent.tasks.iter()
-                    .map(|task| task.id)
-                    .max()
-                    .unwrap_or(0);
-                
-                max_id + 1
-            } else {
-                1 // Start with 1 if no tasks exist
-            }
-        })
-    } else {
-        id
+pub fn initialize_agent(agent_id: u64) -> Result<(), ApiError> {
+    let Some(owner) = crate::agent::owner_of(agent_id) else {
+        return Err(ApiError::NotFound(format!("Agent {} does not exist", agent_id)));
     };
-    
-    ic_cdk::api::debug_print(format!("Creating task with ID: {}, data: {}, frequency: {}, action_type: {}", 
-        actual_id, data, frequency, action_type));
-    
-    AGENT.with(|a| {
-        // Auto-initialize agent if not initialized
-        if a.borrow().is_none() {
-            let new_agent = Agent {
-                owner: ic_cdk::api::msg_caller(),
-                tasks: VecDeque::new(),
-                active: true,
-                created_at: time() / 1_000_000_000,
-            };
-            *a.borrow_mut() = Some(new_agent);
-            ic_cdk::api::debug_print(format!("Agent auto-initialized with owner: {}", ic_cdk::api::msg_caller().to_string()));
-        }
-        
-        if let Some(agent) = &mut *a.borrow_mut() {
-            // Check for duplicate ID
-            if agent.tasks.iter().any(|t| t.id == actual_id) {
-                ic_cdk::api::debug_print(format!("Task with ID {} already exists", actual_id));
-                ic_cdk::trap("Task with this ID already exists");
-            }
-            
-            let task = Task { 
-                id: actual_id, 
-                data, 
-                frequency, 
-                last_run: 0,
-                url,
-                action_type,
-                enabled: true 
-            };
-            
-            agent.tasks.push_back(task.clone());
-            ic_cdk::api::debug_print(format!("Task created successfully: {:?}", task));
-            
-            // Debug log the current task count
-            ic_cdk::api::debug_print(format!("Current task count: {}", agent.tasks.len()));
-        } else {
-            ic_cdk::api::debug_print("Agent not initialized");
-            ic_cdk::trap("Agent not initialized");
+    if owner != msg_caller() {
+        return Err(ApiError::Unauthorized("Not authorized".to_string()));
+    }
+    AGENTS.with(|a| {
+        let mut agents = a.borrow_mut();
+        if agents.contains_key(&agent_id) {
+            return Err(ApiError::AlreadyExists(format!("Agent {} already initialized", agent_id)));
         }
-    });
-    
-    actual_id
+        agents.insert(agent_id, Agent {
+            owner,
+            tasks: VecDeque::new(),
+            active: true,
+            created_at: time() / 1_000_000_000,
+            roles: BTreeMap::new(),
+        });
+        Ok(())
+    })?;
+    debug_print(format!("Agent {} initialized with owner: {}", agent_id, owner));
+    Ok(())
 }
 
+// Gives `principal` a role on the agent, replacing any it had
 #[update]
-pub fn update_task(id: u64, data: Option<String>, frequency: Option<u64>, url: Option<String>, action_type: Option<String>, enabled: Option<bool>) {
-    AGENT.with(|a| {
-        if let Some(agent) = &mut *a.borrow_mut() {
-            for task in agent.tasks.iter_mut() {
-                if task.id == id {
-                    if let Some(data_val) = data {
-                        task.data = data_val;
-                    }
-                    if let Some(frequency_val) = frequency {
-                        task.frequency = frequency_val;
-                    }
-                    if let Some(url_val) = url {
-                        task.url = Some(url_val);
-                    }
-                    if let Some(action_type_val) = action_type {
-                        task.action_type = action_type_val;
-                    }
-                    if let Some(enabled_val) = enabled {
-                        task.enabled = enabled_val;
-                    }
-                    ic_cdk::api::debug_print(format!("Task updated with ID: {}", id));
-                    return;
-                }
+pub fn add_controller_role(agent_id: u64, principal: Principal, role: Role) -> Result<(), ApiError> {
+    authorize(agent_id, Permission::Administer)?;
+    if principal == Principal::anonymous() {
+        return Err(ApiError::InvalidArgument("The anonymous principal can't hold a role".to_string()));
+    }
+    AGENTS.with(|a| {
+        if let Some(agent) = a.borrow_mut().get_mut(&agent_id) {
+            if agent.owner == principal {
+                return Err(ApiError::InvalidArgument("The agent owner already has every permission".to_string()));
             }
-            ic_cdk::trap("Task not found");
+            agent.roles.insert(principal, role);
         }
-    });
+        Ok(())
+    })?;
+    debug_print(format!("Granted {:?} on agent {} to {}", role, agent_id, principal));
+    Ok(())
+}
+
+#[update]
+pub fn revoke_role(agent_id: u64, principal: Principal) -> Result<(), ApiError> {
+    authorize(agent_id, Permission::Administer)?;
+    let revoked = AGENTS.with(|a| a.borrow_mut().get_mut(&agent_id).and_then(|agent| agent.roles.remove(&principal)));
+    match revoked {
+        Some(_) => Ok(()),
+        None => Err(ApiError::NotFound(format!("{} has no role on agent {}", principal, agent_id))),
+    }
 }
 
 #[query]
-pub fn get_tasks() -> Vec<Task> {
-    AGENT.with(|a| {
-        // Auto-initialize agent if not initialized
-        if a.borrow().is_none() {
-            let new_agent = Agent {
-                owner: ic_cdk::api::msg_caller(),
-                tasks: VecDeque::new(),
-                active: true,
-                created_at: time() / 1_000_000_000,
-            };
-            *a.borrow_mut() = Some(new_agent);
-            ic_cdk::api::debug_print(format!("Agent auto-initialized with owner: {}", ic_cdk::api::msg_caller().to_string()));
+pub fn get_agent_roles(agent_id: u64) -> Result<Vec<(Principal, Role)>, ApiError> {
+    authorize(agent_id, Permission::View)?;
+    Ok(AGENTS.with(|a| {
+        a.borrow()
+            .get(&agent_id)
+            .map(|agent| agent.roles.iter().map(|(principal, role)| (*principal, *role)).collect())
+            .unwrap_or_default()
+    }))
+}
+
+// The task creation endpoints take an ID for compatibility with older clients but
+// ignore it: the canister picks task IDs and returns the one it picked
+#[update]
+pub fn create_task(agent_id: u64, _id: u64, data: String, frequency: u64) -> Result<u64, ApiError> {
+    add_task(msg_caller(), agent_id, TaskAction::Custom { data }, frequency)
+}
+
+#[update]
+pub fn create_task_complete(agent_id: u64, _id: u64, action: TaskAction, frequency: u64) -> Result<u64, ApiError> {
+    debug_print(format!("Creating task, action: {:?}, frequency: {}", action, frequency));
+    add_task(msg_caller(), agent_id, action, frequency)
+}
+
+#[update]
+pub fn update_task(id: u64, action: Option<TaskAction>, frequency: Option<u64>, enabled: Option<bool>) -> Result<(), ApiError> {
+    update_task_as(msg_caller(), id, action, frequency, enabled)
+}
+
+fn update_task_as(
+    caller: Principal,
+    id: u64,
+    action: Option<TaskAction>,
+    frequency: Option<u64>,
+    enabled: Option<bool>,
+) -> Result<(), ApiError> {
+    authorize_task_as(caller, id, change_permission(id, action.as_ref()))?;
+    with_task(id, |task| {
+        let mut updated = task.clone();
+        if let Some(action_val) = action {
+            updated.action = action_val;
         }
-        
-        let tasks = a.borrow()
-            .as_ref()
-            .map(|agent| {
-                let tasks: Vec<Task> = agent.tasks.iter().cloned().collect();
-                ic_cdk::api::debug_print(format!("Fetched {} tasks", tasks.len()));
-                tasks
-            })
-            .unwrap_or_default();
-        
-        ic_cdk::api::debug_print(format!("Returning {} tasks", tasks.len()));
-        tasks
+        if let Some(frequency_val) = frequency {
+            updated.frequency = frequency_val;
+        }
+        if let Some(enabled_val) = enabled {
+            updated.enabled = enabled_val;
+        }
+        check_http_schedule(&updated)?;
+        *task = updated;
+        Ok::<_, ApiError>(())
     })
+    .unwrap_or(Ok(()))?;
+    debug_print(format!("Task updated with ID: {}", id));
+    reschedule(id);
+    Ok(())
 }
 
 #[query]
-pub fn get_task(id: u64) -> Option<Task> {
-    ic_cdk::api::debug_print(format!("Looking for task with ID: {}", id));
-    
-    ensure_agent_initialized();
-    
-    AGENT.with(|a| {
-        let task = a.borrow()
-            .as_ref()
-            .and_then(|agent| {
-                let found = agent.tasks.iter().find(|task| task.id == id).cloned();
-                if found.is_some() {
-                    ic_cdk::api::debug_print(format!("Found task with ID: {}", id));
-                } else {
-                    ic_cdk::api::debug_print(format!("No task found with ID: {}", id));
-                }
-                found
-            });
-        
-        task
-    })
+pub fn get_tasks(agent_id: u64) -> Result<Vec<Task>, ApiError> {
+    authorize(agent_id, Permission::View)?;
+    Ok(AGENTS.with(|a| {
+        a.borrow()
+            .get(&agent_id)
+            .map(|agent| agent.tasks.iter().cloned().collect())
+            .unwrap_or_default()
+    }))
+}
+
+#[query]
+pub fn get_task(id: u64) -> Result<Task, ApiError> {
+    authorize_task(id, Permission::View)?;
+    with_task(id, |task| task.clone()).ok_or_else(|| ApiError::NotFound(format!("Task {} not found", id)))
 }
 
 #[update]
-pub fn delete_task(id: u64) {
-    ic_cdk::api::debug_print(format!("Attempting to delete task with ID: {}", id));
-    
-    ensure_agent_initialized();
-    
-    AGENT.with(|a| {
-        if let Some(agent) = &mut *a.borrow_mut() {
-            let original_len = agent.tasks.len();
+pub fn delete_task(id: u64) -> Result<(), ApiError> {
+    debug_print(format!("Attempting to delete task with ID: {}", id));
+    authorize_task(id, Permission::Manage)?;
+    AGENTS.with(|a| {
+        for agent in a.borrow_mut().values_mut() {
             agent.tasks.retain(|task| task.id != id);
-            if agent.tasks.len() == original_len {
-                ic_cdk::api::debug_print(format!("Task with ID {} not found", id));
-                ic_cdk::trap("Task not found");
-            } else {
-                ic_cdk::api::debug_print(format!("Task with ID {} deleted successfully", id));
-            }
         }
     });
+    debug_print(format!("Task with ID {} deleted successfully", id));
+    crate::scheduler::schedule(Job::Task(id), None);
+    crate::task_runs::forget(id);
+    Ok(())
 }
 
 // Token operations through agent - all functions will create a task record
 
 #[update]
-pub fn create_token_init_task(name: String, symbol: String, decimals: u8, 
+#[allow(clippy::too_many_arguments)]
+pub fn create_token_init_task(agent_id: u64, name: String, symbol: String, decimals: u8, 
                             description: Option<String>, logo: Option<String>, 
-                            initial_supply: Nat, fee: Nat) -> u64 {
-    ic_cdk::api::debug_print(format!("Creating token initialization task for: {}", name));
-    
-    // Store token parameters in the data field as JSON
-    let data = format!(
-        "{{\"name\":\"{}\",\"symbol\":\"{}\",\"decimals\":{},\"initial_supply\":\"{}\",\"fee\":\"{}\",\"description\":{},\"logo\":{}}}",
-        name, symbol, decimals, initial_supply, fee,
-        description.map_or("null".to_string(), |d| format!("\"{}\"", d)),
-        logo.map_or("null".to_string(), |l| format!("\"{}\"", l))
-    );
+                            initial_supply: Nat, fee: Nat) -> Result<u64, ApiError> {
+    debug_print(format!("Creating token initialization task for: {}", name));
     
     // Create the task
-    let task_id = create_task_complete(
-        0, // Use 0 to auto-assign ID
-        data,
+    let task_id = add_task(
+        msg_caller(),
+        agent_id,
+        TaskAction::TokenInit { name, symbol, decimals, description, logo, initial_supply, fee },
         0, // One-time task
-        None,
-        "token_init".to_string()
-    );
+    )?;
     
-    ic_cdk::api::debug_print(format!("Created token initialization task with ID: {}", task_id));
-    task_id
+    debug_print(format!("Created token initialization task with ID: {}", task_id));
+    Ok(task_id)
 }
 
 #[update]
-pub fn create_token_transfer_task(to: Account, amount: Nat, memo: Option<Vec<u8>>) -> u64 {
-    ic_cdk::api::debug_print(format!("Creating token transfer task to: {}", to.owner.to_string()));
-    
-    // Store transfer parameters in the data field as JSON
-    let data = format!(
-        "{{\"to\":\"{}\",\"amount\":\"{}\",\"memo\":\"{}\"}}",
-        to.owner.to_string(),
-        amount.to_string(),
-        memo.as_ref().map_or("".to_string(), |m| String::from_utf8_lossy(m).to_string())
-    );
+pub fn create_token_transfer_task(agent_id: u64, symbol: String, to: Account, amount: Nat, memo: Option<Vec<u8>>) -> Result<u64, ApiError> {
+    debug_print(format!("Creating {} transfer task to: {}", symbol, to.owner));
     
     // Create the task
-    let task_id = create_task_complete(
-        0, // Use 0 to auto-assign ID
-        data,
+    let task_id = add_task(
+        msg_caller(),
+        agent_id,
+        TaskAction::Transfer { symbol, to, amount, memo },
         0, // One-time task
-        None,
-        "token_transfer".to_string()
-    );
+    )?;
     
-    ic_cdk::api::debug_print(format!("Created token transfer task with ID: {}", task_id));
-    task_id
+    debug_print(format!("Created token transfer task with ID: {}", task_id));
+    Ok(task_id)
 }
 
 #[update]
-pub fn create_token_mint_task(to: Account, amount: Nat) -> u64 {
-    ic_cdk::api::debug_print(format!("Creating token mint task for: {}", to.owner.to_string()));
-    
-    // Store mint parameters in the data field as JSON
-    let data = format!(
-        "{{\"to\":\"{}\",\"amount\":\"{}\"}}",
-        to.owner.to_string(),
-        amount.to_string()
-    );
+pub fn create_token_mint_task(agent_id: u64, symbol: String, to: Account, amount: Nat) -> Result<u64, ApiError> {
+    debug_print(format!("Creating {} mint task for: {}", symbol, to.owner));
     
     // Create the task
-    let task_id = create_task_complete(
-        0, // Use 0 to auto-assign ID
-        data,
+    let task_id = add_task(
+        msg_caller(),
+        agent_id,
+        TaskAction::Mint { symbol, to, amount },
         0, // One-time task
-        None,
-        "token_mint".to_string()
-    );
+    )?;
     
-    ic_cdk::api::debug_print(format!("Created token mint task with ID: {}", task_id));
-    task_id
+    debug_print(format!("Created token mint task with ID: {}", task_id));
+    Ok(task_id)
 }
 
 #[update]
-pub fn create_token_burn_task(from: Account, amount: Nat) -> u64 {
-    ic_cdk::api::debug_print(format!("Creating token burn task for: {}", from.owner.to_string()));
-    
-    // Store burn parameters in the data field as JSON
-    let data = format!(
-        "{{\"from\":\"{}\",\"amount\":\"{}\"}}",
-        from.owner.to_string(),
-        amount.to_string()
-    );
+pub fn create_token_burn_task(agent_id: u64, symbol: String, from: Account, amount: Nat) -> Result<u64, ApiError> {
+    debug_print(format!("Creating {} burn task for: {}", symbol, from.owner));
     
     // Create the task
-    let task_id = create_task_complete(
-        0, // Use 0 to auto-assign ID
-        data,
+    let task_id = add_task(
+        msg_caller(),
+        agent_id,
+        TaskAction::Burn { symbol, from, amount },
         0, // One-time task
-        None,
-        "token_burn".to_string()
-    );
+    )?;
     
-    ic_cdk::api::debug_print(format!("Created token burn task with ID: {}", task_id));
-    task_id
+    debug_print(format!("Created token burn task with ID: {}", task_id));
+    Ok(task_id)
 }
 
 // Get all tasks of a specific token operation type
 #[query]
-pub fn get_token_tasks_by_type(operation_type: String) -> Vec<Task> {
-    get_tasks_by_type(format!("token_{}", operation_type))
+pub fn get_token_tasks_by_type(agent_id: u64, operation_type: String) -> Result<Vec<Task>, ApiError> {
+    get_tasks_by_type(agent_id, format!("token_{}", operation_type))
 }
 
 // Helper function to get tasks by type
 #[query]
-pub fn get_tasks_by_type(task_type: String) -> Vec<Task> {
-    AGENT.with(|a| {
-        ensure_agent_initialized();
-        
-        if let Some(agent) = &*a.borrow() {
-            agent.tasks.iter()
-                .filter(|task| task.action_type == task_type)
-                .cloned()
-                .collect()
-        } else {
-            Vec::new()
-        }
-    })
+pub fn get_tasks_by_type(agent_id: u64, task_type: String) -> Result<Vec<Task>, ApiError> {
+    Ok(get_tasks(agent_id)?
+        .into_iter()
+        .filter(|task| task.action.kind() == task_type)
+        .collect())
 }
 
 // Token information queries - these don't create tasks
 #[query]
-pub fn token_balance(account: crate::token::Account) -> Nat {
-    crate::token::icrc1_balance_of(account)
+pub fn token_balance(account: Account, symbol: String) -> Result<Nat, ApiError> {
+    crate::token2::icrc2_balance_of(account, symbol)
 }
 
 #[query]
-pub fn token_metadata() -> Vec<(String, String)> {
-    crate::token::icrc1_metadata()
+pub fn token_metadata(symbol: String) -> Result<Vec<(String, String)>, ApiError> {
+    crate::token2::icrc2_metadata(symbol)
 }
 
 #[query]
-pub fn token_name() -> String {
-    crate::token::icrc1_name()
+pub fn token_name(symbol: String) -> Result<String, ApiError> {
+    crate::token2::icrc2_name(symbol)
 }
 
 #[query]
-pub fn token_symbol() -> String {
-    crate::token::icrc1_symbol()
+pub fn token_symbol(symbol: String) -> Result<String, ApiError> {
+    crate::token2::icrc2_symbol(symbol)
 }
 
 #[query]
-pub fn token_decimals() -> u8 {
-    crate::token::icrc1_decimals()
+pub fn token_decimals(symbol: String) -> Result<u8, ApiError> {
+    crate::token2::icrc2_decimals(symbol)
 }
 
 #[query]
-pub fn token_total_supply() -> Nat {
-    crate::token::icrc1_total_supply()
+pub fn token_total_supply(symbol: String) -> Result<Nat, ApiError> {
+    crate::token2::icrc2_total_supply(symbol)
 }
 
 #[query]
-pub fn token_fee() -> Nat {
-    crate::token::icrc1_fee()
+pub fn token_fee(symbol: String) -> Result<Nat, ApiError> {
+    crate::token2::icrc2_fee(symbol)
 }
 
 #[query]
-pub fn token_transactions(limit: u64) -> Vec<crate::token::Transaction> {
-    crate::token::get_transactions(limit)
+pub fn token_transactions(limit: u64, symbol: String) -> Result<Vec<Transaction>, ApiError> {
+    crate::token2::icrc2_get_transactions(limit, symbol)
 }
 
 // Task for scheduling token operations
 #[update]
-pub fn create_token_operation_task(id: u64, operation: String, data: String, frequency: u64) {
-    ic_cdk::api::debug_print(format!("Creating token operation task: {}, operation: {}", id, operation));
-    
-    AGENT.with(|a| {
-        ensure_agent_initialized();
-        
-        if let Some(agent) = &mut *a.borrow_mut() {
-            // Check for duplicate ID
-            if agent.tasks.iter().any(|t| t.id == id) {
-                ic_cdk::api::debug_print(format!("Task with ID {} already exists", id));
-                ic_cdk::trap("Task with this ID already exists");
-            }
-            
-            let task = Task { 
-                id, 
-                data, // Operation parameters as JSON
-                frequency, 
-                last_run: 0,
-                url: None,
-                action_type: format!("token_{}", operation), // "token_transfer", "token_mint", etc.
-                enabled: true,
-            };
-            
-            agent.tasks.push_back(task);
-            ic_cdk::api::debug_print(format!("Token operation task created with ID: {}", id));
-        }
+pub fn create_token_operation_task(agent_id: u64, _id: u64, action: TaskAction, frequency: u64) -> Result<u64, ApiError> {
+    debug_print(format!("Creating token operation task: {}", action.kind()));
+    if !action.kind().starts_with("token_") {
+        return Err(ApiError::InvalidArgument(format!("{} is not a token operation", action.kind())));
+    }
+    add_task(msg_caller(), agent_id, action, frequency)
+}
+
+// Keeps recurring http_request tasks from spending cycles more than once a minute
+fn check_http_schedule(task: &Task) -> Result<(), ApiError> {
+    if !matches!(task.action, TaskAction::HttpRequest { .. }) {
+        return Ok(());
+    }
+    let cron = task.cron.as_deref().and_then(|expression| expression.parse::<crate::cron::CronExpr>().ok());
+    crate::http_outcall::check_interval(task.frequency, cron.as_ref()).map_err(ApiError::InvalidArgument)
+}
+
+// When a task is next due, in seconds. Tasks without a frequency or cron run once.
+fn next_run(task: &Task) -> Option<u64> {
+    if !task.enabled || task.status != TaskStatus::Active {
+        return None;
+    }
+    if HTTP_IN_FLIGHT.with(|in_flight| in_flight.borrow().contains(&task.id)) {
+        return None;
+    }
+    if task.retry_at.is_some() {
+        return task.retry_at;
+    }
+    if let Some(expression) = &task.cron {
+        let cron: crate::cron::CronExpr = expression.parse().ok()?;
+        let after = if task.last_run == 0 { task.created_at } else { task.last_run };
+        return cron.next_after(after);
+    }
+    if task.last_run == 0 {
+        Some(0)
+    } else if task.frequency == 0 {
+        None
+    } else {
+        Some(task.last_run + task.frequency)
+    }
+}
+
+// Rebuilds the scheduler queue from the stored tasks, e.g. after an upgrade
+pub(crate) fn reschedule_all() {
+    let entries: Vec<(Job, Option<u64>)> = AGENTS.with(|a| {
+        a.borrow()
+            .values()
+            .filter(|agent| agent.active)
+            .flat_map(|agent| agent.tasks.iter().map(|task| (Job::Task(task.id), next_run(task))))
+            .collect()
+    });
+    crate::scheduler::reset(|job| matches!(job, Job::Task(_)), entries);
+}
+
+fn reschedule(task_id: u64) {
+    let due = AGENTS.with(|a| {
+        a.borrow()
+            .values()
+            .filter(|agent| agent.active)
+            .find_map(|agent| agent.tasks.iter().find(|task| task.id == task_id))
+            .and_then(next_run)
     });
+    crate::scheduler::schedule(Job::Task(task_id), due);
 }
 
-// Modified execute_tasks to process token operation tasks
+// Puts a task on a cron schedule, or back on its frequency when `expression` is None
 #[update]
-pub fn execute_tasks() {
-    let now = time() / 1_000_000_000; // seconds
-    //ic_cdk::api::debug_print(format!("Executing tasks at timestamp: {}", now));
-    
-    ensure_agent_initialized();
+pub fn set_task_cron(id: u64, expression: Option<String>) -> Result<(), ApiError> {
+    if let Some(expression) = &expression {
+        crate::cron::validate(expression).map_err(ApiError::InvalidArgument)?;
+    }
+    authorize_task(id, change_permission(id, None))?;
+    with_task(id, |task| {
+        let previous = std::mem::replace(&mut task.cron, expression);
+        check_http_schedule(task).inspect_err(|_| task.cron = previous)
+    })
+    .unwrap_or(Ok(()))?;
+    reschedule(id);
+    Ok(())
+}
+
+// Token operations act as `owner`, the owner of the task's agent
+fn run_task(task: &mut Task, owner: Principal, now: u64) {
+    // Keep track of old last_run for logging
+    let old_last_run = task.last_run;
+    let cycles_before = ic_cdk::api::canister_cycle_balance();
     
-    AGENT.with(|a| {
-        if let Some(agent) = &mut *a.borrow_mut() {
-            //let mut executed_count = 0;
-            
-            for task in agent.tasks.iter_mut() {
-                if task.enabled && (now >= task.last_run + task.frequency || task.last_run == 0) {
-                    // Keep track of old last_run for logging
-                    let old_last_run = task.last_run;
-                    
-                    // Token operations
-                    if task.action_type.starts_with("token_") {
-                        match task.action_type.as_str() {
-                            "token_init" => execute_token_init_task(task),
-                            "token_transfer" => execute_token_transfer_task(task),
-                            "token_mint" => execute_token_mint_task(task),
-                            "token_burn" => execute_token_burn_task(task),
-                            _ => {
-                                ic_cdk::api::debug_print(format!("Unknown token operation: {}", task.action_type));
-                            }
-                        }
-                    } else if task.action_type == "http_request" {
-                        // HTTP request handling
-                        ic_cdk::api::debug_print(format!("HTTP request action for task ID: {}", task.id));
-                        // HTTP outbound calls would go here
-                    } else {
-                        // Custom task handling
-                        ic_cdk::api::debug_print(format!("Custom action for task ID: {}", task.id));
-                        // Custom logic here
-                    }
-                    
-                    // Update last run time
-                    task.last_run = now;
-                    //executed_count += 1;
-                    
-                    ic_cdk::api::debug_print(format!("Executed task ID: {}, last run updated from {} to {}", 
-                           task.id, old_last_run, now));
+    match task.action.clone() {
+        TaskAction::TokenInit { name, symbol, decimals, description, logo, initial_supply, fee } => {
+            let metadata = Metadata { name, symbol, decimals, description, logo, total_supply: initial_supply, owner, fee };
+            execute_token_init_task(task, owner, metadata)
+        }
+        TaskAction::Transfer { symbol, to, amount, memo } => execute_token_transfer_task(task, owner, symbol, to, amount, memo),
+        TaskAction::Mint { symbol, to, amount } => execute_token_mint_task(task, owner, symbol, to, amount),
+        TaskAction::Burn { symbol, from, amount } => execute_token_burn_task(task, owner, symbol, from, amount),
+        TaskAction::HttpRequest { url, method, headers, body, max_response_bytes, max_cycles } => {
+            debug_print(format!("HTTP request action for task ID: {} ({})", task.id, url));
+            task.last_run = now;
+            match crate::http_outcall::prepare(url, method, headers, body, max_response_bytes, max_cycles) {
+                // The run is recorded by `complete_http_task` once the response is in
+                Ok(outcall) => {
+                    let task_id = task.id;
+                    // This is the retry, if one was pending; the response decides whether there's another
+                    task.retry_at = None;
+                    HTTP_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(task_id));
+                    ic_cdk::futures::spawn(async move {
+                        let outcome = crate::http_outcall::send(outcall).await;
+                        complete_http_task(task_id, now, outcome);
+                    });
+                    return;
                 }
+                Err(result) => task.last_result = Some(result),
             }
-            
-            //ic_cdk::api::debug_print(format!("Executed {} tasks out of {}", executed_count, agent.tasks.len()));
         }
+        TaskAction::Custom { .. } => {
+            // Custom task handling
+            debug_print(format!("Custom action for task ID: {}", task.id));
+            // Custom logic here
+            task.last_result = Some(TaskResult::Success { tx_id: None });
+        }
+    }
+    
+    // Update last run time
+    task.last_run = now;
+    apply_retry_policy(task, now);
+
+    if let Some(result) = &task.last_result {
+        let cycles_used = cycles_before.saturating_sub(ic_cdk::api::canister_cycle_balance());
+        let finished_at = time() / 1_000_000_000;
+        crate::task_runs::record(task.id, now, finished_at, result, u64::try_from(cycles_used).unwrap_or(u64::MAX), None);
+    }
+    
+    debug_print(format!("Executed task ID: {}, last run updated from {} to {}", 
+           task.id, old_last_run, now));
+}
+
+// Finishes a run of an http_request task started at `started_at`
+fn complete_http_task(task_id: u64, started_at: u64, outcome: crate::http_outcall::OutcallOutcome) {
+    if record_http_run(task_id, started_at, time() / 1_000_000_000, outcome) {
+        reschedule(task_id);
+    }
+}
+
+// Records the response as a run of the task and lets it run again.
+// False when the task was deleted while its request was in flight.
+fn record_http_run(task_id: u64, started_at: u64, finished_at: u64, outcome: crate::http_outcall::OutcallOutcome) -> bool {
+    HTTP_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&task_id));
+    let found = with_task(task_id, |task| {
+        crate::task_runs::record(task_id, started_at, finished_at, &outcome.result, outcome.cycles_used, outcome.response);
+        task.last_result = Some(outcome.result);
+        apply_retry_policy(task, finished_at);
     });
+    if found.is_none() {
+        debug_print(format!("Task {} was deleted while its HTTP request was in flight", task_id));
+    }
+    found.is_some()
 }
 
-// Token task execution helpers
-fn execute_token_init_task(task: &mut Task) {
-    ic_cdk::api::debug_print(format!("Executing token initialization task: {}", task.id));
-    
-    // Parse the JSON from data field
-    match serde_json::from_str::<serde_json::Value>(&task.data) {
-        Ok(json_data) => {
-            let name = json_data["name"].as_str().unwrap_or_default().to_string();
-            let symbol = json_data["symbol"].as_str().unwrap_or_default().to_string();
-            let decimals = json_data["decimals"].as_u64().unwrap_or(8) as u8;
-            
-            let initial_supply_str = json_data["initial_supply"].as_str().unwrap_or("0");
-            let fee_str = json_data["fee"].as_str().unwrap_or("0");
-            
-            let initial_supply = Nat::from(u64::from_str_radix(initial_supply_str, 10).unwrap_or(0));
-            let fee = Nat::from(u64::from_str_radix(fee_str, 10).unwrap_or(0));
-            
-            let description = json_data["description"].as_str().map(|s| s.to_string());
-            let logo = json_data["logo"].as_str().map(|s| s.to_string());
-            
-            // Call the actual token init function
-            let result = crate::token::icrc1_init(
-                name, 
-                symbol, 
-                decimals, 
-                description, 
-                logo, 
-                initial_supply, 
-                fee
-            );
-            
-            // Update task with result
-            let status_update = format!("{{\"status\":\"{}\"}}", if result { "success" } else { "failed" });
-            update_task_data(task, &status_update);
-            
-            ic_cdk::api::debug_print(format!("Token initialization result: {}", result));
-            
-            // Disable the task after execution as it's a one-time operation
+// Schedules a retry after a failed run, or moves the task to the dead-letter list
+// once its policy gives up on it
+fn apply_retry_policy(task: &mut Task, now: u64) {
+    let Some(TaskResult::Failed { kind, .. }) = &task.last_result else {
+        task.attempts = 0;
+        task.retry_at = None;
+        return;
+    };
+    task.attempts += 1;
+    let seed = time() ^ task.id.rotate_left(32);
+    match task.retry_policy.next_delay(task.attempts, *kind, seed) {
+        Some(delay) => {
+            // One-shot tasks disable themselves after running; keep them alive for the retry
+            task.enabled = true;
+            task.retry_at = Some(now.saturating_add(delay));
+            debug_print(format!("Task {} failed (attempt {}), retrying in {}s", task.id, task.attempts, delay));
+        }
+        None => {
             task.enabled = false;
-        },
-        Err(e) => {
-            ic_cdk::api::debug_print(format!("Failed to parse token initialization data: {}", e));
-            let status_update = format!("{{\"status\":\"failed\",\"error\":\"Parse error: {}\"}}", e);
-            update_task_data(task, &status_update);
+            task.retry_at = None;
+            task.status = TaskStatus::Failed { failed_at: now };
+            debug_print(format!("Task {} failed after {} attempt(s), moved to dead letters", task.id, task.attempts));
         }
     }
 }
 
-fn execute_token_transfer_task(task: &mut Task) {
-    ic_cdk::api::debug_print(format!("Executing token transfer task: {}", task.id));
-    
-    match serde_json::from_str::<serde_json::Value>(&task.data) {
-        Ok(json_data) => {
-            if let (Some(to_principal), Some(amount_str)) = (
-                json_data["to"].as_str(), 
-                json_data["amount"].as_str()
-            ) {
-                let to = crate::token::Account {
-                    owner: Principal::from_text(to_principal).unwrap_or(Principal::anonymous()),
-                    subaccount: None,
-                };
-                
-                let amount = Nat::from(u64::from_str_radix(amount_str, 10).unwrap_or(0));
-                let memo = json_data["memo"].as_str().map(|s| s.as_bytes().to_vec());
-                
-                let transfer_args = crate::token::TransferArgs {
-                    from_subaccount: None,
-                    to,
-                    amount,
-                    fee: None,
-                    memo,
-                    created_at_time: Some(time() / 1_000_000_000),
-                };
-                
-                match crate::token::icrc1_transfer(transfer_args) {
-                    crate::token::TransferResult::Ok(tx_id) => {
-                        ic_cdk::api::debug_print(format!("Token transfer successful, tx_id: {}", tx_id));
-                        let status_update = format!("{{\"status\":\"success\",\"tx_id\":\"{}\"}}", tx_id);
-                        update_task_data(task, &status_update);
-                    },
-                    crate::token::TransferResult::Err(err) => {
-                        ic_cdk::api::debug_print(format!("Token transfer failed: {:?}", err));
-                        let status_update = format!("{{\"status\":\"failed\",\"error\":\"{:?}\"}}", err);
-                        update_task_data(task, &status_update);
-                    }
-                }
-                
-                // Task is completed, disable it
-                task.enabled = false;
-            } else {
-                ic_cdk::api::debug_print("Missing required fields for token transfer");
-                let status_update = "{{\"status\":\"failed\",\"error\":\"Missing required fields\"}}";
-                update_task_data(task, status_update);
-            }
-        },
-        Err(e) => {
-            ic_cdk::api::debug_print(format!("Failed to parse token transfer data: {}", e));
-            let status_update = format!("{{\"status\":\"failed\",\"error\":\"Parse error: {}\"}}", e);
-            update_task_data(task, &status_update);
+#[update]
+pub fn set_task_retry_policy(id: u64, policy: RetryPolicy) -> Result<(), ApiError> {
+    policy.validate().map_err(ApiError::InvalidArgument)?;
+    authorize_task(id, Permission::Manage)?;
+    with_task(id, |task| task.retry_policy = policy);
+    Ok(())
+}
+
+// Tasks of an agent that exhausted their retries or hit a non-retryable error
+#[query]
+pub fn get_dead_letter_tasks(agent_id: u64) -> Result<Vec<Task>, ApiError> {
+    Ok(get_tasks(agent_id)?
+        .into_iter()
+        .filter(|task| task.status != TaskStatus::Active)
+        .collect())
+}
+
+// Puts a dead-lettered task back on the schedule with a fresh set of attempts
+#[update]
+pub fn retry_dead_letter_task(id: u64) -> Result<(), ApiError> {
+    authorize_task(id, Permission::Manage)?;
+    let requeued = with_task(id, |task| {
+        if task.status == TaskStatus::Active {
+            return false;
         }
+        task.status = TaskStatus::Active;
+        task.enabled = true;
+        task.attempts = 0;
+        task.retry_at = Some(time() / 1_000_000_000);
+        true
+    });
+    if requeued != Some(true) {
+        return Err(ApiError::InvalidState(format!("Task {} has not failed", id)));
     }
+    reschedule(id);
+    Ok(())
 }
 
-fn execute_token_mint_task(task: &mut Task) {
-    ic_cdk::api::debug_print(format!("Executing token mint task: {}", task.id));
-    
-    match serde_json::from_str::<serde_json::Value>(&task.data) {
-        Ok(json_data) => {
-            if let (Some(to_principal), Some(amount_str)) = (
-                json_data["to"].as_str(), 
-                json_data["amount"].as_str()
-            ) {
-                let to = crate::token::Account {
-                    owner: Principal::from_text(to_principal).unwrap_or(Principal::anonymous()),
-                    subaccount: None,
-                };
-                
-                let amount = Nat::from(u64::from_str_radix(amount_str, 10).unwrap_or(0));
-                
-                match crate::token::mint(to, amount) {
-                    crate::token::TransferResult::Ok(tx_id) => {
-                        ic_cdk::api::debug_print(format!("Token minting successful, tx_id: {}", tx_id));
-                        let status_update = format!("{{\"status\":\"success\",\"tx_id\":\"{}\"}}", tx_id);
-                        update_task_data(task, &status_update);
-                    },
-                    crate::token::TransferResult::Err(err) => {
-                        ic_cdk::api::debug_print(format!("Token minting failed: {:?}", err));
-                        let status_update = format!("{{\"status\":\"failed\",\"error\":\"{:?}\"}}", err);
-                        update_task_data(task, &status_update);
-                    }
-                }
-                
-                // Task is completed, disable it
-                task.enabled = false;
-            } else {
-                ic_cdk::api::debug_print("Missing required fields for token minting");
-                let status_update = "{{\"status\":\"failed\",\"error\":\"Missing required fields\"}}";
-                update_task_data(task, status_update);
-            }
-        },
-        Err(e) => {
-            ic_cdk::api::debug_print(format!("Failed to parse token minting data: {}", e));
-            let status_update = format!("{{\"status\":\"failed\",\"error\":\"Parse error: {}\"}}", e);
-            update_task_data(task, &status_update);
+// Called by the scheduler when `task_id` is due. Returns when it is due next.
+pub(crate) fn run_scheduled_task(task_id: u64, now: u64) -> Option<u64> {
+    AGENTS.with(|a| {
+        let mut agents = a.borrow_mut();
+        let (owner, task) = agents
+            .values_mut()
+            .filter(|agent| agent.active)
+            .find_map(|agent| {
+                let owner = agent.owner;
+                agent.tasks.iter_mut().find(|task| task.id == task_id).map(|task| (owner, task))
+            })?;
+        if next_run(task).is_some_and(|due| due <= now) {
+            run_task(task, owner, now);
         }
-    }
+        next_run(task)
+    })
 }
 
-fn execute_token_burn_task(task: &mut Task) {
-    ic_cdk::api::debug_print(format!("Executing token burn task: {}", task.id));
+// Runs every due task of an agent right away; the scheduler normally does this on its own
+#[update]
+pub fn execute_tasks(agent_id: u64) -> Result<(), ApiError> {
+    let now = time() / 1_000_000_000; // seconds
+    
+    authorize(agent_id, Permission::Execute)?;
     
-    match serde_json::from_str::<serde_json::Value>(&task.data) {
-        Ok(json_data) => {
-            if let (Some(from_principal), Some(amount_str)) = (
-                json_data["from"].as_str(), 
-                json_data["amount"].as_str()
-            ) {
-                let from = crate::token::Account {
-                    owner: Principal::from_text(from_principal).unwrap_or(Principal::anonymous()),
-                    subaccount: None,
-                };
-                
-                let amount = Nat::from(u64::from_str_radix(amount_str, 10).unwrap_or(0));
-                
-                match crate::token::burn(from, amount) {
-                    crate::token::TransferResult::Ok(tx_id) => {
-                        ic_cdk::api::debug_print(format!("Token burning successful, tx_id: {}", tx_id));
-                        let status_update = format!("{{\"status\":\"success\",\"tx_id\":\"{}\"}}", tx_id);
-                        update_task_data(task, &status_update);
-                    },
-                    crate::token::TransferResult::Err(err) => {
-                        ic_cdk::api::debug_print(format!("Token burning failed: {:?}", err));
-                        let status_update = format!("{{\"status\":\"failed\",\"error\":\"{:?}\"}}", err);
-                        update_task_data(task, &status_update);
-                    }
+    AGENTS.with(|a| {
+        if let Some(agent) = a.borrow_mut().get_mut(&agent_id) {
+            let owner = agent.owner;
+            for task in agent.tasks.iter_mut() {
+                if next_run(task).is_some_and(|due| due <= now) {
+                    run_task(task, owner, now);
                 }
-                
-                // Task is completed, disable it
-                task.enabled = false;
-            } else {
-                ic_cdk::api::debug_print("Missing required fields for token burning");
-                let status_update = "{{\"status\":\"failed\",\"error\":\"Missing required fields\"}}";
-                update_task_data(task, status_update);
             }
-        },
-        Err(e) => {
-            ic_cdk::api::debug_print(format!("Failed to parse token burning data: {}", e));
-            let status_update = format!("{{\"status\":\"failed\",\"error\":\"Parse error: {}\"}}", e);
-            update_task_data(task, &status_update);
         }
-    }
+    });
+    reschedule_all();
+    Ok(())
 }
 
-// Helper to update task data
-fn update_task_data(task: &mut Task, status_update: &str) {
+// Token task execution helpers
+fn execute_token_init_task(task: &mut Task, owner: Principal, metadata: Metadata) {
+    debug_print(format!("Executing token initialization task: {}", task.id));
+    
+    let result = crate::token2::init_token(owner, metadata);
+    debug_print(format!("Token initialization result: {:?}", result));
+    task.last_result = Some(match result {
+        Ok(_) => TaskResult::Success { tx_id: None },
+        Err(err) => TaskResult::Failed { error: err.to_string(), kind: TaskErrorKind::from(&err) },
+    });
+    
+    // Disable the task after execution as it's a one-time operation
+    task.enabled = false;
+}
 
-    // Append the status update to existing data
-    let mut data_value: serde_json::Value = serde_json::from_str(&task.data).unwrap_or(serde_json::json!({}));
-    let status_value: serde_json::Value = serde_json::from_str(status_update).unwrap_or(serde_json::json!({}));
+fn execute_token_transfer_task(task: &mut Task, owner: Principal, symbol: String, to: Account, amount: Nat, memo: Option<Vec<u8>>) {
+    debug_print(format!("Executing token transfer task: {}", task.id));
     
-    // Merge the objects
-    if let (Some(data_obj), Some(status_obj)) = (data_value.as_object_mut(), status_value.as_object()) {
-        for (key, value) in status_obj {
-            data_obj.insert(key.clone(), value.clone());
-        }
-    }
+    let transfer_args = TransferArgs {
+        from_subaccount: None,
+        to,
+        amount,
+        fee: None,
+        memo,
+        created_at_time: None,
+    };
     
-    task.data = data_value.to_string();
-    ic_cdk::api::debug_print(format!("Updated task {} data: {}", task.id, task.data));
+    let result = crate::token2::transfer_as(owner, symbol, transfer_args);
+    debug_print(format!("Token transfer result: {:?}", result));
+    record_transfer_result(task, result);
+    
+    // Task is completed, disable it
+    task.enabled = false;
 }
 
-// Agent retirement
-#[update]
-pub fn retire_agent() {
-    assert_owner();
-    AGENT.with(|a| *a.borrow_mut() = None);
-    ic_cdk::api::debug_print("Agent retired");
-}
-
-// Permissions: only owner can mutate
-fn assert_owner() {
-    AGENT.with(|a| {
-        if let Some(agent) = &*a.borrow() {
-            if agent.owner != ic_cdk::api::msg_caller() {
-                ic_cdk::api::debug_print(format!("Authorization failed: {} is not the owner {}", 
-                      ic_cdk::api::msg_caller().to_string(), agent.owner.to_string()));
-                ic_cdk::trap("Not authorized");
-            }
-        } else {
-            ic_cdk::api::debug_print("Agent not initialized");
-            ic_cdk::trap("Agent not initialized");
-        }
+fn execute_token_mint_task(task: &mut Task, owner: Principal, symbol: String, to: Account, amount: Nat) {
+    debug_print(format!("Executing token mint task: {}", task.id));
+    
+    let result = crate::token2::mint_as(owner, to, amount, symbol);
+    debug_print(format!("Token minting result: {:?}", result));
+    record_transfer_result(task, result);
+    
+    // Task is completed, disable it
+    task.enabled = false;
+}
+
+// Burns out of the agent owner's own account; `from` names the subaccount
+fn execute_token_burn_task(task: &mut Task, owner: Principal, symbol: String, from: Account, amount: Nat) {
+    debug_print(format!("Executing token burn task: {}", task.id));
+    
+    let result = if from.owner == owner {
+        let burn_args = BurnArgs { from_subaccount: from.subaccount, amount, memo: None, created_at_time: None };
+        crate::token2::burn_as(owner, symbol, burn_args)
+    } else {
+        Err(ApiError::Unauthorized(format!("Agent tasks can only burn tokens of {}", owner)))
+    };
+    debug_print(format!("Token burning result: {:?}", result));
+    record_transfer_result(task, result);
+    
+    // Task is completed, disable it
+    task.enabled = false;
+}
+
+fn record_transfer_result(task: &mut Task, result: Result<Nat, ApiError>) {
+    task.last_result = Some(match result {
+        Ok(tx_id) => TaskResult::Success { tx_id: Some(tx_id) },
+        Err(err) => TaskResult::Failed { error: err.to_string(), kind: TaskErrorKind::from(&err) },
     });
 }
 
+// Agent retirement: drops the agent's task queue and run history, and archives the agent
+#[update]
+pub fn retire_agent(agent_id: u64) -> Result<(), ApiError> {
+    authorize(agent_id, Permission::Administer)?;
+    remove_task_queue(agent_id);
+    crate::agent::archive_agent(agent_id);
+    debug_print(format!("Agent {} retired", agent_id));
+    Ok(())
+}
+
+pub(crate) fn remove_task_queue(agent_id: u64) {
+    let agent = AGENTS.with(|a| a.borrow_mut().remove(&agent_id));
+    for task in agent.map(|agent| agent.tasks).unwrap_or_default() {
+        crate::scheduler::schedule(Job::Task(task.id), None);
+        crate::task_runs::forget(task.id);
+    }
+}
+
+// Inactive queues keep their tasks but none of them run on schedule
+pub(crate) fn set_agent_active(agent_id: u64, active: bool) {
+    let found = AGENTS.with(|a| a.borrow_mut().get_mut(&agent_id).map(|agent| agent.active = active));
+    if found.is_some() {
+        reschedule_all();
+    }
+}
+
 // Cycle management (placeholder, as cycles API is limited from Rust)
 #[query]
 pub fn cycles_available() -> u128 {
     let cycles = ic_cdk::api::canister_cycle_balance();
-    ic_cdk::api::debug_print(format!("Available cycles: {}", cycles));
+    debug_print(format!("Available cycles: {}", cycles));
     cycles
 }
 
-// Helper function to ensure agent is initialized
-#[ic_cdk::heartbeat]
-fn heartbeat() {
-    // Periodically execute tasks on the canister heartbeat (called roughly every second)
-    execute_tasks();
-}
-
-fn ensure_agent_initialized() {
-    AGENT.with(|a| {
-        if a.borrow().is_none() {
-            let new_agent = Agent {
-                owner: msg_caller(),
-                tasks: VecDeque::new(),
-                active: true,
-                created_at: time() / 1_000_000_000,
-            };
-            *a.borrow_mut() = Some(new_agent);
-            ic_cdk::api::debug_print(format!("Agent auto-initialized with owner: {}", msg_caller().to_string()));
+// Unit tests run outside a canister: a fixed clock, and nowhere to log to
+#[cfg(test)]
+fn time() -> u64 {
+    1_700_000_000_000_000_000
+}
+
+#[cfg(test)]
+fn debug_print(_message: String) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_outcall::OutcallOutcome;
+    use crate::task_runs::TaskRunStatus;
+    use ic_cdk::management_canister::HttpRequestResult;
+
+    fn http_task(id: u64, frequency: u64) -> Task {
+        let action = TaskAction::HttpRequest {
+            url: "https://example.com".to_string(),
+            method: HttpMethod::GET,
+            headers: Vec::new(),
+            body: None,
+            max_response_bytes: None,
+            max_cycles: None,
+        };
+        Task::new(id, action, frequency, 0)
+    }
+
+    #[test]
+    fn task_ids_are_never_reused() {
+        let mut agent = Agent { owner: Principal::anonymous(), tasks: VecDeque::new(), active: true, created_at: 0, roles: BTreeMap::new() };
+        agent.tasks.push_back(http_task(3, 60));
+        // Snapshots without a counter continue after their highest task ID
+        restore_stable_state(BTreeMap::from([(1, agent.clone())]), 0, None, None);
+        assert_eq!(claim_task_id(), Ok(4));
+        assert_eq!(claim_task_id(), Ok(5));
+        // The counter doesn't go back when the tasks holding the highest IDs are gone
+        restore_stable_state(BTreeMap::from([(1, agent)]), 20, None, None);
+        assert_eq!(claim_task_id(), Ok(20));
+        assert_eq!(take_stable_state().1, 21);
+    }
+
+    #[test]
+    fn deleted_task_ids_are_not_handed_out_again() {
+        let mut agent = Agent { owner: Principal::anonymous(), tasks: VecDeque::new(), active: true, created_at: 0, roles: BTreeMap::new() };
+        agent.tasks.push_back(http_task(1, 60));
+        agent.tasks.push_back(http_task(2, 60));
+        restore_stable_state(BTreeMap::from([(1, agent)]), 3, None, None);
+        AGENTS.with(|a| a.borrow_mut().get_mut(&1).unwrap().tasks.retain(|task| task.id != 2));
+        assert_eq!(claim_task_id(), Ok(3));
+        assert_eq!(agent_of_task(2), None);
+    }
+
+    #[test]
+    fn running_out_of_task_ids_is_an_error() {
+        // A task holding the last ID, e.g. one created when callers could pick IDs
+        let mut agent = Agent { owner: Principal::anonymous(), tasks: VecDeque::new(), active: true, created_at: 0, roles: BTreeMap::new() };
+        agent.tasks.push_back(http_task(u64::MAX, 60));
+        restore_stable_state(BTreeMap::from([(1, agent)]), 0, None, None);
+        assert!(matches!(claim_task_id(), Err(ApiError::QuotaExceeded(_))));
+        assert!(matches!(claim_task_id(), Err(ApiError::QuotaExceeded(_))));
+        assert_eq!(take_stable_state().1, u64::MAX);
+
+        restore_stable_state(BTreeMap::new(), u64::MAX - 1, None, None);
+        assert_eq!(claim_task_id(), Ok(u64::MAX - 1));
+        assert!(matches!(claim_task_id(), Err(ApiError::QuotaExceeded(_))));
+    }
+
+    #[test]
+    fn in_flight_requests_are_not_due() {
+        let mut task = http_task(1, 60);
+        task.last_run = 100;
+        task.retry_at = Some(50);
+        assert_eq!(next_run(&task), Some(50));
+        HTTP_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(task.id));
+        assert_eq!(next_run(&task), None);
+        HTTP_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&task.id));
+        task.retry_at = None;
+        assert_eq!(next_run(&task), Some(160));
+    }
+
+    #[test]
+    fn http_tasks_need_a_minute_between_runs() {
+        assert!(check_http_schedule(&http_task(1, 0)).is_ok());
+        assert!(check_http_schedule(&http_task(1, 60)).is_ok());
+        assert!(matches!(check_http_schedule(&http_task(1, 1)), Err(ApiError::InvalidArgument(_))));
+        let mut every_second = http_task(1, 3600);
+        every_second.cron = Some("* * * * * *".to_string());
+        assert!(check_http_schedule(&every_second).is_err());
+        // Other tasks can run as often as they like
+        assert!(check_http_schedule(&Task::new(2, TaskAction::Custom { data: String::new() }, 1, 0)).is_ok());
+    }
+
+    fn start_http_run(task_id: u64) {
+        let mut agent = Agent { owner: Principal::anonymous(), tasks: VecDeque::new(), active: true, created_at: 0, roles: BTreeMap::new() };
+        agent.tasks.push_back(http_task(task_id, 60));
+        restore_stable_state(BTreeMap::from([(1, agent)]), 0, None, None);
+        HTTP_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(task_id));
+    }
+
+    fn in_flight(task_id: u64) -> bool {
+        HTTP_IN_FLIGHT.with(|in_flight| in_flight.borrow().contains(&task_id))
+    }
+
+    fn recorded_runs(task_id: u64) -> Vec<crate::task_runs::TaskRun> {
+        let history = crate::task_runs::take_stable_state();
+        history.logs.get(&task_id).map(|log| log.runs.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    #[test]
+    fn responses_are_recorded_with_their_cycles() {
+        start_http_run(1);
+        let response = HttpRequestResult { status: Nat::from(200u32), headers: Vec::new(), body: b"ok".to_vec() };
+        let outcome = OutcallOutcome { result: TaskResult::Success { tx_id: None }, response: Some(response.clone()), cycles_used: 600 };
+        assert!(record_http_run(1, 100, 105, outcome));
+        assert!(!in_flight(1));
+        let task = with_task(1, |task| task.clone()).unwrap();
+        assert_eq!(task.last_result, Some(TaskResult::Success { tx_id: None }));
+        assert_eq!((task.attempts, task.retry_at), (0, None));
+        let runs = recorded_runs(1);
+        assert_eq!(runs.len(), 1);
+        assert_eq!((runs[0].status.clone(), runs[0].started_at, runs[0].finished_at), (TaskRunStatus::Succeeded, 100, 105));
+        assert_eq!(runs[0].cycles_used, 600);
+        assert_eq!(runs[0].http_response, Some(response));
+    }
+
+    #[test]
+    fn failed_outcalls_are_recorded_and_retried() {
+        start_http_run(1);
+        let failed = TaskResult::Failed { error: "HTTP outcall failed".to_string(), kind: TaskErrorKind::CallRejected };
+        let outcome = OutcallOutcome { result: failed.clone(), response: None, cycles_used: 600 };
+        assert!(record_http_run(1, 100, 105, outcome));
+        assert!(!in_flight(1));
+        let task = with_task(1, |task| task.clone()).unwrap();
+        assert_eq!(task.last_result, Some(failed));
+        assert_eq!(task.attempts, 1);
+        assert!(task.retry_at.is_some_and(|retry_at| retry_at > 105));
+        assert_eq!(next_run(&task), task.retry_at);
+        let runs = recorded_runs(1);
+        assert_eq!((runs[0].status.clone(), runs[0].cycles_used, runs[0].http_response.clone()), (TaskRunStatus::Failed, 600, None));
+    }
+
+    #[test]
+    fn responses_for_deleted_tasks_are_dropped() {
+        start_http_run(1);
+        HTTP_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(2));
+        let outcome = OutcallOutcome { result: TaskResult::Success { tx_id: None }, response: None, cycles_used: 600 };
+        assert!(!record_http_run(2, 100, 105, outcome));
+        assert!(!in_flight(2));
+        assert!(recorded_runs(2).is_empty());
+    }
+
+    #[test]
+    fn operators_cannot_set_up_token_tasks() {
+        let (owner, operator) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let mut agent = Agent { owner, tasks: VecDeque::new(), active: true, created_at: 0, roles: BTreeMap::new() };
+        agent.roles.insert(operator, Role::Operator);
+        agent.tasks.push_back(Task::new(1, TaskAction::Custom { data: String::new() }, 60, 0));
+        let account = Account { owner, subaccount: None };
+        let transfer = TaskAction::Transfer { symbol: "ABC".to_string(), to: account.clone(), amount: Nat::from(10u64), memo: None };
+        agent.tasks.push_back(Task::new(2, transfer.clone(), 0, 0));
+        restore_stable_state(BTreeMap::from([(1, agent)]), 3, None, None);
+
+        let token_actions = [
+            transfer.clone(),
+            TaskAction::Mint { symbol: "ABC".to_string(), to: account.clone(), amount: Nat::from(10u64) },
+            TaskAction::Burn { symbol: "ABC".to_string(), from: account, amount: Nat::from(10u64) },
+        ];
+        for action in token_actions {
+            assert!(matches!(add_task(operator, 1, action, 0), Err(ApiError::Unauthorized(_))));
         }
-    });
+        // Nor turn another task into one, or reschedule an existing one
+        assert!(matches!(update_task_as(operator, 1, Some(transfer), None, None), Err(ApiError::Unauthorized(_))));
+        assert!(matches!(update_task_as(operator, 2, None, Some(60), None), Err(ApiError::Unauthorized(_))));
+        let tasks: Vec<Task> = AGENTS.with(|a| a.borrow()[&1].tasks.iter().cloned().collect());
+        assert_eq!(tasks.len(), 2);
+        assert_eq!((tasks[0].action.kind(), tasks[1].frequency), ("custom", 0));
+        // Owners may, and operators still manage the other tasks
+        assert_eq!(change_permission(2, None), Permission::Administer);
+        assert!(has_permission(1, owner, Permission::Administer));
+        assert!(has_permission(1, operator, change_permission(1, None)));
+    }
 }
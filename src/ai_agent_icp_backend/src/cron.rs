@@ -0,0 +1,306 @@
+use ic_cdk::api::time;
+use ic_cdk_macros::query;
+use std::str::FromStr;
+
+use crate::error::ApiError;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+// Long enough to find a Feb 29 across a skipped leap year (e.g. 2096 -> 2104)
+const MAX_SEARCH_DAYS: u64 = 9 * 366;
+const MAX_FIRE_TIMES: u32 = 100;
+
+const MONTH_NAMES: [&str; 12] = ["JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC"];
+const DAY_NAMES: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+// A parsed cron expression, evaluated in UTC. Accepts the 5-field form
+// (minute hour day-of-month month day-of-week) and the 6-field form with a leading
+// seconds field, as used by the off-chain toad-scheduler. Each field takes `*`, `?`
+// (day fields only), values, ranges `a-b`, steps `*/n` `a/n` `a-b/n`, and `,` lists;
+// months and days of week also take names (JAN, MON...), and 7 is Sunday too.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CronExpr {
+    seconds: u64,  // bit n set = second n
+    minutes: u64,
+    hours: u64,
+    days: u64,     // 1..=31
+    months: u64,   // 1..=12
+    weekdays: u64, // 0..=6, Sunday = 0
+    // Like Vixie cron: when both day fields are restricted a day matching either one fires
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+fn parse_value(token: &str, names: Option<&[&str]>, offset: u32) -> Result<u32, String> {
+    if let Ok(value) = token.parse::<u32>() {
+        return Ok(value);
+    }
+    names
+        .and_then(|names| names.iter().position(|name| name.eq_ignore_ascii_case(token)))
+        .map(|index| index as u32 + offset)
+        .ok_or_else(|| format!("invalid value '{}'", token))
+}
+
+// Parses one field into a bitmask of the values in min..=max
+fn parse_field(field: &str, min: u32, max: u32, names: Option<&[&str]>, name_offset: u32, allow_any: bool) -> Result<u64, String> {
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step = step.parse::<u32>().map_err(|_| format!("invalid step '{}'", step))?;
+                if step == 0 {
+                    return Err("step must be greater than zero".to_string());
+                }
+                (range, Some(step))
+            }
+            None => (part, None),
+        };
+        let (start, end) = if range == "*" || (allow_any && range == "?") {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (parse_value(start, names, name_offset)?, parse_value(end, names, name_offset)?)
+        } else {
+            let start = parse_value(range, names, name_offset)?;
+            // `a/n` runs from a to the end of the field
+            (start, if step.is_some() { max } else { start })
+        };
+        if start < min || end > max || start > end {
+            return Err(format!("'{}' is outside {}-{}", part, min, max));
+        }
+        let mut value = start;
+        while value <= end {
+            mask |= 1 << value;
+            value += step.unwrap_or(1);
+        }
+    }
+    Ok(mask)
+}
+
+impl FromStr for CronExpr {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let (seconds, rest) = match fields.len() {
+            5 => ("0", &fields[..]),
+            6 => (fields[0], &fields[1..]),
+            n => return Err(format!("expected 5 or 6 fields, found {}", n)),
+        };
+        let field = |name: &str, result: Result<u64, String>| result.map_err(|e| format!("{} field: {}", name, e));
+        let mut weekdays = field("day-of-week", parse_field(rest[4], 0, 7, Some(&DAY_NAMES), 0, true))?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(CronExpr {
+            seconds: field("seconds", parse_field(seconds, 0, 59, None, 0, false))?,
+            minutes: field("minute", parse_field(rest[0], 0, 59, None, 0, false))?,
+            hours: field("hour", parse_field(rest[1], 0, 23, None, 0, false))?,
+            days: field("day-of-month", parse_field(rest[2], 1, 31, None, 0, true))?,
+            months: field("month", parse_field(rest[3], 1, 12, Some(&MONTH_NAMES), 1, false))?,
+            weekdays,
+            days_restricted: !(rest[2].starts_with('*') || rest[2] == "?"),
+            weekdays_restricted: !(rest[4].starts_with('*') || rest[4] == "?"),
+        })
+    }
+}
+
+// (year, month, day) of a day count since 1970-01-01, from Howard Hinnant's date algorithms
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+fn has(mask: u64, value: u64) -> bool {
+    mask & (1 << value) != 0
+}
+
+impl CronExpr {
+    fn matches_day(&self, days: u64) -> bool {
+        let (_, month, day) = civil_from_days(days);
+        if !has(self.months, month) {
+            return false;
+        }
+        let weekday = (days + 4) % 7; // 1970-01-01 was a Thursday
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => has(self.days, day) || has(self.weekdays, weekday),
+            _ => has(self.days, day) && has(self.weekdays, weekday),
+        }
+    }
+
+    // Earliest matching second of a day at or after `from` seconds into it
+    fn first_time_of_day(&self, from: u64) -> Option<u64> {
+        let (from_hour, from_minute, from_second) = (from / 3600, from / 60 % 60, from % 60);
+        for hour in (from_hour..24).filter(|h| has(self.hours, *h)) {
+            let first_minute = if hour == from_hour { from_minute } else { 0 };
+            for minute in (first_minute..60).filter(|m| has(self.minutes, *m)) {
+                let first_second = if hour == from_hour && minute == from_minute { from_second } else { 0 };
+                if let Some(second) = (first_second..60).find(|s| has(self.seconds, *s)) {
+                    return Some(hour * 3600 + minute * 60 + second);
+                }
+            }
+        }
+        None
+    }
+
+    // First fire time strictly after `after` (seconds since epoch), if any in the next years
+    pub fn next_after(&self, after: u64) -> Option<u64> {
+        let start = after.checked_add(1)?;
+        let first_day = start / SECONDS_PER_DAY;
+        let mut from = start % SECONDS_PER_DAY;
+        for day in first_day..first_day + MAX_SEARCH_DAYS {
+            if self.matches_day(day) {
+                if let Some(second) = self.first_time_of_day(from) {
+                    return Some(day * SECONDS_PER_DAY + second);
+                }
+            }
+            from = 0;
+        }
+        None
+    }
+
+    // Only six-field expressions can fire twice in the same minute
+    pub fn fires_more_than_once_a_minute(&self) -> bool {
+        self.seconds.count_ones() > 1
+    }
+
+    pub fn fire_times(&self, after: u64, count: u32) -> Vec<u64> {
+        let mut times = Vec::new();
+        let mut last = after;
+        while times.len() < count.min(MAX_FIRE_TIMES) as usize {
+            match self.next_after(last) {
+                Some(next) => {
+                    times.push(next);
+                    last = next;
+                }
+                None => break,
+            }
+        }
+        times
+    }
+}
+
+// Rejects expressions that don't parse or can never fire (e.g. "0 0 30 2 *")
+pub fn validate(expression: &str) -> Result<CronExpr, String> {
+    let cron: CronExpr = expression.parse().map_err(|e| format!("Invalid cron expression '{}': {}", expression, e))?;
+    if cron.next_after(time() / 1_000_000_000).is_none() {
+        return Err(format!("Cron expression '{}' never fires", expression));
+    }
+    Ok(cron)
+}
+
+// Next `count` (at most 100) fire times of `expression`, in seconds since epoch
+#[query]
+pub fn cron_next_fire_times(expression: String, count: u32) -> Result<Vec<u64>, ApiError> {
+    let cron = validate(&expression).map_err(ApiError::InvalidArgument)?;
+    Ok(cron.fire_times(time() / 1_000_000_000, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JAN_1_2024: u64 = 1_704_067_200; // a Monday, 00:00:00 UTC
+    const HOUR: u64 = 60 * 60;
+
+    fn cron(expression: &str) -> CronExpr {
+        expression.parse().unwrap()
+    }
+
+    fn day(n: u64) -> u64 {
+        JAN_1_2024 + (n - 1) * SECONDS_PER_DAY // 00:00 on January n, 2024
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for expression in [
+            "* * * *",
+            "* * * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "? * * * *",
+            "* * * FOO *",
+            "* * * * MONDAY",
+            "1,,2 * * * *",
+        ] {
+            assert!(expression.parse::<CronExpr>().is_err(), "{} should not parse", expression);
+        }
+    }
+
+    #[test]
+    fn ranges_steps_and_lists() {
+        let every_quarter_hour_at_work = cron("*/15 9-17 * * *");
+        assert_eq!(every_quarter_hour_at_work.next_after(day(1)), Some(day(1) + 9 * HOUR));
+        assert_eq!(every_quarter_hour_at_work.next_after(day(1) + 9 * HOUR), Some(day(1) + 9 * HOUR + 15 * 60));
+        assert_eq!(every_quarter_hour_at_work.next_after(day(1) + 17 * HOUR + 45 * 60), Some(day(2) + 9 * HOUR));
+
+        // `a/n` runs to the end of the field, `a-b/n` stops at b
+        assert_eq!(cron("50/5 * * * *").fire_times(day(1), 3), vec![day(1) + 50 * 60, day(1) + 55 * 60, day(1) + HOUR + 50 * 60]);
+        assert_eq!(cron("0 1-5/2,20 * * *").fire_times(day(1), 4), vec![
+            day(1) + HOUR,
+            day(1) + 3 * HOUR,
+            day(1) + 5 * HOUR,
+            day(1) + 20 * HOUR,
+        ]);
+    }
+
+    #[test]
+    fn month_and_day_names() {
+        assert_eq!(cron("0 0 * jan,Mar mon-FRI"), cron("0 0 * 1,3 1-5"));
+        // 7 is Sunday as well as 0
+        assert_eq!(cron("0 0 * * 7"), cron("0 0 * * SUN"));
+        assert_eq!(cron("0 0 * * 7").next_after(day(1)), Some(day(7)));
+        assert_eq!(cron("0 0 1 FEB *").next_after(day(1)), Some(day(32)));
+    }
+
+    #[test]
+    fn six_fields_start_with_seconds() {
+        let every_ten_seconds = cron("*/10 * * * * *");
+        assert_eq!(every_ten_seconds.next_after(day(1) + 3), Some(day(1) + 10));
+        assert_eq!(every_ten_seconds.next_after(day(1) + 59), Some(day(1) + 60));
+        // The 5-field form fires on second 0
+        assert_eq!(cron("* * * * *").next_after(day(1)), Some(day(1) + 60));
+        assert!(every_ten_seconds.fires_more_than_once_a_minute());
+        assert!(!cron("30 * * * * *").fires_more_than_once_a_minute());
+        assert!(!cron("* * * * *").fires_more_than_once_a_minute());
+    }
+
+    #[test]
+    fn either_day_field_fires_when_both_are_restricted() {
+        // Fridays and the 13th, like Vixie cron
+        assert_eq!(cron("0 0 13 * FRI").fire_times(day(1), 4), vec![day(5), day(12), day(13), day(19)]);
+        // Otherwise the unrestricted field matches every day
+        assert_eq!(cron("0 0 13 * *").fire_times(day(1), 2), vec![day(13), day(13 + 31)]);
+        assert_eq!(cron("0 0 * * FRI").fire_times(day(1), 2), vec![day(5), day(12)]);
+        assert_eq!(cron("0 0 ? * FRI"), cron("0 0 * * FRI"));
+        // A field starting with `*` counts as unrestricted even with a step, so this is odd Fridays
+        assert_eq!(cron("0 0 */2 * FRI").fire_times(day(1), 2), vec![day(5), day(19)]);
+    }
+
+    #[test]
+    fn finds_rare_days_and_gives_up_on_impossible_ones() {
+        const MAR_1_2097: u64 = 4_012_934_400;
+        const FEB_29_2104: u64 = 4_233_686_400; // 2100 is not a leap year
+        assert_eq!(cron("0 0 29 2 *").next_after(MAR_1_2097), Some(FEB_29_2104));
+        assert_eq!(cron("0 0 30 2 *").next_after(day(1)), None);
+        assert_eq!(cron("0 0 31 4,6,9,11 *").next_after(day(1)), None);
+        assert!(cron("0 0 30 2 *").fire_times(day(1), 5).is_empty());
+    }
+
+    #[test]
+    fn fire_times_are_capped() {
+        assert_eq!(cron("* * * * * *").fire_times(day(1), 1_000).len(), MAX_FIRE_TIMES as usize);
+        assert_eq!(cron("* * * * *").next_after(u64::MAX), None);
+    }
+}
@@ -0,0 +1,243 @@
+use candid::{CandidType, Principal};
+use ic_cdk::api::{debug_print, msg_caller, time};
+use ic_cdk_macros::{query, update};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::error::ApiError;
+
+// What a delegate may do on the owner's behalf
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum DelegationScope {
+    TokenInit,
+    Mint,
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct Delegation {
+    pub delegate: Principal,
+    pub scopes: Vec<DelegationScope>,
+    pub symbols: Option<Vec<String>>, // None covers every token of the owner
+    pub expires_at: Option<u64>,      // nanoseconds since epoch
+    pub created_at: u64,
+}
+
+impl Delegation {
+    fn is_live(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+
+    fn allows(&self, scope: &DelegationScope, symbol: Option<&str>, now: u64) -> bool {
+        self.is_live(now)
+            && self.scopes.contains(scope)
+            && match (&self.symbols, symbol) {
+                (Some(symbols), Some(symbol)) => symbols.iter().any(|s| s == symbol),
+                _ => true,
+            }
+    }
+}
+
+thread_local! {
+    // owner -> delegations granted by that owner
+    static DELEGATIONS: RefCell<BTreeMap<Principal, Vec<Delegation>>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+pub(crate) fn take_stable_state() -> BTreeMap<Principal, Vec<Delegation>> {
+    DELEGATIONS.with(|d| std::mem::take(&mut *d.borrow_mut()))
+}
+
+pub(crate) fn restore_stable_state(state: BTreeMap<Principal, Vec<Delegation>>) {
+    DELEGATIONS.with(|d| *d.borrow_mut() = state);
+}
+
+// True when `caller` is `owner` itself or holds a live delegation from it for `scope`
+pub fn is_authorized(owner: Principal, caller: Principal, scope: DelegationScope, symbol: Option<&str>) -> bool {
+    authorized(owner, caller, &scope, symbol, time)
+}
+
+// `now` is only read when there is a delegation to check
+fn authorized(
+    owner: Principal,
+    caller: Principal,
+    scope: &DelegationScope,
+    symbol: Option<&str>,
+    now: impl FnOnce() -> u64,
+) -> bool {
+    if caller == Principal::anonymous() {
+        return false;
+    }
+    if caller == owner {
+        return true;
+    }
+    DELEGATIONS.with(|d| {
+        d.borrow()
+            .get(&owner)
+            .and_then(|delegations| delegations.iter().find(|delegation| delegation.delegate == caller))
+            .is_some_and(|delegation| delegation.allows(scope, symbol, now()))
+    })
+}
+
+// Lets `delegate` (e.g. the LangChain backend) act for the caller. Replaces any
+// earlier delegation to the same principal.
+#[update]
+pub fn grant_delegation(
+    delegate: Principal,
+    scopes: Vec<DelegationScope>,
+    symbols: Option<Vec<String>>,
+    expires_at: Option<u64>,
+) -> Result<(), ApiError> {
+    let owner = msg_caller();
+    grant_delegation_as(owner, delegate, scopes, symbols, expires_at, time())?;
+    debug_print(format!("{} delegated to {}", owner, delegate));
+    Ok(())
+}
+
+fn grant_delegation_as(
+    owner: Principal,
+    delegate: Principal,
+    scopes: Vec<DelegationScope>,
+    symbols: Option<Vec<String>>,
+    expires_at: Option<u64>,
+    now: u64,
+) -> Result<(), ApiError> {
+    if owner == Principal::anonymous() {
+        return Err(ApiError::Unauthorized("Anonymous callers cannot delegate".to_string()));
+    }
+    if delegate == owner {
+        return Err(ApiError::InvalidArgument("Cannot delegate to yourself".to_string()));
+    }
+    if scopes.is_empty() {
+        return Err(ApiError::InvalidArgument("At least one scope is required".to_string()));
+    }
+    if expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(ApiError::InvalidArgument("Delegation expiry must be in the future".to_string()));
+    }
+    DELEGATIONS.with(|d| {
+        let mut delegations = d.borrow_mut();
+        let granted = delegations.entry(owner).or_default();
+        granted.retain(|existing| existing.delegate != delegate && existing.is_live(now));
+        granted.push(Delegation { delegate, scopes, symbols, expires_at, created_at: now });
+    });
+    Ok(())
+}
+
+#[update]
+pub fn revoke_delegation(delegate: Principal) -> Result<(), ApiError> {
+    revoke_delegation_as(msg_caller(), delegate)
+}
+
+fn revoke_delegation_as(owner: Principal, delegate: Principal) -> Result<(), ApiError> {
+    if owner == Principal::anonymous() {
+        return Err(ApiError::Unauthorized("Anonymous callers cannot revoke delegations".to_string()));
+    }
+    let removed = DELEGATIONS.with(|d| {
+        let mut delegations = d.borrow_mut();
+        let Some(granted) = delegations.get_mut(&owner) else {
+            return false;
+        };
+        let before = granted.len();
+        granted.retain(|existing| existing.delegate != delegate);
+        let removed = granted.len() != before;
+        if granted.is_empty() {
+            delegations.remove(&owner);
+        }
+        removed
+    });
+    if removed {
+        Ok(())
+    } else {
+        Err(ApiError::NotFound(format!("No delegation to {} found", delegate)))
+    }
+}
+
+#[query]
+pub fn get_delegations(owner: Principal) -> Vec<Delegation> {
+    let now = time();
+    DELEGATIONS.with(|d| {
+        d.borrow()
+            .get(&owner)
+            .map(|delegations| delegations.iter().filter(|delegation| delegation.is_live(now)).cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn grant(owner: Principal, delegation: Delegation) {
+        DELEGATIONS.with(|d| d.borrow_mut().entry(owner).or_default().push(delegation));
+    }
+
+    fn mint_delegation(delegate: Principal, symbols: Option<Vec<String>>, expires_at: Option<u64>) -> Delegation {
+        Delegation { delegate, scopes: vec![DelegationScope::Mint], symbols, expires_at, created_at: 0 }
+    }
+
+    fn clock_never_read() -> u64 {
+        panic!("no delegation to check, so the clock must not be read")
+    }
+
+    #[test]
+    fn owner_is_authorized_and_anonymous_never_is() {
+        let owner = principal(1);
+        assert!(authorized(owner, owner, &DelegationScope::TokenInit, Some("ABC"), clock_never_read));
+        assert!(!authorized(owner, Principal::anonymous(), &DelegationScope::Mint, None, clock_never_read));
+        // Not even for tokens "owned" by the anonymous principal
+        let anonymous = Principal::anonymous();
+        assert!(!authorized(anonymous, anonymous, &DelegationScope::Mint, None, clock_never_read));
+    }
+
+    #[test]
+    fn strangers_are_rejected() {
+        let (owner, delegate, stranger) = (principal(1), principal(2), principal(3));
+        grant(owner, mint_delegation(delegate, None, None));
+        assert!(!authorized(owner, stranger, &DelegationScope::Mint, None, clock_never_read));
+        // A delegation only covers the owner that granted it
+        assert!(!authorized(stranger, delegate, &DelegationScope::Mint, None, clock_never_read));
+    }
+
+    #[test]
+    fn delegation_covers_its_scopes_symbols_and_lifetime() {
+        let (owner, delegate) = (principal(1), principal(2));
+        grant(owner, mint_delegation(delegate, Some(vec!["ABC".to_string()]), Some(NOW + 10)));
+
+        assert!(authorized(owner, delegate, &DelegationScope::Mint, Some("ABC"), || NOW));
+        assert!(!authorized(owner, delegate, &DelegationScope::TokenInit, Some("ABC"), || NOW));
+        assert!(!authorized(owner, delegate, &DelegationScope::Mint, Some("XYZ"), || NOW));
+        assert!(!authorized(owner, delegate, &DelegationScope::Mint, Some("ABC"), || NOW + 10));
+    }
+
+    #[test]
+    fn delegation_without_symbols_covers_every_token() {
+        let (owner, delegate) = (principal(1), principal(2));
+        grant(owner, mint_delegation(delegate, None, None));
+        assert!(authorized(owner, delegate, &DelegationScope::Mint, Some("ANY"), || NOW));
+        assert!(authorized(owner, delegate, &DelegationScope::Mint, None, || u64::MAX));
+    }
+
+    #[test]
+    fn only_the_owner_grants_and_revokes_its_delegations() {
+        let (owner, delegate, stranger) = (principal(1), principal(2), principal(3));
+        grant(owner, mint_delegation(delegate, None, None));
+        let mint = || vec![DelegationScope::Mint];
+
+        let anonymous = Principal::anonymous();
+        assert!(matches!(grant_delegation_as(anonymous, delegate, mint(), None, None, NOW), Err(ApiError::Unauthorized(_))));
+        assert!(matches!(revoke_delegation_as(anonymous, delegate), Err(ApiError::Unauthorized(_))));
+        // A stranger only ever grants and revokes its own delegations
+        assert!(matches!(revoke_delegation_as(stranger, delegate), Err(ApiError::NotFound(_))));
+        grant_delegation_as(stranger, principal(4), mint(), None, None, NOW).unwrap();
+        assert!(authorized(owner, delegate, &DelegationScope::Mint, None, || NOW));
+        assert!(!authorized(owner, principal(4), &DelegationScope::Mint, None, || NOW));
+
+        revoke_delegation_as(owner, delegate).unwrap();
+        assert!(!authorized(owner, delegate, &DelegationScope::Mint, None, || NOW));
+    }
+}
@@ -0,0 +1,42 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::token2::TransferError;
+
+// Error half of every fallible endpoint, so clients can tell failures apart without
+// parsing messages. The message is for humans only.
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum ApiError {
+    NotFound(String),
+    Unauthorized(String),
+    AlreadyExists(String),
+    InvalidArgument(String),
+    QuotaExceeded(String),
+    InvalidState(String), // e.g. the token is retired, or the task hasn't failed
+    CallFailed(String),   // a call to another canister the endpoint relies on failed
+    Transfer(TransferError),
+    Moved(Principal), // the token now lives in this ledger canister, ask it instead
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::NotFound(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::AlreadyExists(message)
+            | ApiError::InvalidArgument(message)
+            | ApiError::QuotaExceeded(message)
+            | ApiError::InvalidState(message)
+            | ApiError::CallFailed(message) => f.write_str(message),
+            ApiError::Transfer(err) => write!(f, "{:?}", err),
+            ApiError::Moved(ledger) => write!(f, "Moved to ledger canister {}", ledger),
+        }
+    }
+}
+
+impl From<TransferError> for ApiError {
+    fn from(err: TransferError) -> Self {
+        ApiError::Transfer(err)
+    }
+}
@@ -0,0 +1,265 @@
+use candid::{Nat, Principal};
+use ic_cdk::api::{canister_cycle_balance, debug_print, is_controller, msg_caller, msg_cycles_refunded};
+use ic_cdk::call::{Call, CallFailed};
+use ic_cdk::management_canister::{
+    cost_http_request, transform_context_from_query, HttpHeader, HttpMethod, HttpRequestArgs, HttpRequestResult,
+    TransformArgs,
+};
+use ic_cdk_macros::{query, update};
+use std::cell::RefCell;
+
+use crate::agent_core::TaskResult;
+use crate::cron::CronExpr;
+use crate::error::ApiError;
+use crate::retry::TaskErrorKind;
+
+// Responses are kept in the task's run history, so keep them small
+const DEFAULT_MAX_RESPONSE_BYTES: u64 = 16 * 1024;
+const MAX_RESPONSE_BYTES: u64 = 64 * 1024;
+
+// Budget of a run whose task sets no max_cycles. Covers the default response size on
+// a 13-node subnet; larger requests have to set their own budget.
+const DEFAULT_MAX_CYCLES: u128 = 1_000_000_000;
+
+// Recurring requests run at most this often, in seconds
+pub(crate) const MIN_INTERVAL: u64 = 60;
+
+// Response headers that are the same on every replica; the rest (date, set-cookie,
+// request IDs...) would keep the replicas from reaching consensus
+const KEPT_HEADERS: [&str; 4] = ["content-type", "content-encoding", "content-language", "location"];
+
+thread_local! {
+    // Where outcalls are sent; None is the management canister. Lets a local
+    // mock canister with the same `http_request` interface stand in for it.
+    static OUTCALL_TARGET: RefCell<Option<Principal>> = const { RefCell::new(None) };
+}
+
+// An http_request task, ready to be sent
+pub struct Outcall {
+    args: HttpRequestArgs,
+    cycles: u128,
+}
+
+pub struct OutcallOutcome {
+    pub result: TaskResult,
+    pub response: Option<HttpRequestResult>,
+    pub cycles_used: u64,
+}
+
+// Checks the request and prices it against the task's cycle budget
+pub(crate) fn prepare(
+    url: String,
+    method: HttpMethod,
+    headers: Vec<HttpHeader>,
+    body: Option<Vec<u8>>,
+    max_response_bytes: Option<u64>,
+    max_cycles: Option<u128>,
+) -> Result<Outcall, TaskResult> {
+    let max_response_bytes = check_request(&url, max_response_bytes)?;
+    let args = HttpRequestArgs {
+        url,
+        max_response_bytes: Some(max_response_bytes),
+        method,
+        headers,
+        body,
+        transform: Some(transform_context_from_query("transform_http_response".to_string(), Vec::new())),
+    };
+    let cycles = cost_http_request(&args);
+    check_budget(cycles, max_cycles, canister_cycle_balance())?;
+    Ok(Outcall { args, cycles })
+}
+
+fn failed(error: String, kind: TaskErrorKind) -> TaskResult {
+    TaskResult::Failed { error, kind }
+}
+
+// Returns the response size limit the request is sent with
+fn check_request(url: &str, max_response_bytes: Option<u64>) -> Result<u64, TaskResult> {
+    if !url.starts_with("https://") {
+        return Err(failed(format!("Only https:// URLs can be requested, got {}", url), TaskErrorKind::InvalidTask));
+    }
+    let max_response_bytes = max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES);
+    if max_response_bytes > MAX_RESPONSE_BYTES {
+        return Err(failed(format!("max_response_bytes can be at most {}", MAX_RESPONSE_BYTES), TaskErrorKind::InvalidTask));
+    }
+    Ok(max_response_bytes)
+}
+
+fn check_budget(cycles: u128, max_cycles: Option<u128>, balance: u128) -> Result<(), TaskResult> {
+    let budget = max_cycles.unwrap_or(DEFAULT_MAX_CYCLES);
+    if cycles > budget {
+        return Err(failed(format!("Request costs {} cycles, over the task budget of {}", cycles, budget), TaskErrorKind::InsufficientCycles));
+    }
+    if balance < cycles {
+        return Err(failed(format!("Canister can't afford the {} cycles the request costs", cycles), TaskErrorKind::InsufficientCycles));
+    }
+    Ok(())
+}
+
+// Rejects schedules that would send a recurring request more than once a minute.
+// A frequency of 0 without a cron expression runs the request once.
+pub(crate) fn check_interval(frequency: u64, cron: Option<&CronExpr>) -> Result<(), String> {
+    let too_often = match cron {
+        Some(cron) => cron.fires_more_than_once_a_minute(),
+        None => frequency != 0 && frequency < MIN_INTERVAL,
+    };
+    if too_often {
+        return Err(format!("HTTP request tasks can run at most once every {} seconds", MIN_INTERVAL));
+    }
+    Ok(())
+}
+
+fn result_of(status: &Nat) -> TaskResult {
+    let status = u16::try_from(status.0.clone()).unwrap_or(0);
+    match status {
+        200..=299 => TaskResult::Success { tx_id: None },
+        400..=499 => failed(format!("HTTP {}", status), TaskErrorKind::HttpClientError),
+        _ => failed(format!("HTTP {}", status), TaskErrorKind::HttpServerError),
+    }
+}
+
+// What the outcall took from the balance: the cycles attached less the refund.
+// `refunded` is only known in the callback of a call that reached the management canister.
+fn cycles_charged(attached: u128, refunded: u128) -> u64 {
+    u64::try_from(attached.saturating_sub(refunded)).unwrap_or(u64::MAX)
+}
+
+fn outcome_of(response: Result<HttpRequestResult, String>, cycles_used: u64) -> OutcallOutcome {
+    match response {
+        Ok(response) => OutcallOutcome { result: result_of(&response.status), response: Some(response), cycles_used },
+        Err(error) => OutcallOutcome {
+            result: TaskResult::Failed { error, kind: TaskErrorKind::CallRejected },
+            response: None,
+            cycles_used,
+        },
+    }
+}
+
+pub(crate) async fn send(outcall: Outcall) -> OutcallOutcome {
+    let target = OUTCALL_TARGET.with(|t| *t.borrow()).unwrap_or(Principal::management_canister());
+    debug_print(format!("HTTP {:?} {} ({} cycles)", outcall.args.method, outcall.args.url, outcall.cycles));
+    let (response, cycles_used) = match Call::unbounded_wait(target, "http_request").with_arg(&outcall.args).with_cycles(outcall.cycles).await {
+        Ok(response) => (
+            response.candid::<HttpRequestResult>().map_err(|e| format!("Failed to decode HTTP response: {}", e)),
+            cycles_charged(outcall.cycles, msg_cycles_refunded()),
+        ),
+        Err(CallFailed::CallRejected(e)) => {
+            (Err(format!("HTTP outcall failed: {}", e)), cycles_charged(outcall.cycles, msg_cycles_refunded()))
+        }
+        // Never sent, so nothing was charged
+        Err(e) => (Err(format!("HTTP outcall failed: {}", e)), 0),
+    };
+    outcome_of(response, cycles_used)
+}
+
+// Called by the replicas on each raw response before they compare them
+#[query]
+pub fn transform_http_response(args: TransformArgs) -> HttpRequestResult {
+    let mut response = args.response;
+    response.headers.retain(|header| KEPT_HEADERS.iter().any(|kept| header.name.eq_ignore_ascii_case(kept)));
+    for header in response.headers.iter_mut() {
+        header.name = header.name.to_ascii_lowercase();
+    }
+    response.headers.sort();
+    response
+}
+
+// Controllers only: send outcalls to a mock canister instead, or back to the
+// management canister with None. Not persisted, so upgrades reset it.
+#[update]
+pub fn set_http_outcall_target(target: Option<Principal>) -> Result<(), ApiError> {
+    if !is_controller(&msg_caller()) {
+        return Err(ApiError::Unauthorized("Only controllers can change the HTTP outcall target".to_string()));
+    }
+    debug_print(format!("HTTP outcall target set to {:?}", target));
+    OUTCALL_TARGET.with(|t| *t.borrow_mut() = target);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind<T>(result: Result<T, TaskResult>) -> Option<TaskErrorKind> {
+        match result {
+            Err(TaskResult::Failed { kind, .. }) => Some(kind),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn checks_the_url_and_response_size() {
+        assert_eq!(check_request("https://example.com", None).ok(), Some(DEFAULT_MAX_RESPONSE_BYTES));
+        assert_eq!(check_request("https://example.com", Some(MAX_RESPONSE_BYTES)).ok(), Some(MAX_RESPONSE_BYTES));
+        assert_eq!(kind(check_request("http://example.com", None)), Some(TaskErrorKind::InvalidTask));
+        assert_eq!(kind(check_request("https://example.com", Some(MAX_RESPONSE_BYTES + 1))), Some(TaskErrorKind::InvalidTask));
+    }
+
+    #[test]
+    fn uncapped_tasks_get_the_default_budget() {
+        assert!(check_budget(DEFAULT_MAX_CYCLES, None, u128::MAX).is_ok());
+        assert_eq!(kind(check_budget(DEFAULT_MAX_CYCLES + 1, None, u128::MAX)), Some(TaskErrorKind::InsufficientCycles));
+        // A task's own budget replaces the default, either way
+        assert!(check_budget(DEFAULT_MAX_CYCLES + 1, Some(2 * DEFAULT_MAX_CYCLES), u128::MAX).is_ok());
+        assert!(check_budget(1_000, Some(999), u128::MAX).is_err());
+        assert_eq!(kind(check_budget(1_000, Some(1_000), 999)), Some(TaskErrorKind::InsufficientCycles));
+    }
+
+    #[test]
+    fn recurring_requests_run_at_most_once_a_minute() {
+        assert!(check_interval(0, None).is_ok());
+        assert!(check_interval(MIN_INTERVAL, None).is_ok());
+        assert!(check_interval(1, None).is_err());
+        let cron = |expression: &str| expression.parse::<CronExpr>().unwrap();
+        // A cron schedule replaces the frequency
+        assert!(check_interval(1, Some(&cron("* * * * *"))).is_ok());
+        assert!(check_interval(0, Some(&cron("*/30 * * * * *"))).is_err());
+    }
+
+    #[test]
+    fn classifies_response_statuses() {
+        assert_eq!(result_of(&Nat::from(204u32)), TaskResult::Success { tx_id: None });
+        assert_eq!(kind::<()>(Err(result_of(&Nat::from(404u32)))), Some(TaskErrorKind::HttpClientError));
+        assert_eq!(kind::<()>(Err(result_of(&Nat::from(503u32)))), Some(TaskErrorKind::HttpServerError));
+        assert_eq!(kind::<()>(Err(result_of(&Nat::from(100_000u32)))), Some(TaskErrorKind::HttpServerError));
+    }
+
+    #[test]
+    fn transform_keeps_only_stable_headers() {
+        let header = |name: &str, value: &str| HttpHeader { name: name.to_string(), value: value.to_string() };
+        let response = HttpRequestResult {
+            status: Nat::from(200u32),
+            headers: vec![header("Date", "Mon, 01 Jan 2024"), header("Content-Type", "text/plain"), header("Location", "/next")],
+            body: b"ok".to_vec(),
+        };
+        let transformed = transform_http_response(TransformArgs { response, context: Vec::new() });
+        assert_eq!(transformed.headers, vec![header("content-type", "text/plain"), header("location", "/next")]);
+        assert_eq!(transformed.body, b"ok".to_vec());
+    }
+
+    #[test]
+    fn charges_the_attached_cycles_less_the_refund() {
+        assert_eq!(cycles_charged(1_000, 400), 600);
+        assert_eq!(cycles_charged(1_000, 0), 1_000);
+        assert_eq!(cycles_charged(1_000, 2_000), 0);
+        assert_eq!(cycles_charged(u128::MAX, 0), u64::MAX);
+    }
+
+    #[test]
+    fn outcomes_keep_the_response_and_the_cycles() {
+        let response = |status: u32| HttpRequestResult { status: Nat::from(status), headers: Vec::new(), body: b"ok".to_vec() };
+        let outcome = outcome_of(Ok(response(200)), 600);
+        assert_eq!(outcome.result, TaskResult::Success { tx_id: None });
+        assert_eq!(outcome.response, Some(response(200)));
+        assert_eq!(outcome.cycles_used, 600);
+        // Error statuses are failed runs that still keep the response
+        let outcome = outcome_of(Ok(response(500)), 600);
+        assert_eq!(kind::<()>(Err(outcome.result)), Some(TaskErrorKind::HttpServerError));
+        assert_eq!(outcome.response, Some(response(500)));
+
+        let outcome = outcome_of(Err("HTTP outcall failed: timeout".to_string()), 600);
+        assert_eq!(kind::<()>(Err(outcome.result)), Some(TaskErrorKind::CallRejected));
+        assert_eq!(outcome.response, None);
+        assert_eq!(outcome.cycles_used, 600);
+    }
+}
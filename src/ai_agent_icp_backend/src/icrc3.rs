@@ -0,0 +1,276 @@
+use candid::{CandidType, Int, Nat};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub type Hash = [u8; 32];
+
+// ICRC-3 generic value, the representation of every block in a ledger log
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum ICRC3Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<ICRC3Value>),
+    Map(Vec<(String, ICRC3Value)>),
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: ICRC3Value,
+}
+
+candid::define_function!(pub GetBlocksCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: GetBlocksCallback,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct ICRC3DataCertificate {
+    pub certificate: Vec<u8>,
+    pub hash_tree: Vec<u8>,
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct SupportedBlockType {
+    pub block_type: String,
+    pub url: String,
+}
+
+fn sha256(bytes: &[u8]) -> Hash {
+    Sha256::digest(bytes).into()
+}
+
+pub fn leb128(n: &Nat) -> Vec<u8> {
+    let mut buf = Vec::new();
+    n.encode(&mut buf).expect("writing to a Vec cannot fail");
+    buf
+}
+
+// Representation-independent hash as specified by ICRC-3
+pub fn hash_value(value: &ICRC3Value) -> Hash {
+    match value {
+        ICRC3Value::Blob(bytes) => sha256(bytes),
+        ICRC3Value::Text(text) => sha256(text.as_bytes()),
+        ICRC3Value::Nat(n) => sha256(&leb128(n)),
+        ICRC3Value::Int(i) => {
+            let mut buf = Vec::new();
+            i.encode(&mut buf).expect("writing to a Vec cannot fail");
+            sha256(&buf)
+        }
+        ICRC3Value::Array(items) => {
+            let mut hasher = Sha256::new();
+            for item in items {
+                hasher.update(hash_value(item));
+            }
+            hasher.finalize().into()
+        }
+        ICRC3Value::Map(entries) => {
+            let mut pairs: Vec<Vec<u8>> = entries
+                .iter()
+                .map(|(key, value)| {
+                    let mut pair = sha256(key.as_bytes()).to_vec();
+                    pair.extend_from_slice(&hash_value(value));
+                    pair
+                })
+                .collect();
+            pairs.sort();
+            let mut hasher = Sha256::new();
+            for pair in pairs {
+                hasher.update(pair);
+            }
+            hasher.finalize().into()
+        }
+    }
+}
+
+// The blocks of `log` in the requested ranges, up to `max_blocks` in all. Ranges
+// past the end of the log are cut short; none are archived.
+pub fn get_blocks(log: &[ICRC3Value], args: Vec<GetBlocksArgs>, max_blocks: u64) -> GetBlocksResult {
+    let log_length = log.len() as u64;
+    let mut blocks = Vec::new();
+    for range in args {
+        let start = u64::try_from(&range.start.0).unwrap_or(u64::MAX).min(log_length);
+        let remaining = max_blocks - blocks.len() as u64;
+        let length = u64::try_from(&range.length.0).unwrap_or(u64::MAX).min(remaining);
+        let end = start.saturating_add(length).min(log_length);
+        for id in start..end {
+            blocks.push(BlockWithId { id: Nat::from(id), block: log[id as usize].clone() });
+        }
+    }
+    GetBlocksResult { log_length: Nat::from(log_length), blocks, archived_blocks: Vec::new() }
+}
+
+// IC certification hash tree, used to certify the tip of every ledger
+#[derive(Clone, Debug)]
+pub enum HashTree {
+    Empty,
+    Fork(Box<HashTree>, Box<HashTree>),
+    Labeled(Vec<u8>, Box<HashTree>),
+    Leaf(Vec<u8>),
+    Pruned(Hash),
+}
+
+fn domain_sep(hasher: &mut Sha256, domain: &str) {
+    hasher.update([domain.len() as u8]);
+    hasher.update(domain.as_bytes());
+}
+
+impl HashTree {
+    pub fn labeled(label: &str, tree: HashTree) -> Self {
+        HashTree::Labeled(label.as_bytes().to_vec(), Box::new(tree))
+    }
+
+    pub fn fork(left: HashTree, right: HashTree) -> Self {
+        HashTree::Fork(Box::new(left), Box::new(right))
+    }
+
+    pub fn digest(&self) -> Hash {
+        let mut hasher = Sha256::new();
+        match self {
+            HashTree::Empty => domain_sep(&mut hasher, "ic-hashtree-empty"),
+            HashTree::Fork(left, right) => {
+                domain_sep(&mut hasher, "ic-hashtree-fork");
+                hasher.update(left.digest());
+                hasher.update(right.digest());
+            }
+            HashTree::Labeled(label, tree) => {
+                domain_sep(&mut hasher, "ic-hashtree-labeled");
+                hasher.update(label);
+                hasher.update(tree.digest());
+            }
+            HashTree::Leaf(value) => {
+                domain_sep(&mut hasher, "ic-hashtree-leaf");
+                hasher.update(value);
+            }
+            HashTree::Pruned(hash) => return *hash,
+        }
+        hasher.finalize().into()
+    }
+
+    fn to_cbor(&self) -> ciborium::Value {
+        use ciborium::Value;
+        match self {
+            HashTree::Empty => Value::Array(vec![Value::from(0u8)]),
+            HashTree::Fork(left, right) => Value::Array(vec![Value::from(1u8), left.to_cbor(), right.to_cbor()]),
+            HashTree::Labeled(label, tree) => {
+                Value::Array(vec![Value::from(2u8), Value::Bytes(label.clone()), tree.to_cbor()])
+            }
+            HashTree::Leaf(value) => Value::Array(vec![Value::from(3u8), Value::Bytes(value.clone())]),
+            HashTree::Pruned(hash) => Value::Array(vec![Value::from(4u8), Value::Bytes(hash.to_vec())]),
+        }
+    }
+
+    // Self-describing CBOR encoding, as expected by agents verifying a certificate
+    pub fn serialize(&self) -> Vec<u8> {
+        let tagged = ciborium::Value::Tag(55799, Box::new(self.to_cbor()));
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&tagged, &mut bytes).expect("writing to a Vec cannot fail");
+        bytes
+    }
+}
+
+// Builds a balanced tree over labeled subtrees (which must be sorted by label).
+// When `keep` is set every subtree but that label is pruned, producing a witness.
+pub fn labeled_forest(entries: &[(Vec<u8>, HashTree)], keep: Option<&[u8]>) -> HashTree {
+    match entries.len() {
+        0 => HashTree::Empty,
+        1 => {
+            let (label, tree) = &entries[0];
+            let node = HashTree::Labeled(label.clone(), Box::new(tree.clone()));
+            match keep {
+                Some(k) if k != label.as_slice() => HashTree::Pruned(node.digest()),
+                _ => node,
+            }
+        }
+        n => {
+            let (left, right) = entries.split_at(n / 2);
+            let node = HashTree::fork(labeled_forest(left, keep), labeled_forest(right, keep));
+            match &node {
+                HashTree::Fork(l, r) if matches!((&**l, &**r), (HashTree::Pruned(_), HashTree::Pruned(_))) => {
+                    HashTree::Pruned(node.digest())
+                }
+                _ => node,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(hash: Hash) -> String {
+        hex::encode(hash)
+    }
+
+    // Test vectors from the ICRC-3 specification
+    #[test]
+    fn hashes_match_the_icrc3_vectors() {
+        assert_eq!(hex(hash_value(&ICRC3Value::Nat(Nat::from(42u64)))), "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1");
+        assert_eq!(hex(hash_value(&ICRC3Value::Int(Int::from(-42)))), "de5a6f78116eca62d7fc5ce159d23ae6b889b365a1739ad2cf36f925a140d0cc");
+        assert_eq!(
+            hex(hash_value(&ICRC3Value::Text("Hello, World!".to_string()))),
+            "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f"
+        );
+        assert_eq!(hex(hash_value(&ICRC3Value::Blob(vec![1, 2, 3, 4]))), "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a");
+        let array = ICRC3Value::Array(vec![
+            ICRC3Value::Nat(Nat::from(3u64)),
+            ICRC3Value::Text("foo".to_string()),
+            ICRC3Value::Blob(vec![5, 6]),
+        ]);
+        assert_eq!(hex(hash_value(&array)), "514a04011caa503990d446b7dec5d79e19c221ae607fb08b2848c67734d468d6");
+        let blob = |bytes: &str| ICRC3Value::Blob(hex::decode(bytes).unwrap());
+        let map = ICRC3Value::Map(vec![
+            ("from".to_string(), blob("00abcdef0012340056789a00bcdef000012345678900abcdef01")),
+            ("to".to_string(), blob("00ab0def0012340056789a00bcdef000012345678900abcdef01")),
+            ("amount".to_string(), ICRC3Value::Nat(Nat::from(42u64))),
+            ("created_at".to_string(), ICRC3Value::Nat(Nat::from(1_699_218_263u64))),
+            ("memo".to_string(), ICRC3Value::Nat(Nat::from(0u64))),
+        ]);
+        assert_eq!(hex(hash_value(&map)), "c56ece650e1de4269c5bdeff7875949e3e2033f85b2d193c2ff4f7f78bdcfc75");
+    }
+
+    #[test]
+    fn map_hashes_ignore_entry_order() {
+        let entry = |key: &str, n: u64| (key.to_string(), ICRC3Value::Nat(Nat::from(n)));
+        let forward = ICRC3Value::Map(vec![entry("a", 1), entry("b", 2)]);
+        let backward = ICRC3Value::Map(vec![entry("b", 2), entry("a", 1)]);
+        assert_eq!(hash_value(&forward), hash_value(&backward));
+    }
+
+    #[test]
+    fn get_blocks_clamps_ranges_to_the_log() {
+        let log: Vec<ICRC3Value> = (0..5u64).map(|n| ICRC3Value::Nat(Nat::from(n))).collect();
+        let range = |start: u64, length: u64| GetBlocksArgs { start: Nat::from(start), length: Nat::from(length) };
+        let ids = |result: GetBlocksResult| -> Vec<u64> {
+            assert_eq!(result.log_length, Nat::from(5u64));
+            result.blocks.iter().map(|block| u64::try_from(&block.id.0).unwrap()).collect()
+        };
+
+        assert_eq!(ids(get_blocks(&log, vec![range(3, 10)], 100)), vec![3, 4]);
+        assert_eq!(ids(get_blocks(&log, vec![range(5, 1), range(7, 2)], 100)), Vec::<u64>::new());
+        let huge = GetBlocksArgs { start: Nat::from(u128::MAX), length: Nat::from(u128::MAX) };
+        assert_eq!(ids(get_blocks(&log, vec![huge], 100)), Vec::<u64>::new());
+        // The cap counts every range together
+        assert_eq!(ids(get_blocks(&log, vec![range(0, 2), range(1, 5)], 3)), vec![0, 1, 1]);
+        assert_eq!(get_blocks(&log, vec![range(0, 1)], 100).blocks[0].block, log[0]);
+    }
+}
@@ -0,0 +1,288 @@
+use candid::{CandidType, Nat, Principal};
+#[cfg(not(test))]
+use ic_cdk::api::is_controller;
+use ic_cdk::api::{canister_self, debug_print, msg_caller, time};
+use ic_cdk::management_canister::{
+    create_canister_with_extra_cycles, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgs,
+    InstallCodeArgs,
+};
+use ic_cdk_macros::{query, update};
+use std::cell::RefCell;
+
+use crate::error::ApiError;
+use crate::token2::{Account, TokenState, TokenStatus, TOKEN_STATE};
+
+// Cycles handed to every spawned ledger, enough for it to run and create its first archive
+const LEDGER_CREATION_CYCLES: u128 = 2_000_000_000_000;
+
+thread_local! {
+    // Wasm of the DFINITY ICRC-1/2/3 ledger (ic-icrc1-ledger), uploaded by a controller
+    static LEDGER_WASM: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn take_stable_state() -> Option<Vec<u8>> {
+    LEDGER_WASM.with(|wasm| wasm.borrow_mut().take())
+}
+
+pub(crate) fn restore_stable_state(wasm: Option<Vec<u8>>) {
+    LEDGER_WASM.with(|w| *w.borrow_mut() = wasm);
+}
+
+// Init argument of ic-icrc1-ledger, only the fields we set
+#[derive(CandidType)]
+enum LedgerArg {
+    Init(InitArgs),
+}
+
+#[derive(CandidType)]
+enum MetadataValue {
+    Text(String),
+}
+
+#[derive(CandidType)]
+struct FeatureFlags {
+    icrc2: bool,
+}
+
+#[derive(CandidType)]
+struct ArchiveOptions {
+    num_blocks_to_archive: u64,
+    trigger_threshold: u64,
+    controller_id: Principal,
+    cycles_for_archive_creation: Option<u64>,
+}
+
+#[derive(CandidType)]
+struct InitArgs {
+    minting_account: Account,
+    fee_collector_account: Option<Account>,
+    transfer_fee: Nat,
+    decimals: Option<u8>,
+    token_symbol: String,
+    token_name: String,
+    metadata: Vec<(String, MetadataValue)>,
+    initial_balances: Vec<(Account, Nat)>,
+    feature_flags: Option<FeatureFlags>,
+    archive_options: ArchiveOptions,
+}
+
+#[update]
+pub fn set_ledger_wasm(wasm: Vec<u8>) -> Result<(), ApiError> {
+    if !is_controller(&msg_caller()) {
+        return Err(ApiError::Unauthorized("Only controllers can upload the ledger wasm".to_string()));
+    }
+    debug_print(format!("Ledger wasm uploaded ({} bytes)", wasm.len()));
+    LEDGER_WASM.with(|w| *w.borrow_mut() = Some(wasm));
+    Ok(())
+}
+
+// Moves a token into its own standards-exact ICRC-1/2/3 ledger canister. The child is
+// seeded with the current balances and the block log stays queryable here. The ICRC
+// ledger's init args can't seed allowances, so tokens with live allowances are refused. The local ledger is frozen while the child is being installed
+// and redirects every later write and balance query to the child.
+// Controllers only, as every ledger costs the canister LEDGER_CREATION_CYCLES. A canister
+// created by a spawn that failed to install is reused by the next attempt.
+#[update]
+pub async fn icrc2_spawn_ledger(symbol: String) -> Result<Principal, ApiError> {
+    spawn_ledger_as(msg_caller(), symbol).await
+}
+
+async fn spawn_ledger_as(caller: Principal, symbol: String) -> Result<Principal, ApiError> {
+    if caller == Principal::anonymous() || !is_controller(&caller) {
+        return Err(ApiError::Unauthorized("Only controllers can spawn ledger canisters".to_string()));
+    }
+    let wasm = LEDGER_WASM
+        .with(|w| w.borrow().clone())
+        .ok_or_else(|| ApiError::InvalidState("No ledger wasm uploaded".to_string()))?;
+
+    let (init_args, owner, pending) = TOKEN_STATE.with(|token_state| {
+        let mut tokens = token_state.borrow_mut();
+        let state = tokens.get_mut(&symbol).ok_or_else(|| ApiError::NotFound(format!("Token {} not found", symbol)))?;
+        check_spawnable(state, time())?;
+        state.spawning = true;
+
+        let mut metadata = Vec::new();
+        if let Some(logo) = &state.metadata.logo {
+            metadata.push(("icrc1:logo".to_string(), MetadataValue::Text(logo.clone())));
+        }
+        if let Some(description) = &state.metadata.description {
+            metadata.push(("icrc1:description".to_string(), MetadataValue::Text(description.clone())));
+        }
+        let init_args = InitArgs {
+            minting_account: state.minting_account.clone(),
+            fee_collector_account: state.fee_collector.clone(),
+            transfer_fee: state.metadata.fee.clone(),
+            decimals: Some(state.metadata.decimals),
+            token_symbol: state.metadata.symbol.clone(),
+            token_name: state.metadata.name.clone(),
+            metadata,
+            initial_balances: state
+                .balances
+                .iter()
+                .filter(|(_, balance)| **balance > 0u64)
+                .map(|(account, balance)| (account.clone(), balance.clone()))
+                .collect(),
+            feature_flags: Some(FeatureFlags { icrc2: true }),
+            archive_options: ArchiveOptions {
+                num_blocks_to_archive: 1000,
+                trigger_threshold: 2000,
+                controller_id: canister_self(),
+                cycles_for_archive_creation: Some(1_000_000_000_000),
+            },
+        };
+        Ok::<_, ApiError>((init_args, state.metadata.owner, state.pending_ledger))
+    })?;
+
+    let spawned = spawn_ledger(&symbol, wasm, init_args, owner, pending).await;
+
+    TOKEN_STATE.with(|token_state| {
+        if let Some(state) = token_state.borrow_mut().get_mut(&symbol) {
+            state.spawning = false;
+            if let Ok(ledger) = &spawned {
+                state.ledger_canister = Some(*ledger);
+                state.pending_ledger = None;
+            }
+        }
+    });
+    match &spawned {
+        Ok(ledger) => debug_print(format!("Token {} moved to ledger canister {}", symbol, ledger)),
+        Err(e) => debug_print(format!("Failed to spawn ledger for {}: {}", symbol, e)),
+    }
+    spawned
+}
+
+fn check_spawnable(state: &mut TokenState, now: u64) -> Result<(), ApiError> {
+    let symbol = &state.metadata.symbol;
+    if state.status != TokenStatus::Active {
+        return Err(ApiError::InvalidState(format!("Token {} is {:?}", symbol, state.status)));
+    }
+    if let Some(ledger) = state.ledger_canister {
+        return Err(ApiError::AlreadyExists(format!("Token {} already lives in ledger canister {}", symbol, ledger)));
+    }
+    if state.spawning {
+        return Err(ApiError::InvalidState(format!("A ledger for {} is already being spawned", symbol)));
+    }
+    state.prune_expired_allowances(now);
+    if !state.allowances.is_empty() {
+        return Err(ApiError::InvalidState(format!(
+            "Token {} has {} outstanding allowances, which a spawned ledger can't carry over",
+            state.metadata.symbol,
+            state.allowances.len()
+        )));
+    }
+    Ok(())
+}
+
+// Unit tests run outside a canister, where nobody is a controller
+#[cfg(test)]
+fn is_controller(_principal: &Principal) -> bool {
+    false
+}
+
+async fn spawn_ledger(
+    symbol: &str,
+    wasm: Vec<u8>,
+    init_args: InitArgs,
+    owner: Principal,
+    pending: Option<Principal>,
+) -> Result<Principal, ApiError> {
+    let arg = candid::encode_one(LedgerArg::Init(init_args))
+        .map_err(|e| ApiError::InvalidArgument(format!("Failed to encode init args: {}", e)))?;
+    let (ledger, mode) = match pending {
+        // A failed install may have left a module behind, which a reinstall replaces
+        Some(ledger) => (ledger, CanisterInstallMode::Reinstall),
+        None => {
+            let create_args = CreateCanisterArgs {
+                settings: Some(CanisterSettings {
+                    controllers: Some(vec![canister_self(), owner]),
+                    ..Default::default()
+                }),
+            };
+            let ledger = create_canister_with_extra_cycles(&create_args, LEDGER_CREATION_CYCLES)
+                .await
+                .map_err(|e| ApiError::CallFailed(format!("Failed to create ledger canister: {}", e)))?
+                .canister_id;
+            TOKEN_STATE.with(|token_state| {
+                if let Some(state) = token_state.borrow_mut().get_mut(symbol) {
+                    state.pending_ledger = Some(ledger);
+                }
+            });
+            (ledger, CanisterInstallMode::Install)
+        }
+    };
+    install_code(&InstallCodeArgs { mode, canister_id: ledger, wasm_module: wasm, arg })
+        .await
+        .map_err(|e| ApiError::CallFailed(format!("Created ledger canister {} but failed to install it: {}", ledger, e)))?;
+    Ok(ledger)
+}
+
+#[query]
+pub fn icrc2_ledger_canister(symbol: String) -> Result<Option<Principal>, ApiError> {
+    TOKEN_STATE.with(|token_state| {
+        let tokens = token_state.borrow();
+        let state = tokens.get(&symbol).ok_or_else(|| ApiError::NotFound(format!("Token {} not found", symbol)))?;
+        Ok(state.ledger_canister)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token2::{Allowance, Metadata};
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    fn account(id: u8) -> Account {
+        Account { owner: Principal::from_slice(&[id]), subaccount: None }
+    }
+
+    fn token() -> TokenState {
+        TokenState {
+            metadata: Metadata { symbol: "ABC".to_string(), ..Default::default() },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn spawning_is_refused_while_allowances_are_outstanding() {
+        let mut state = token();
+        let allowance = |expires_at| Allowance { allowance: Nat::from(10u64), expires_at };
+        state.allowances.insert((account(1), account(2)), allowance(Some(NOW)));
+        state.allowances.insert((account(1), account(3)), allowance(None));
+        assert!(matches!(check_spawnable(&mut state, NOW), Err(ApiError::InvalidState(_))));
+        // The expired allowance is pruned, the live one still blocks the spawn
+        assert_eq!(state.allowances.len(), 1);
+        state.allowances.clear();
+        assert_eq!(check_spawnable(&mut state, NOW), Ok(()));
+    }
+
+    #[test]
+    fn only_active_local_tokens_are_spawned() {
+        let mut state = token();
+        state.status = TokenStatus::Frozen;
+        assert!(matches!(check_spawnable(&mut state, NOW), Err(ApiError::InvalidState(_))));
+        let mut state = token();
+        state.spawning = true;
+        assert!(matches!(check_spawnable(&mut state, NOW), Err(ApiError::InvalidState(_))));
+        let mut state = token();
+        state.ledger_canister = Some(account(9).owner);
+        assert!(matches!(check_spawnable(&mut state, NOW), Err(ApiError::AlreadyExists(_))));
+    }
+
+    #[test]
+    fn only_controllers_spawn_ledgers() {
+        let mut state = token();
+        state.metadata.owner = account(1).owner;
+        TOKEN_STATE.with(|token_state| token_state.borrow_mut().register(state).unwrap());
+        // The token owner is no controller either
+        for caller in [account(1).owner, account(2).owner, Principal::anonymous()] {
+            let mut spawn = pin!(spawn_ledger_as(caller, "ABC".to_string()));
+            let result = spawn.as_mut().poll(&mut Context::from_waker(Waker::noop()));
+            assert!(matches!(result, Poll::Ready(Err(ApiError::Unauthorized(_)))));
+        }
+        TOKEN_STATE.with(|token_state| assert!(!token_state.borrow().get("ABC").unwrap().spawning));
+    }
+}
@@ -1,11 +1,63 @@
 mod agent_core;
-mod token;
 mod token2;
 mod agent_config;
 mod agent;
+mod persistence;
+mod icrc3;
+mod ledger_factory;
+mod delegation;
+mod scheduler;
+mod cron;
+mod task_runs;
+mod retry;
+mod http_outcall;
+mod access;
+mod error;
+mod output_store;
+mod pagination;
 pub use token2::*;
 pub use agent_core::*;
-pub use token::{
-	TransferArgs, TransferError, TransferResult, Metadata, TokenState, Transaction
-	// add other specific items you want from token
-};
+pub use error::ApiError;
+
+// Types named in endpoint signatures, so export_candid! can resolve them from here.
+use std::collections::BTreeMap;
+use candid::{Nat, Principal};
+use ic_cdk::management_canister::{HttpRequestResult, TransformArgs};
+use access::Role;
+use agent::{AgentOutput, OutputLimits, OutputPage, OutputRetention, OutputStats};
+use output_store::OutputChunk;
+use agent_config::{AgentConfig, OutputStatus, Schedule};
+use delegation::{Delegation, DelegationScope};
+use icrc3::{GetBlocksArgs, GetBlocksResult, ICRC3DataCertificate, SupportedBlockType};
+use retry::RetryPolicy;
+use task_runs::{TaskRunPage, TaskRunRetention};
+
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use candid_parser::utils::{service_compatible, CandidSource};
+    use std::path::PathBuf;
+
+    // token.did is what dfx deploys with and what the frontends are generated from, so
+    // it has to match the endpoints here. Run with UPDATE_CANDID=1 to rewrite it.
+    #[test]
+    fn token_did_matches_exported_interface() {
+        let generated = super::__export_service();
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("token.did");
+
+        if std::env::var("UPDATE_CANDID").is_ok() {
+            std::fs::write(&path, &generated).expect("failed to write token.did");
+            return;
+        }
+
+        let checked_in = std::fs::read_to_string(&path).expect("failed to read token.did");
+        service_compatible(CandidSource::Text(&generated), CandidSource::File(&path))
+            .unwrap_or_else(|e| panic!("the canister no longer satisfies token.did: {e:?}"));
+        assert!(
+            generated == checked_in,
+            "token.did is out of date; regenerate it with \
+             `UPDATE_CANDID=1 cargo test -p ai_agent_icp_backend token_did`"
+        );
+    }
+}
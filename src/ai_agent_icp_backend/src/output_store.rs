@@ -0,0 +1,195 @@
+use candid::CandidType;
+use ic_cdk_macros::query;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::error::ApiError;
+
+// Large outputs are kept (and can be read) in pieces of at most this size
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Output content, stored once however many agent output records point at it
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Blob {
+    pub size: u64,
+    pub chunks: Vec<ByteBuf>,
+    // Output records pointing at the blob; rebuilt from the agents on restore
+    #[serde(skip)]
+    pub refs: u64,
+}
+
+impl Blob {
+    fn new(content: &[u8]) -> Self {
+        let chunks = content.chunks(CHUNK_SIZE).map(|chunk| ByteBuf::from(chunk.to_vec())).collect();
+        Blob { size: content.len() as u64, chunks, refs: 0 }
+    }
+
+    fn content(&self) -> String {
+        let bytes: Vec<u8> = self.chunks.iter().flat_map(|chunk| chunk.iter().copied()).collect();
+        // Blobs are only ever built from Strings
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct OutputChunk {
+    pub content: Vec<u8>,
+    pub index: u32,
+    pub chunk_count: u32,
+    pub size: u64, // of the whole output
+}
+
+thread_local! {
+    static BLOBS: RefCell<BTreeMap<String, Blob>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+pub(crate) fn take_stable_state() -> BTreeMap<String, Blob> {
+    BLOBS.with(|blobs| std::mem::take(&mut *blobs.borrow_mut()))
+}
+
+// `refs` counts the output records pointing at each hash. `legacy` is the flat hash to
+// content map of snapshots written before the blob store. Unreferenced blobs are dropped.
+pub(crate) fn restore_stable_state(
+    mut blobs: BTreeMap<String, Blob>,
+    legacy: HashMap<String, String>,
+    refs: HashMap<String, u64>,
+) {
+    for (hash, content) in legacy {
+        blobs.entry(hash).or_insert_with(|| Blob::new(content.as_bytes()));
+    }
+    blobs.retain(|hash, blob| {
+        blob.refs = refs.get(hash).copied().unwrap_or(0);
+        blob.refs > 0
+    });
+    BLOBS.with(|current| *current.borrow_mut() = blobs);
+}
+
+pub(crate) fn hash(content: &str) -> String {
+    hex::encode(Sha256::digest(content.as_bytes()))
+}
+
+// Stores `content` for one more output record and returns its hash
+pub(crate) fn put(content: String) -> String {
+    let hash = hash(&content);
+    BLOBS.with(|blobs| {
+        blobs.borrow_mut().entry(hash.clone()).or_insert_with(|| Blob::new(content.as_bytes())).refs += 1;
+    });
+    hash
+}
+
+// Drops one output record's reference to `hash`, and the blob with the last one
+pub(crate) fn release(hash: &str) {
+    BLOBS.with(|blobs| {
+        let mut blobs = blobs.borrow_mut();
+        if let Some(blob) = blobs.get_mut(hash) {
+            blob.refs = blob.refs.saturating_sub(1);
+            if blob.refs == 0 {
+                blobs.remove(hash);
+            }
+        }
+    });
+}
+
+pub(crate) fn get(hash: &str) -> Option<String> {
+    BLOBS.with(|blobs| blobs.borrow().get(hash).map(Blob::content))
+}
+
+pub(crate) fn size_of(hash: &str) -> Option<u64> {
+    BLOBS.with(|blobs| blobs.borrow().get(hash).map(|blob| blob.size))
+}
+
+// (blobs, bytes) held by the store, each blob counted once
+pub(crate) fn totals() -> (u64, u64) {
+    BLOBS.with(|blobs| {
+        let blobs = blobs.borrow();
+        (blobs.len() as u64, blobs.values().map(|blob| blob.size).sum())
+    })
+}
+
+// Piece `index` of an output, for outputs too large to fetch with get_outputs
+#[query]
+pub fn get_output_chunk(hash: String, index: u32) -> Result<OutputChunk, ApiError> {
+    crate::agent::authorize_output(&hash)?;
+    chunk_of(&hash, index)
+}
+
+fn chunk_of(hash: &str, index: u32) -> Result<OutputChunk, ApiError> {
+    BLOBS.with(|blobs| {
+        let blobs = blobs.borrow();
+        let blob = blobs.get(hash).ok_or_else(|| ApiError::NotFound(format!("No output with hash {}", hash)))?;
+        let chunk = blob.chunks.get(index as usize).ok_or_else(|| {
+            ApiError::InvalidArgument(format!("Output {} has {} chunks", hash, blob.chunks.len()))
+        })?;
+        Ok(OutputChunk { content: chunk.to_vec(), index, chunk_count: blob.chunks.len() as u32, size: blob.size })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refs(hash: &str) -> Option<u64> {
+        BLOBS.with(|blobs| blobs.borrow().get(hash).map(|blob| blob.refs))
+    }
+
+    #[test]
+    fn identical_content_is_stored_once() {
+        let hash = put("output".to_string());
+        assert_eq!(put("output".to_string()), hash);
+        assert_eq!(refs(&hash), Some(2));
+        assert_eq!(totals(), (1, 6));
+        put("other".to_string());
+        assert_eq!(totals(), (2, 11));
+    }
+
+    #[test]
+    fn the_last_release_frees_the_content() {
+        let hash = put("output".to_string());
+        put("output".to_string());
+        release(&hash);
+        assert_eq!(get(&hash), Some("output".to_string()));
+        release(&hash);
+        assert_eq!(get(&hash), None);
+        assert_eq!(totals(), (0, 0));
+        // Releasing what isn't there is a no-op
+        release(&hash);
+        assert_eq!(refs(&hash), None);
+    }
+
+    #[test]
+    fn restore_rebuilds_refs_and_drops_unreferenced_content() {
+        let (kept, dropped, legacy) = (hash("kept"), hash("dropped"), hash("legacy"));
+        let blobs = BTreeMap::from([
+            (kept.clone(), Blob::new(b"kept")),
+            (dropped.clone(), Blob::new(b"dropped")),
+        ]);
+        let refs_by_hash = HashMap::from([(kept.clone(), 2), (legacy.clone(), 1)]);
+        restore_stable_state(blobs, HashMap::from([(legacy.clone(), "legacy".to_string())]), refs_by_hash);
+        assert_eq!(refs(&kept), Some(2));
+        assert_eq!(refs(&legacy), Some(1));
+        assert_eq!(get(&legacy), Some("legacy".to_string()));
+        assert_eq!(refs(&dropped), None);
+        // The rebuilt counts work like ones counted by `put`
+        release(&kept);
+        release(&kept);
+        assert_eq!(get(&kept), None);
+    }
+
+    #[test]
+    fn large_outputs_are_split_into_64kb_chunks() {
+        let content = "x".repeat(CHUNK_SIZE + 1);
+        let large = put(content.clone());
+        let first = chunk_of(&large, 0).unwrap();
+        assert_eq!((first.content.len(), first.chunk_count, first.size), (CHUNK_SIZE, 2, CHUNK_SIZE as u64 + 1));
+        assert_eq!(chunk_of(&large, 1).unwrap().content, b"x".to_vec());
+        assert!(matches!(chunk_of(&large, 2), Err(ApiError::InvalidArgument(_))));
+        assert_eq!(get(&large), Some(content));
+
+        let exact = put("y".repeat(CHUNK_SIZE));
+        assert_eq!(chunk_of(&exact, 0).unwrap().chunk_count, 1);
+        assert!(matches!(chunk_of(&hash("missing"), 0), Err(ApiError::NotFound(_))));
+    }
+}
@@ -0,0 +1,51 @@
+// Cursor paging over records listed newest first, keyed by an ID that grows with age
+// going the other way (run IDs, output run IDs...)
+
+// Up to `limit` records of `newest_first` older than `cursor`, and the cursor of the
+// page after them if any records are left. A `limit` of 0 is read as 1, so a page is
+// never empty while records remain.
+pub(crate) fn page<'a, T: Clone + 'a>(
+    newest_first: impl Iterator<Item = &'a T>,
+    id: impl Fn(&T) -> u64,
+    cursor: Option<u64>,
+    limit: usize,
+) -> (Vec<T>, Option<u64>) {
+    let mut older = newest_first.filter(|record| cursor.is_none_or(|cursor| id(record) < cursor)).peekable();
+    let records: Vec<T> = older.by_ref().take(limit.max(1)).cloned().collect();
+    let next_cursor = match (older.peek(), records.last()) {
+        (Some(_), Some(last)) => Some(id(last)),
+        _ => None,
+    };
+    (records, next_cursor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(records: &[u64], cursor: Option<u64>, limit: usize) -> (Vec<u64>, Option<u64>) {
+        page(records.iter().rev(), |id| *id, cursor, limit)
+    }
+
+    #[test]
+    fn walks_back_one_page_at_a_time() {
+        let records = [1, 2, 3, 4, 5];
+        assert_eq!(ids(&records, None, 2), (vec![5, 4], Some(4)));
+        assert_eq!(ids(&records, Some(4), 2), (vec![3, 2], Some(2)));
+        assert_eq!(ids(&records, Some(2), 2), (vec![1], None));
+    }
+
+    #[test]
+    fn the_last_full_page_has_no_cursor() {
+        assert_eq!(ids(&[1, 2], None, 2), (vec![2, 1], None));
+        assert_eq!(ids(&[], None, 2), (vec![], None));
+        assert_eq!(ids(&[1, 2], Some(1), 2), (vec![], None));
+    }
+
+    #[test]
+    fn a_zero_limit_still_makes_progress() {
+        let records = [1, 2, 3];
+        assert_eq!(ids(&records, None, 0), (vec![3], Some(3)));
+        assert_eq!(ids(&records, Some(2), 0), (vec![1], None));
+    }
+}
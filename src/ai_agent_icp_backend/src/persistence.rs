@@ -0,0 +1,352 @@
+use ic_cdk::api::debug_print;
+use ic_cdk::stable::{stable_grow, stable_read, stable_size, stable_write};
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade};
+use serde::{Deserialize, Serialize};
+use candid::Principal;
+use std::collections::BTreeMap;
+
+use crate::agent::StableAgents;
+use crate::agent_core::Agent;
+use crate::delegation::Delegation;
+
+// Layout of stable memory: [payload length: u64 LE][schema version: u32 LE][CBOR payload]
+const HEADER_LEN: u64 = 12;
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+// Bump this whenever a change to the persisted types can't be expressed as a
+// `#[serde(default)]` field, and add the matching arm to `migrate`.
+pub const STATE_VERSION: u32 = 2;
+
+// Snapshot of every heap structure in the canister, written on upgrade
+#[derive(Serialize, Deserialize, Default)]
+pub struct StableState {
+    // Single ICRC-1 token of snapshots written before it moved into `token2`; read only
+    #[serde(default, skip_serializing)]
+    pub token: Option<crate::token2::LegacyTokenState>,
+    #[serde(default)]
+    pub token2: crate::token2::TokenRegistry,
+    // Single canister-wide agent of snapshots written before `agent_tasks`; read only
+    #[serde(default, skip_serializing)]
+    pub agent: Option<Agent>,
+    #[serde(default)]
+    pub agent_tasks: BTreeMap<u64, Agent>,
+    #[serde(default)]
+    pub next_task_id: u64,
+    #[serde(default)]
+    pub agents: StableAgents,
+    #[serde(default, with = "serde_bytes")]
+    pub ledger_wasm: Option<Vec<u8>>,
+    #[serde(default)]
+    pub delegations: BTreeMap<Principal, Vec<Delegation>>,
+    #[serde(default)]
+    pub task_runs: crate::task_runs::TaskHistory,
+}
+
+impl StableState {
+    fn take() -> Self {
+        let (agent_tasks, next_task_id) = crate::agent_core::take_stable_state();
+        Self {
+            token: None,
+            token2: crate::token2::take_stable_state(),
+            agent: None,
+            agent_tasks,
+            next_task_id,
+            agents: crate::agent::take_stable_state(),
+            ledger_wasm: crate::ledger_factory::take_stable_state(),
+            delegations: crate::delegation::take_stable_state(),
+            task_runs: crate::task_runs::take_stable_state(),
+        }
+    }
+
+    fn restore(self) {
+        let legacy_symbol = crate::token2::restore_stable_state(self.token2, self.token);
+        crate::agent::restore_stable_state(self.agents);
+        crate::agent_core::restore_stable_state(self.agent_tasks, self.next_task_id, self.agent, legacy_symbol);
+        crate::ledger_factory::restore_stable_state(self.ledger_wasm);
+        crate::delegation::restore_stable_state(self.delegations);
+        crate::task_runs::restore_stable_state(self.task_runs);
+    }
+}
+
+pub fn encode_state(state: &StableState) -> Result<Vec<u8>, String> {
+    let mut payload = Vec::new();
+    ciborium::into_writer(state, &mut payload).map_err(|e| format!("Failed to encode state: {}", e))?;
+    let mut bytes = Vec::with_capacity(HEADER_LEN as usize + payload.len());
+    bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&STATE_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+// Decodes a payload written by any earlier schema version into the current one
+pub fn migrate(version: u32, payload: &[u8]) -> Result<StableState, String> {
+    match version {
+        // v1 stored agent_core tasks as stringly JSON, and could hold tokens minting from
+        // the owner's default account
+        1 => {
+            let mut value: ciborium::Value =
+                ciborium::from_reader(payload).map_err(|e| format!("Failed to decode state v{}: {}", version, e))?;
+            crate::agent_core::migrate_v1_tasks(&mut value)?;
+            let mut state: StableState =
+                value.deserialized().map_err(|e| format!("Failed to migrate state v{}: {}", version, e))?;
+            crate::token2::migrate_v1_minting_accounts(&mut state.token2);
+            Ok(state)
+        }
+        2 => ciborium::from_reader(payload).map_err(|e| format!("Failed to decode state v{}: {}", version, e)),
+        _ => Err(format!("Unsupported stable state version {} (current is {})", version, STATE_VERSION)),
+    }
+}
+
+pub fn decode_state(bytes: &[u8]) -> Result<Option<StableState>, String> {
+    if (bytes.len() as u64) < HEADER_LEN {
+        return Ok(None);
+    }
+    let len = u64::from_le_bytes(bytes[0..8].try_into().unwrap());
+    if len == 0 {
+        return Ok(None);
+    }
+    let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    let end = HEADER_LEN + len;
+    if end > bytes.len() as u64 {
+        return Err(format!("Stable state is truncated: expected {} bytes, found {}", end, bytes.len()));
+    }
+    migrate(version, &bytes[HEADER_LEN as usize..end as usize]).map(Some)
+}
+
+// Timers don't survive upgrades, so both lifecycle entry points start them
+// once the state they act on is in place
+fn start_timers() {
+    crate::token2::start_ledger_pruning();
+    crate::agent_core::reschedule_all();
+    crate::agent::reschedule_agents();
+    crate::agent::start_output_pruning();
+}
+
+#[init]
+fn init() {
+    start_timers();
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let bytes = encode_state(&StableState::take()).unwrap_or_else(|e| ic_cdk::trap(e));
+    let required_pages = (bytes.len() as u64).div_ceil(WASM_PAGE_SIZE);
+    let current_pages = stable_size();
+    if required_pages > current_pages {
+        stable_grow(required_pages - current_pages)
+            .unwrap_or_else(|e| ic_cdk::trap(format!("Failed to grow stable memory: {}", e)));
+    }
+    stable_write(0, &bytes);
+    debug_print(format!("Saved {} bytes of state (version {})", bytes.len(), STATE_VERSION));
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    restore_state();
+    start_timers();
+}
+
+fn restore_state() {
+    // A canister upgraded from a build without persistence has no stable memory yet
+    if stable_size() == 0 {
+        debug_print("No stable state found, starting fresh");
+        return;
+    }
+    let mut header = [0u8; HEADER_LEN as usize];
+    stable_read(0, &mut header);
+    let len = u64::from_le_bytes(header[0..8].try_into().unwrap());
+    if HEADER_LEN + len > stable_size() * WASM_PAGE_SIZE {
+        ic_cdk::trap(format!("Stable state length {} exceeds stable memory size", len));
+    }
+    let mut bytes = vec![0u8; (HEADER_LEN + len) as usize];
+    stable_read(0, &mut bytes);
+    match decode_state(&bytes) {
+        Ok(Some(state)) => {
+            state.restore();
+            debug_print(format!("Restored {} bytes of state", bytes.len()));
+        }
+        Ok(None) => debug_print("No stable state found, starting fresh"),
+        Err(e) => ic_cdk::trap(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_core::{TaskAction, TaskResult};
+    use crate::token2::{Account, Metadata, TokenState};
+    use candid::Nat;
+    use ciborium::Value;
+    use std::collections::VecDeque;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn value<T: Serialize>(value: &T) -> Value {
+        Value::serialized(value).unwrap()
+    }
+
+    fn map(entries: Vec<(&str, Value)>) -> Value {
+        Value::Map(entries.into_iter().map(|(key, value)| (Value::Text(key.to_string()), value)).collect())
+    }
+
+    // Stable memory contents as an older build would have written them
+    fn snapshot(version: u32, payload: &Value) -> Vec<u8> {
+        let mut cbor = Vec::new();
+        ciborium::into_writer(payload, &mut cbor).unwrap();
+        let mut bytes = (cbor.len() as u64).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&version.to_le_bytes());
+        bytes.extend_from_slice(&cbor);
+        bytes
+    }
+
+    fn token(symbol: &str, owner: Principal, balance: u64) -> TokenState {
+        let mut state = TokenState {
+            metadata: Metadata { symbol: symbol.to_string(), owner, total_supply: Nat::from(balance), ..Default::default() },
+            ..Default::default()
+        };
+        state.balances.insert(Account { owner, subaccount: None }, Nat::from(balance));
+        state
+    }
+
+    fn legacy_task(id: u64, action_type: &str, data: &str) -> Value {
+        map(vec![
+            ("id", value(&id)),
+            ("data", value(&data)),
+            ("frequency", value(&60u64)),
+            ("last_run", value(&0u64)),
+            ("url", Value::Null),
+            ("action_type", value(&action_type)),
+            ("enabled", value(&true)),
+        ])
+    }
+
+    #[test]
+    fn empty_or_short_memory_has_no_state() {
+        assert!(decode_state(&[]).unwrap().is_none());
+        assert!(decode_state(&[0u8; HEADER_LEN as usize]).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_and_unknown_versions() {
+        let mut bytes = encode_state(&StableState::default()).unwrap();
+        bytes.pop();
+        assert!(decode_state(&bytes).err().unwrap().contains("truncated"));
+        let bytes = snapshot(STATE_VERSION + 1, &map(vec![]));
+        assert!(decode_state(&bytes).err().unwrap().contains("Unsupported"));
+    }
+
+    #[test]
+    fn current_state_round_trips() {
+        let owner = principal(1);
+        let mut state = StableState::default();
+        state.token2.register(token("ABC", owner, 100)).unwrap();
+        state.agent_tasks.insert(
+            0,
+            Agent { owner, tasks: VecDeque::new(), active: true, created_at: 5, roles: BTreeMap::new() },
+        );
+        state.next_task_id = 12;
+        state.ledger_wasm = Some(vec![0, 97, 115, 109]);
+        state.delegations.insert(
+            owner,
+            vec![Delegation { delegate: principal(2), scopes: Vec::new(), symbols: None, expires_at: Some(9), created_at: 1 }],
+        );
+
+        let bytes = encode_state(&state).unwrap();
+        let decoded = decode_state(&bytes).unwrap().unwrap();
+        assert_eq!(decoded.token2.get("ABC").unwrap().balances[&Account { owner, subaccount: None }], Nat::from(100u64));
+        assert_eq!(decoded.token2.id_of("ABC"), Some(1));
+        assert_eq!(decoded.agent_tasks[&0].created_at, 5);
+        assert_eq!(decoded.next_task_id, 12);
+        assert_eq!(decoded.ledger_wasm, state.ledger_wasm);
+        assert_eq!(decoded.delegations[&owner][0].expires_at, Some(9));
+        // Nothing is lost or added on the way through
+        assert_eq!(encode_state(&decoded).unwrap(), bytes);
+    }
+
+    #[test]
+    fn migrates_v1_tasks_to_typed_actions() {
+        let owner = principal(1);
+        let to = principal(3);
+        let transfer = format!(r#"{{"to":"{}","amount":"25","status":"success","tx_id":"7"}}"#, to.to_text());
+        let payload = map(vec![(
+            "agent",
+            map(vec![
+                ("owner", value(&owner)),
+                (
+                    "tasks",
+                    Value::Array(vec![
+                        legacy_task(1, "token_transfer", &transfer),
+                        legacy_task(2, "token_mint", r#"{"amount":"5"}"#),
+                        legacy_task(3, "summarize", "free text"),
+                    ]),
+                ),
+                ("active", value(&true)),
+                ("created_at", value(&11u64)),
+            ]),
+        )]);
+
+        let state = decode_state(&snapshot(1, &payload)).unwrap().unwrap();
+        let agent = state.agent.unwrap();
+        assert_eq!(agent.owner, owner);
+        let tasks: Vec<_> = agent.tasks.into_iter().collect();
+        match &tasks[0].action {
+            TaskAction::Transfer { symbol, to: account, amount, memo } => {
+                assert!(symbol.is_empty()); // filled in with the legacy token's symbol on restore
+                assert_eq!(account.owner, to);
+                assert_eq!(*amount, Nat::from(25u64));
+                assert!(memo.is_none());
+            }
+            other => panic!("expected a transfer, got {:?}", other),
+        }
+        assert!(matches!(tasks[0].last_result, Some(TaskResult::Success { tx_id: Some(ref id) }) if *id == 7u64));
+        assert!(tasks[0].enabled);
+        // The mint has no recipient, so it is kept but never runs
+        assert!(matches!(tasks[1].action, TaskAction::Custom { .. }));
+        assert!(!tasks[1].enabled);
+        assert!(matches!(tasks[2].action, TaskAction::Custom { ref data } if data == "free text"));
+        assert!(tasks[2].enabled);
+    }
+
+    #[test]
+    fn migrates_symbol_keyed_token2_ledgers_into_the_registry() {
+        let owner = principal(1);
+        let mut by_symbol = BTreeMap::new();
+        by_symbol.insert("ZED", token("ZED", owner, 1));
+        by_symbol.insert("ABC", token("ABC", owner, 2));
+        let state = decode_state(&snapshot(1, &map(vec![("token2", value(&by_symbol))]))).unwrap().unwrap();
+
+        // IDs follow the symbol order, and the next token gets a fresh one
+        assert_eq!(state.token2.id_of("ABC"), Some(1));
+        assert_eq!(state.token2.id_of("ZED"), Some(2));
+        // They minted from the owner's default account before burn support
+        assert_eq!(state.token2.get("ABC").unwrap().minting_account.subaccount, Some(crate::token2::MINTING_SUBACCOUNT));
+        let mut registry = state.token2;
+        assert_eq!(registry.register(token("NEW", owner, 0)).unwrap(), 3);
+    }
+
+    #[test]
+    fn migrates_the_legacy_single_token() {
+        let owner = principal(1);
+        let holder = Account { owner: principal(4), subaccount: None };
+        let mut legacy = token("ABC", owner, 60);
+        legacy.balances.insert(holder.clone(), Nat::from(40u64));
+        let payload = map(vec![
+            ("token", value(&legacy)),
+            ("token2", value(&BTreeMap::from([("ABC", token("ABC", owner, 1))]))),
+        ]);
+        let mut state = decode_state(&snapshot(2, &payload)).unwrap().unwrap();
+        // Only v1 snapshots have their minting accounts moved
+        assert_eq!(state.token2.get("ABC").unwrap().minting_account.subaccount, None);
+
+        // Its symbol is taken by now, so it moves out of the way
+        let symbol = crate::token2::migrate_legacy_token(&mut state.token2, state.token.unwrap(), 1_000).unwrap();
+        assert_eq!(symbol, "LEGACY-1");
+        let migrated = state.token2.get("LEGACY-1").unwrap();
+        assert_eq!(migrated.balances[&holder], Nat::from(40u64));
+        assert_eq!(migrated.minting_account.subaccount, Some(crate::token2::MINTING_SUBACCOUNT));
+        // One mint block per balance, so the block log adds up to the supply
+        assert_eq!(migrated.blocks.len(), 2);
+    }
+}
@@ -0,0 +1,187 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ApiError;
+use crate::token2::TransferError;
+
+// Why a task run failed, used to decide whether it is worth retrying
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub enum TaskErrorKind {
+    InsufficientFunds,
+    BadFee,
+    BadBurn,
+    TooOld,
+    CreatedInFuture,
+    Duplicate,
+    TemporarilyUnavailable,
+    GenericError,
+    InvalidTask, // the task itself can't be carried out, e.g. a token already exists
+    InsufficientCycles,
+    HttpClientError, // 4xx
+    HttpServerError, // 5xx and other non-2xx
+    CallRejected,    // the outcall itself failed
+    #[default]
+    Other,
+}
+
+impl From<&ApiError> for TaskErrorKind {
+    fn from(err: &ApiError) -> Self {
+        match err {
+            ApiError::Transfer(err) => match err {
+                TransferError::BadFee { .. } => TaskErrorKind::BadFee,
+                TransferError::BadBurn { .. } => TaskErrorKind::BadBurn,
+                TransferError::InsufficientFunds { .. } | TransferError::InsufficientAllowance { .. } => {
+                    TaskErrorKind::InsufficientFunds
+                }
+                TransferError::TooOld | TransferError::Expired { .. } => TaskErrorKind::TooOld,
+                TransferError::CreatedInFuture { .. } => TaskErrorKind::CreatedInFuture,
+                TransferError::Duplicate { .. } => TaskErrorKind::Duplicate,
+                TransferError::TemporarilyUnavailable => TaskErrorKind::TemporarilyUnavailable,
+                TransferError::GenericError { .. } | TransferError::AllowanceChanged { .. } => TaskErrorKind::GenericError,
+            },
+            // The token may be unfrozen, or the call may go through next time
+            ApiError::InvalidState(_) | ApiError::CallFailed(_) => TaskErrorKind::TemporarilyUnavailable,
+            ApiError::QuotaExceeded(_) => TaskErrorKind::Other,
+            ApiError::NotFound(_)
+            | ApiError::Unauthorized(_)
+            | ApiError::AlreadyExists(_)
+            | ApiError::InvalidArgument(_)
+            | ApiError::Moved(_) => TaskErrorKind::InvalidTask,
+        }
+    }
+}
+
+// How a task is retried after a failed run. Delays are in seconds.
+#[derive(Clone, Debug, Serialize, Deserialize, CandidType, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32, // including the first run
+    pub backoff_base_seconds: u64,
+    pub backoff_cap_seconds: u64,
+    pub jitter_percent: u8, // up to this much is added to each delay
+    pub retryable: Vec<TaskErrorKind>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff_base_seconds: 30,
+            backoff_cap_seconds: 60 * 60,
+            jitter_percent: 10,
+            retryable: vec![
+                TaskErrorKind::InsufficientFunds,
+                TaskErrorKind::TemporarilyUnavailable,
+                TaskErrorKind::GenericError,
+                TaskErrorKind::CreatedInFuture,
+                TaskErrorKind::HttpServerError,
+                TaskErrorKind::CallRejected,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_attempts == 0 {
+            return Err("max_attempts must be at least 1".to_string());
+        }
+        if self.backoff_base_seconds > self.backoff_cap_seconds {
+            return Err("backoff_base_seconds can't exceed backoff_cap_seconds".to_string());
+        }
+        if self.jitter_percent > 100 {
+            return Err("jitter_percent can't exceed 100".to_string());
+        }
+        Ok(())
+    }
+
+    // Delay before the retry that follows failed attempt number `attempt` (1-based),
+    // or None once the task should give up
+    pub fn next_delay(&self, attempt: u32, kind: TaskErrorKind, seed: u64) -> Option<u64> {
+        if attempt >= self.max_attempts || !self.retryable.contains(&kind) {
+            return None;
+        }
+        let exponent = (attempt - 1).min(63);
+        let delay = self.backoff_base_seconds.saturating_mul(1u64 << exponent).min(self.backoff_cap_seconds);
+        let max_jitter = delay.saturating_mul(self.jitter_percent as u64) / 100;
+        Some(delay + splitmix64(seed) % (max_jitter + 1))
+    }
+}
+
+// Cheap deterministic mixing; jitter only has to spread retries apart, not be unpredictable
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter_percent: u8) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 6,
+            backoff_base_seconds: 10,
+            backoff_cap_seconds: 100,
+            jitter_percent,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = policy(0);
+        let delays: Vec<_> = (1..=5).map(|attempt| policy.next_delay(attempt, TaskErrorKind::GenericError, 7)).collect();
+        assert_eq!(delays, vec![Some(10), Some(20), Some(40), Some(80), Some(100)]);
+        // No overflow however often it failed
+        let policy = RetryPolicy { max_attempts: u32::MAX, backoff_cap_seconds: u64::MAX, ..policy };
+        assert_eq!(policy.next_delay(200, TaskErrorKind::GenericError, 7), Some(u64::MAX));
+    }
+
+    #[test]
+    fn gives_up_after_the_last_attempt() {
+        let policy = policy(0);
+        assert!(policy.next_delay(5, TaskErrorKind::GenericError, 0).is_some());
+        assert_eq!(policy.next_delay(6, TaskErrorKind::GenericError, 0), None);
+        assert_eq!(RetryPolicy { max_attempts: 1, ..policy }.next_delay(1, TaskErrorKind::GenericError, 0), None);
+    }
+
+    #[test]
+    fn only_retryable_errors_are_retried() {
+        let policy = policy(0);
+        for kind in [TaskErrorKind::InvalidTask, TaskErrorKind::BadFee, TaskErrorKind::HttpClientError, TaskErrorKind::Other] {
+            assert_eq!(policy.next_delay(1, kind, 0), None);
+        }
+        let policy = RetryPolicy { retryable: vec![TaskErrorKind::Other], ..policy };
+        assert_eq!(policy.next_delay(1, TaskErrorKind::Other, 0), Some(10));
+        assert_eq!(policy.next_delay(1, TaskErrorKind::GenericError, 0), None);
+    }
+
+    #[test]
+    fn jitter_stays_within_its_percentage() {
+        let policy = policy(50);
+        let delays: Vec<u64> = (0..1_000).map(|seed| policy.next_delay(3, TaskErrorKind::GenericError, seed).unwrap()).collect();
+        assert!(delays.iter().all(|delay| (40..=60).contains(delay)));
+        // Spread out, but the same seed always gives the same delay
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+        assert_eq!(policy.next_delay(3, TaskErrorKind::GenericError, 42), policy.next_delay(3, TaskErrorKind::GenericError, 42));
+    }
+
+    #[test]
+    fn validates_policies() {
+        assert!(RetryPolicy::default().validate().is_ok());
+        assert!(RetryPolicy { max_attempts: 0, ..Default::default() }.validate().is_err());
+        assert!(RetryPolicy { backoff_base_seconds: 10, backoff_cap_seconds: 5, ..Default::default() }.validate().is_err());
+        assert!(RetryPolicy { jitter_percent: 101, ..Default::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn classifies_api_errors() {
+        let kind = |err: ApiError| TaskErrorKind::from(&err);
+        assert_eq!(kind(ApiError::Transfer(TransferError::InsufficientFunds { balance: 0u64.into() })), TaskErrorKind::InsufficientFunds);
+        assert_eq!(kind(ApiError::Transfer(TransferError::Expired { ledger_time: 0 })), TaskErrorKind::TooOld);
+        assert_eq!(kind(ApiError::CallFailed(String::new())), TaskErrorKind::TemporarilyUnavailable);
+        assert_eq!(kind(ApiError::NotFound(String::new())), TaskErrorKind::InvalidTask);
+    }
+}
@@ -0,0 +1,179 @@
+use ic_cdk::api::{debug_print, time};
+use ic_cdk_timers::{clear_timer, set_timer, TimerId};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+
+// Something the scheduler wakes up for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Job {
+    Task(u64),  // agent_core task ID
+    Agent(u64), // agent::AGENTS ID
+}
+
+// Jobs ordered by when they are next due (seconds since epoch)
+#[derive(Default)]
+pub struct TaskQueue {
+    by_due: BTreeSet<(u64, Job)>,
+    due_of: HashMap<Job, u64>,
+}
+
+impl TaskQueue {
+    // Schedules `job` at `due`, or drops it from the queue when `due` is None
+    pub fn set(&mut self, job: Job, due: Option<u64>) {
+        if let Some(old) = self.due_of.remove(&job) {
+            self.by_due.remove(&(old, job));
+        }
+        if let Some(due) = due {
+            self.due_of.insert(job, due);
+            self.by_due.insert((due, job));
+        }
+    }
+
+    pub fn next_due(&self) -> Option<u64> {
+        self.by_due.first().map(|(due, _)| *due)
+    }
+
+    // Removes and returns every job due at or before `now`, earliest first
+    pub fn pop_due(&mut self, now: u64) -> Vec<Job> {
+        let mut due = Vec::new();
+        while let Some(&(at, job)) = self.by_due.first() {
+            if at > now {
+                break;
+            }
+            self.by_due.pop_first();
+            self.due_of.remove(&job);
+            due.push(job);
+        }
+        due
+    }
+
+    pub fn retain(&mut self, keep: impl Fn(&Job) -> bool) {
+        self.by_due.retain(|(_, job)| keep(job));
+        self.due_of.retain(|job, _| keep(job));
+    }
+}
+
+thread_local! {
+    static QUEUE: RefCell<TaskQueue> = RefCell::new(TaskQueue::default());
+    // The single pending timer and the due time it was armed for
+    static TIMER: RefCell<Option<(u64, TimerId)>> = const { RefCell::new(None) };
+}
+
+pub fn schedule(job: Job, due: Option<u64>) {
+    QUEUE.with(|q| q.borrow_mut().set(job, due));
+    arm();
+}
+
+// Replaces every queued job matching `replaced` with `entries`, e.g. after an
+// upgrade (timers don't survive one)
+pub fn reset(replaced: impl Fn(&Job) -> bool, entries: impl IntoIterator<Item = (Job, Option<u64>)>) {
+    QUEUE.with(|q| {
+        let mut queue = q.borrow_mut();
+        queue.retain(|job| !replaced(job));
+        for (job, due) in entries {
+            queue.set(job, due);
+        }
+    });
+    arm();
+}
+
+// Keeps exactly one timer pending, for the earliest due job
+fn arm() {
+    let next = QUEUE.with(|q| q.borrow().next_due());
+    TIMER.with(|t| {
+        let mut timer = t.borrow_mut();
+        if timer.as_ref().map(|(due, _)| *due) == next {
+            return;
+        }
+        if let Some((_, id)) = timer.take() {
+            clear_timer(id);
+        }
+        if let Some(due) = next {
+            let delay = due.saturating_mul(1_000_000_000).saturating_sub(time());
+            *timer = Some((due, set_timer(Duration::from_nanos(delay), fire)));
+        }
+    });
+}
+
+fn fire() {
+    TIMER.with(|t| *t.borrow_mut() = None);
+    let now = time() / 1_000_000_000;
+    let due = QUEUE.with(|q| q.borrow_mut().pop_due(now));
+    debug_print(format!("Scheduler woke up at {} with {} due job(s)", now, due.len()));
+    for job in due {
+        let next = match job {
+            Job::Task(task_id) => crate::agent_core::run_scheduled_task(task_id, now),
+            Job::Agent(agent_id) => crate::agent::run_scheduled_agent(agent_id, now),
+        };
+        QUEUE.with(|q| q.borrow_mut().set(job, next));
+    }
+    arm();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_is_due_before_the_earliest_job() {
+        let mut queue = TaskQueue::default();
+        assert_eq!(queue.next_due(), None);
+        assert!(queue.pop_due(u64::MAX).is_empty());
+
+        queue.set(Job::Task(1), Some(100));
+        queue.set(Job::Agent(1), Some(160));
+        assert_eq!(queue.next_due(), Some(100));
+        for now in [0, 50, 99] {
+            assert!(queue.pop_due(now).is_empty());
+        }
+        // Looking doesn't consume anything
+        assert_eq!(queue.next_due(), Some(100));
+    }
+
+    #[test]
+    fn pops_every_due_job_earliest_first() {
+        let mut queue = TaskQueue::default();
+        queue.set(Job::Task(1), Some(120));
+        queue.set(Job::Task(2), Some(100));
+        queue.set(Job::Agent(1), Some(100));
+        queue.set(Job::Task(3), Some(200));
+
+        assert_eq!(queue.pop_due(120), vec![Job::Task(2), Job::Agent(1), Job::Task(1)]);
+        assert!(queue.pop_due(120).is_empty());
+        assert_eq!(queue.next_due(), Some(200));
+        assert_eq!(queue.pop_due(500), vec![Job::Task(3)]);
+        assert_eq!(queue.next_due(), None);
+    }
+
+    #[test]
+    fn rescheduling_replaces_the_previous_due_time() {
+        let mut queue = TaskQueue::default();
+        queue.set(Job::Task(1), Some(100));
+        queue.set(Job::Task(1), Some(300));
+        assert_eq!(queue.next_due(), Some(300));
+        assert!(queue.pop_due(299).is_empty());
+        // Re-arming after a run, as `fire` does
+        assert_eq!(queue.pop_due(300), vec![Job::Task(1)]);
+        queue.set(Job::Task(1), Some(360));
+        assert_eq!(queue.next_due(), Some(360));
+        assert_eq!(queue.pop_due(360), vec![Job::Task(1)]);
+    }
+
+    #[test]
+    fn removed_jobs_never_fire() {
+        let mut queue = TaskQueue::default();
+        queue.set(Job::Task(1), Some(100));
+        queue.set(Job::Task(2), Some(200));
+        queue.set(Job::Agent(7), Some(150));
+        queue.set(Job::Task(1), None);
+        assert_eq!(queue.next_due(), Some(150));
+
+        queue.retain(|job| !matches!(job, Job::Agent(_)));
+        assert_eq!(queue.next_due(), Some(200));
+        assert_eq!(queue.pop_due(u64::MAX), vec![Job::Task(2)]);
+        // Removing a job that isn't queued is a no-op
+        queue.set(Job::Task(1), None);
+        assert_eq!(queue.next_due(), None);
+    }
+}
@@ -0,0 +1,207 @@
+use candid::CandidType;
+use ic_cdk::management_canister::HttpRequestResult;
+use ic_cdk_macros::{query, update};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::agent_core::TaskResult;
+use crate::error::ApiError;
+
+// Hard limits, whatever the retention policy says
+const MAX_RUNS_PER_TASK: u32 = 1_000;
+const MAX_PAGE_SIZE: u32 = 100;
+
+#[derive(Clone, Debug, Serialize, Deserialize, CandidType, PartialEq)]
+pub enum TaskRunStatus {
+    Succeeded,
+    Failed,
+}
+
+// One execution of an agent_core task. Times are seconds since epoch.
+#[derive(Clone, Debug, Serialize, Deserialize, CandidType)]
+pub struct TaskRun {
+    pub run_id: u64,
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub status: TaskRunStatus,
+    pub result: Option<String>, // e.g. the transaction ID
+    pub error: Option<String>,
+    pub cycles_used: u64, // cycles the run spent from the canister balance (e.g. on outcalls)
+    #[serde(default)]
+    pub http_response: Option<HttpRequestResult>, // for http_request tasks, after the transform
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, CandidType, PartialEq)]
+pub struct TaskRunRetention {
+    pub max_runs_per_task: u32,
+    pub max_age_seconds: Option<u64>,
+}
+
+impl Default for TaskRunRetention {
+    fn default() -> Self {
+        Self { max_runs_per_task: 50, max_age_seconds: None }
+    }
+}
+
+impl TaskRunRetention {
+    fn validate(&self) -> Result<(), String> {
+        if self.max_runs_per_task > MAX_RUNS_PER_TASK {
+            return Err(format!("At most {} runs per task can be kept", MAX_RUNS_PER_TASK));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TaskRunLog {
+    pub next_run_id: u64,
+    pub runs: VecDeque<TaskRun>, // oldest first
+}
+
+impl TaskRunLog {
+    fn prune(&mut self, retention: &TaskRunRetention, now: u64) {
+        let max_runs = retention.max_runs_per_task.min(MAX_RUNS_PER_TASK) as usize;
+        while self.runs.len() > max_runs {
+            self.runs.pop_front();
+        }
+        if let Some(max_age) = retention.max_age_seconds {
+            let cutoff = now.saturating_sub(max_age);
+            while self.runs.front().is_some_and(|run| run.finished_at < cutoff) {
+                self.runs.pop_front();
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TaskHistory {
+    #[serde(default)]
+    pub logs: BTreeMap<u64, TaskRunLog>,
+    #[serde(default)]
+    pub retention: TaskRunRetention,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TaskRunPage {
+    pub runs: Vec<TaskRun>,       // newest first
+    pub next_cursor: Option<u64>, // pass back to get the following (older) page
+}
+
+thread_local! {
+    static HISTORY: RefCell<TaskHistory> = RefCell::new(TaskHistory::default());
+}
+
+pub(crate) fn take_stable_state() -> TaskHistory {
+    HISTORY.with(|h| std::mem::take(&mut *h.borrow_mut()))
+}
+
+pub(crate) fn restore_stable_state(history: TaskHistory) {
+    HISTORY.with(|h| *h.borrow_mut() = history);
+}
+
+// Appends a run of `task_id` and applies the retention policy to its log
+pub(crate) fn record(
+    task_id: u64,
+    started_at: u64,
+    finished_at: u64,
+    result: &TaskResult,
+    cycles_used: u64,
+    http_response: Option<HttpRequestResult>,
+) -> u64 {
+    HISTORY.with(|h| {
+        let history = &mut *h.borrow_mut();
+        let log = history.logs.entry(task_id).or_default();
+        log.next_run_id += 1;
+        let run_id = log.next_run_id;
+        let (status, result, error) = match result {
+            TaskResult::Success { tx_id } => (TaskRunStatus::Succeeded, tx_id.as_ref().map(|id| id.to_string()), None),
+            TaskResult::Failed { error, .. } => (TaskRunStatus::Failed, None, Some(error.clone())),
+        };
+        log.runs.push_back(TaskRun { run_id, started_at, finished_at, status, result, error, cycles_used, http_response });
+        log.prune(&history.retention, finished_at);
+        run_id
+    })
+}
+
+pub(crate) fn forget(task_id: u64) {
+    HISTORY.with(|h| h.borrow_mut().logs.remove(&task_id));
+}
+
+// Runs of a task, newest first. `cursor` is the `next_cursor` of the previous page.
+#[query]
+pub fn get_task_runs(task_id: u64, cursor: Option<u64>, limit: u32) -> Result<TaskRunPage, ApiError> {
+    crate::agent_core::authorize_task(task_id, crate::access::Permission::View)?;
+    Ok(HISTORY.with(|h| {
+        let history = h.borrow();
+        let Some(log) = history.logs.get(&task_id) else {
+            return TaskRunPage { runs: Vec::new(), next_cursor: None };
+        };
+        let (runs, next_cursor) =
+            crate::pagination::page(log.runs.iter().rev(), |run| run.run_id, cursor, limit.min(MAX_PAGE_SIZE) as usize);
+        TaskRunPage { runs, next_cursor }
+    }))
+}
+
+#[query]
+pub fn get_task_run_retention() -> TaskRunRetention {
+    HISTORY.with(|h| h.borrow().retention.clone())
+}
+
+// Controllers only. Runs the new policy no longer keeps are dropped from every log right away
+#[update]
+pub fn set_task_run_retention(retention: TaskRunRetention) -> Result<(), ApiError> {
+    retention.validate().map_err(ApiError::InvalidArgument)?;
+    if !ic_cdk::api::is_controller(&ic_cdk::api::msg_caller()) {
+        return Err(ApiError::Unauthorized("Only controllers can change the task run retention".to_string()));
+    }
+    let now = ic_cdk::api::time() / 1_000_000_000;
+    HISTORY.with(|h| {
+        let history = &mut *h.borrow_mut();
+        history.retention = retention;
+        for log in history.logs.values_mut() {
+            log.prune(&history.retention, now);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record_runs(task_id: u64, count: u64) {
+        for finished_at in 1..=count {
+            record(task_id, finished_at, finished_at, &TaskResult::Success { tx_id: None }, 0, None);
+        }
+    }
+
+    fn run_ids(task_id: u64) -> Vec<u64> {
+        HISTORY.with(|h| h.borrow().logs[&task_id].runs.iter().map(|run| run.run_id).collect())
+    }
+
+    #[test]
+    fn retention_drops_the_oldest_runs_first() {
+        record_runs(1, 60);
+        assert_eq!(run_ids(1), (11..=60).collect::<Vec<_>>());
+        // Other tasks keep their own logs
+        record_runs(2, 3);
+        assert_eq!(run_ids(2), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn retention_drops_runs_past_their_max_age() {
+        HISTORY.with(|h| h.borrow_mut().retention = TaskRunRetention { max_runs_per_task: 50, max_age_seconds: Some(5) });
+        record_runs(1, 20);
+        assert_eq!(run_ids(1), (15..=20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn retention_is_capped() {
+        let retention = |max_runs_per_task| TaskRunRetention { max_runs_per_task, max_age_seconds: None };
+        assert!(retention(MAX_RUNS_PER_TASK).validate().is_ok());
+        let result = set_task_run_retention(retention(MAX_RUNS_PER_TASK + 1));
+        assert!(matches!(result, Err(ApiError::InvalidArgument(_))));
+        assert_eq!(get_task_run_retention(), TaskRunRetention::default());
+    }
+}
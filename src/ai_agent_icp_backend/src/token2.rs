@@ -1,10 +1,20 @@
-use ic_cdk::api::{ debug_print, msg_caller, time};
+#[cfg(not(test))]
+use ic_cdk::api::time;
+use ic_cdk::api::{ debug_print, msg_caller};
 use ic_cdk_macros::{query, update};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::cell::RefCell;
-use std::collections::{HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use candid::{Principal, CandidType, Nat};
 
+use crate::delegation::{is_authorized, DelegationScope};
+use crate::error::ApiError;
+use crate::icrc3::{
+    get_blocks, hash_value, labeled_forest, leb128, GetBlocksArgs, GetBlocksResult, Hash, HashTree,
+    ICRC3DataCertificate, ICRC3Value, SupportedBlockType,
+};
+use sha2::{Digest, Sha256};
+
 #[derive(CandidType, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
 pub struct Account {
     pub owner: Principal,
@@ -30,6 +40,14 @@ pub struct TransferArgs {
     pub created_at_time: Option<u64>,
 }
 
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct BurnArgs {
+    pub from_subaccount: Option<[u8; 32]>,
+    pub amount: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
 #[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
 pub struct ApproveArgs {
     pub from_subaccount: Option<[u8; 32]>,
@@ -42,6 +60,18 @@ pub struct ApproveArgs {
     pub created_at_time: Option<u64>,
 }
 
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct SetFeeCollectorArgs {
+    pub fee_collector: Option<Account>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Allowance {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>,
+}
+
 #[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
 pub struct AllowanceArgs {
     pub account: Account,
@@ -59,7 +89,7 @@ pub struct TransferFromArgs {
     pub created_at_time: Option<u64>,
 }
 
-#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum TransferError {
     BadFee { expected_fee: Nat },
     BadBurn { min_burn_amount: Nat },
@@ -70,12 +100,8 @@ pub enum TransferError {
     TemporarilyUnavailable,
     GenericError { error_code: Nat, message: String },
     InsufficientAllowance { allowance: Nat },
-}
-
-#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
-pub enum TransferResult {
-    Ok(Nat),
-    Err(TransferError),
+    Expired { ledger_time: u64 },
+    AllowanceChanged { current_allowance: Nat },
 }
 
 #[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
@@ -105,14 +131,719 @@ impl Default for Metadata {
     }
 }
 
-#[derive(Clone, Debug, Default,Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct TokenState {
     pub metadata: Metadata,
     pub balances: HashMap<Account, Nat>,
-    pub allowances: HashMap<(Account, Account), Nat>, // (owner, spender) -> allowance
+    #[serde(deserialize_with = "deserialize_allowances")]
+    pub allowances: HashMap<(Account, Account), Allowance>, // (owner, spender) -> allowance
     pub transactions: Vec<Transaction>,
     pub transaction_counter: u64,
     pub minting_account: Account,
+    #[serde(default)]
+    pub blocks: Vec<ICRC3Value>, // ICRC-3 log, hash-chained through `phash`
+    #[serde(default)]
+    pub dedup: DedupConfig,
+    #[serde(default)]
+    pub recent_transactions: RecentTransactions,
+    #[serde(default)]
+    pub fee_collector: Option<Account>, // ICRC-107: fees are burned when unset
+    #[serde(default)]
+    pub ledger_canister: Option<Principal>, // set once the token moved to its own ledger canister
+    #[serde(default)]
+    pub spawning: bool,
+    #[serde(default)]
+    pub pending_ledger: Option<Principal>, // created for the token, but not installed yet
+    #[serde(default)]
+    pub status: TokenStatus,
+}
+
+// Frozen tokens can be thawed by their owner, retired ones are read-only for good
+#[derive(CandidType, Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TokenStatus {
+    #[default]
+    Active,
+    Frozen,
+    Retired,
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct TokenInfo {
+    pub id: u64,
+    pub symbol: String,
+    pub name: String,
+    pub owner: Principal,
+    pub status: TokenStatus,
+}
+
+// Symbols of well-known ICP tokens, kept off limits to avoid impersonation
+const RESERVED_SYMBOLS: [&str; 7] = ["ICP", "CYCLES", "TCYCLES", "CKBTC", "CKETH", "CKUSDC", "CKUSDT"];
+const MAX_SYMBOL_LEN: usize = 16;
+
+// Every token2 ledger, keyed by an ID that never changes or gets reused. Symbols are
+// unique ignoring case, and stay taken after a token is retired so they never resolve
+// to a different ledger.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TokenRegistry {
+    tokens: BTreeMap<u64, TokenState>,
+    next_id: u64,
+    #[serde(skip)]
+    symbols: HashMap<String, u64>, // rebuilt by `reindex` after a restore
+}
+
+impl<'de> Deserialize<'de> for TokenRegistry {
+    // Snapshots taken before the registry stored a plain symbol -> ledger map
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Current {
+            tokens: BTreeMap<u64, TokenState>,
+            next_id: u64,
+        }
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored {
+            Current(Current),
+            Legacy(HashMap<String, TokenState>),
+        }
+        let mut registry = match Stored::deserialize(deserializer)? {
+            Stored::Current(current) => TokenRegistry {
+                tokens: current.tokens,
+                next_id: current.next_id,
+                symbols: HashMap::new(),
+            },
+            Stored::Legacy(by_symbol) => {
+                let mut by_symbol: Vec<(String, TokenState)> = by_symbol.into_iter().collect();
+                by_symbol.sort_by(|a, b| a.0.cmp(&b.0));
+                let tokens: BTreeMap<u64, TokenState> =
+                    by_symbol.into_iter().enumerate().map(|(i, (_, state))| (i as u64 + 1, state)).collect();
+                TokenRegistry { next_id: tokens.len() as u64, tokens, symbols: HashMap::new() }
+            }
+        };
+        registry.reindex();
+        Ok(registry)
+    }
+}
+
+impl TokenRegistry {
+    fn reindex(&mut self) {
+        self.symbols = self.tokens.iter().map(|(id, state)| (state.metadata.symbol.clone(), *id)).collect();
+    }
+
+    pub fn id_of(&self, symbol: &str) -> Option<u64> {
+        self.symbols.get(symbol).copied()
+    }
+
+    pub fn get(&self, symbol: &str) -> Option<&TokenState> {
+        self.tokens.get(&self.id_of(symbol)?)
+    }
+
+    pub fn get_mut(&mut self, symbol: &str) -> Option<&mut TokenState> {
+        let id = self.id_of(symbol)?;
+        self.tokens.get_mut(&id)
+    }
+
+    // Same as `get`, with the error endpoints report for an unknown symbol
+    pub fn require(&self, symbol: &str) -> Result<&TokenState, ApiError> {
+        self.get(symbol).ok_or_else(|| token_not_found(symbol))
+    }
+
+    // Same as `require`, for reads that would be stale once the token moved to its own ledger
+    pub fn require_local(&self, symbol: &str) -> Result<&TokenState, ApiError> {
+        let state = self.require(symbol)?;
+        match state.ledger_canister {
+            Some(ledger) => Err(ApiError::Moved(ledger)),
+            None => Ok(state),
+        }
+    }
+
+    pub fn require_mut(&mut self, symbol: &str) -> Result<&mut TokenState, ApiError> {
+        self.get_mut(symbol).ok_or_else(|| token_not_found(symbol))
+    }
+
+    // Same as `require_local`, for writes
+    pub fn require_local_mut(&mut self, symbol: &str) -> Result<&mut TokenState, ApiError> {
+        let state = self.require_mut(symbol)?;
+        match state.ledger_canister {
+            Some(ledger) => Err(ApiError::Moved(ledger)),
+            None => Ok(state),
+        }
+    }
+
+    pub fn contains_key(&self, symbol: &str) -> bool {
+        self.symbols.contains_key(symbol)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &TokenState)> {
+        self.tokens.values().map(|state| (&state.metadata.symbol, state))
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut TokenState> {
+        self.tokens.values_mut()
+    }
+
+    pub fn info(&self, id: u64) -> Option<TokenInfo> {
+        self.tokens.get(&id).map(|state| TokenInfo {
+            id,
+            symbol: state.metadata.symbol.clone(),
+            name: state.metadata.name.clone(),
+            owner: state.metadata.owner,
+            status: state.status.clone(),
+        })
+    }
+
+    pub fn check_symbol(&self, symbol: &str) -> Result<(), ApiError> {
+        if symbol.is_empty() || symbol.len() > MAX_SYMBOL_LEN {
+            return Err(ApiError::InvalidArgument(format!("Token symbol must be 1 to {} characters long", MAX_SYMBOL_LEN)));
+        }
+        if !symbol.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+            return Err(ApiError::InvalidArgument("Token symbol may only contain letters, digits, '-', '_' and '.'".to_string()));
+        }
+        if RESERVED_SYMBOLS.iter().any(|reserved| reserved.eq_ignore_ascii_case(symbol)) {
+            return Err(ApiError::InvalidArgument(format!("Token symbol {} is reserved", symbol)));
+        }
+        if let Some(taken) = self.symbols.keys().find(|taken| taken.eq_ignore_ascii_case(symbol)) {
+            return Err(ApiError::AlreadyExists(format!("Token symbol {} is already taken by {}", symbol, taken)));
+        }
+        Ok(())
+    }
+
+    pub fn register(&mut self, state: TokenState) -> Result<u64, ApiError> {
+        self.check_symbol(&state.metadata.symbol)?;
+        self.next_id += 1;
+        let id = self.next_id;
+        self.symbols.insert(state.metadata.symbol.clone(), id);
+        self.tokens.insert(id, state);
+        Ok(id)
+    }
+}
+
+// Ledgers persisted before allowances could expire stored the bare amount
+fn deserialize_allowances<'de, D>(deserializer: D) -> Result<HashMap<(Account, Account), Allowance>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StoredAllowance {
+        Current(Allowance),
+        Legacy(Nat),
+    }
+    let stored = HashMap::<(Account, Account), StoredAllowance>::deserialize(deserializer)?;
+    Ok(stored
+        .into_iter()
+        .map(|(key, value)| match value {
+            StoredAllowance::Current(allowance) => (key, allowance),
+            StoredAllowance::Legacy(amount) => (key, Allowance { allowance: amount, expires_at: None }),
+        })
+        .collect())
+}
+
+// ICRC-1 deduplication window, in nanoseconds
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct DedupConfig {
+    pub tx_window: u64,
+    pub permitted_drift: u64,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            tx_window: 24 * 60 * 60 * 1_000_000_000,
+            permitted_drift: 2 * 60 * 1_000_000_000,
+        }
+    }
+}
+
+// Transactions that set `created_at_time` and are still inside the dedup window
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RecentTransactions {
+    pub by_hash: HashMap<Hash, (u64, u64)>, // tx hash -> (created_at_time, block index)
+    pub by_time: BTreeSet<(u64, Hash)>,
+}
+
+impl DedupConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tx_window == 0 {
+            return Err("tx_window must be greater than zero".to_string());
+        }
+        match self.tx_window.checked_add(self.permitted_drift) {
+            Some(window) if window <= MAX_DEDUP_WINDOW => Ok(()),
+            _ => Err(format!("tx_window + permitted_drift can be at most {}ns", MAX_DEDUP_WINDOW)),
+        }
+    }
+}
+
+impl RecentTransactions {
+    fn prune(&mut self, oldest_allowed: u64) {
+        let live = self.by_time.split_off(&(oldest_allowed, [0; 32]));
+        for (_, hash) in std::mem::replace(&mut self.by_time, live) {
+            self.by_hash.remove(&hash);
+        }
+    }
+
+    fn insert(&mut self, hash: Hash, created_at_time: u64, block_index: u64) {
+        self.by_hash.insert(hash, (created_at_time, block_index));
+        self.by_time.insert((created_at_time, hash));
+    }
+}
+
+fn transaction_hash<T: Serialize>(operation: &str, caller: &Account, args: &T) -> Hash {
+    let mut bytes = Vec::new();
+    ciborium::into_writer(&(operation, caller, args), &mut bytes).expect("writing to a Vec cannot fail");
+    Sha256::digest(&bytes).into()
+}
+
+// A ledger operation as recorded in an ICRC-3 block
+#[derive(Clone, Debug, Default)]
+pub struct BlockTx {
+    pub btype: &'static str,
+    pub from: Option<Account>,
+    pub to: Option<Account>,
+    pub spender: Option<Account>,
+    pub amount: Nat,
+    pub fee: Option<Nat>,        // fee set by the caller, recorded inside `tx`
+    pub ledger_fee: Option<Nat>, // fee charged by the ledger when the caller didn't set one
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+    pub expected_allowance: Option<Nat>,
+    pub expires_at: Option<u64>,
+}
+
+const ICRC3_BLOCK_SCHEMA_URL: &str = "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3";
+const SUPPORTED_BLOCK_TYPES: [&str; 6] = ["1burn", "1mint", "1xfer", "2approve", "2xfer", "107feecol"];
+const ALLOWANCE_PRUNING_INTERVAL_SECS: u64 = 60 * 60;
+const MAX_BLOCKS_PER_REQUEST: u64 = 1000;
+// Ceiling for tx_window + permitted_drift, in nanoseconds
+const MAX_DEDUP_WINDOW: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+// Tokens mint from (and burn to) a reserved subaccount of the owner, since an ICRC-1
+// minting account can't hold a balance and the owner's default account receives the supply
+pub const MINTING_SUBACCOUNT: [u8; 32] = [0xff; 32];
+
+fn account_value(account: &Account) -> ICRC3Value {
+    let mut parts = vec![ICRC3Value::Blob(account.owner.as_slice().to_vec())];
+    if let Some(subaccount) = account.subaccount {
+        parts.push(ICRC3Value::Blob(subaccount.to_vec()));
+    }
+    ICRC3Value::Array(parts)
+}
+
+fn block_value(
+    btype: &str,
+    tx: Vec<(String, ICRC3Value)>,
+    fee: Option<Nat>,
+    phash: Option<Hash>,
+    ts: u64,
+) -> ICRC3Value {
+    let mut block = vec![
+        ("btype".to_string(), ICRC3Value::Text(btype.to_string())),
+        ("ts".to_string(), ICRC3Value::Nat(Nat::from(ts))),
+        ("tx".to_string(), ICRC3Value::Map(tx)),
+    ];
+    if let Some(phash) = phash {
+        block.push(("phash".to_string(), ICRC3Value::Blob(phash.to_vec())));
+    }
+    if let Some(fee) = fee {
+        block.push(("fee".to_string(), ICRC3Value::Nat(fee)));
+    }
+    ICRC3Value::Map(block)
+}
+
+impl BlockTx {
+    fn into_fields(self) -> Vec<(String, ICRC3Value)> {
+        let mut tx = vec![("amt".to_string(), ICRC3Value::Nat(self.amount))];
+        if let Some(from) = &self.from {
+            tx.push(("from".to_string(), account_value(from)));
+        }
+        if let Some(to) = &self.to {
+            tx.push(("to".to_string(), account_value(to)));
+        }
+        if let Some(spender) = &self.spender {
+            tx.push(("spender".to_string(), account_value(spender)));
+        }
+        if let Some(fee) = self.fee {
+            tx.push(("fee".to_string(), ICRC3Value::Nat(fee)));
+        }
+        if let Some(expected_allowance) = self.expected_allowance {
+            tx.push(("expected_allowance".to_string(), ICRC3Value::Nat(expected_allowance)));
+        }
+        if let Some(expires_at) = self.expires_at {
+            tx.push(("expires_at".to_string(), ICRC3Value::Nat(Nat::from(expires_at))));
+        }
+        if let Some(memo) = self.memo {
+            tx.push(("memo".to_string(), ICRC3Value::Blob(memo)));
+        }
+        if let Some(created_at_time) = self.created_at_time {
+            tx.push(("ts".to_string(), ICRC3Value::Nat(Nat::from(created_at_time))));
+        }
+        tx
+    }
+}
+
+impl TokenState {
+    // Writes are refused on frozen and retired tokens, and while the token is moving to its own ledger canister
+    fn check_writable(&self) -> Result<(), TransferError> {
+        match self.status {
+            TokenStatus::Active => {}
+            TokenStatus::Frozen => {
+                return Err(TransferError::GenericError {
+                    error_code: Nat::from(4u64),
+                    message: format!("Token {} is frozen", self.metadata.symbol),
+                })
+            }
+            TokenStatus::Retired => {
+                return Err(TransferError::GenericError {
+                    error_code: Nat::from(5u64),
+                    message: format!("Token {} is retired", self.metadata.symbol),
+                })
+            }
+        }
+        // Tokens that moved to their own ledger are turned away by `require_local_mut`
+        if self.spawning {
+            return Err(TransferError::TemporarilyUnavailable);
+        }
+        Ok(())
+    }
+
+    // Retired is final, and asking for the current status is an error rather than a silent no-op
+    fn set_status(&mut self, status: TokenStatus) -> Result<(), ApiError> {
+        let symbol = &self.metadata.symbol;
+        if self.status == TokenStatus::Retired {
+            return Err(ApiError::InvalidState(format!("Token {} is retired", symbol)));
+        }
+        if self.status == status {
+            return Err(ApiError::InvalidState(format!("Token {} is already {:?}", symbol, status)));
+        }
+        if self.spawning || self.ledger_canister.is_some() {
+            return Err(ApiError::InvalidState(format!("Token {} is managed by its own ledger canister", symbol)));
+        }
+        self.status = status;
+        Ok(())
+    }
+
+    // Checks `created_at_time` against the dedup window. Returns the entry to remember once
+    // the transaction succeeds, or None when the caller opted out of deduplication.
+    fn deduplicate<T: Serialize>(
+        &mut self,
+        operation: &str,
+        caller: &Account,
+        args: &T,
+        created_at_time: Option<u64>,
+        now: u64,
+    ) -> Result<Option<(Hash, u64)>, TransferError> {
+        let created_at_time = match created_at_time {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        let window = self.dedup.tx_window.saturating_add(self.dedup.permitted_drift);
+        self.recent_transactions.prune(now.saturating_sub(window));
+        if created_at_time.saturating_add(window) < now {
+            return Err(TransferError::TooOld);
+        }
+        if created_at_time > now.saturating_add(self.dedup.permitted_drift) {
+            return Err(TransferError::CreatedInFuture { ledger_time: now });
+        }
+        let hash = transaction_hash(operation, caller, args);
+        if let Some((_, block_index)) = self.recent_transactions.by_hash.get(&hash) {
+            return Err(TransferError::Duplicate { duplicate_of: Nat::from(*block_index) });
+        }
+        Ok(Some((hash, created_at_time)))
+    }
+
+    fn remember_transaction(&mut self, entry: Option<(Hash, u64)>, block_index: u64) {
+        if let Some((hash, created_at_time)) = entry {
+            self.recent_transactions.insert(hash, created_at_time, block_index);
+        }
+    }
+
+    // Rejects a caller-supplied fee that differs from the ledger fee
+    fn check_fee(&self, fee: &Option<Nat>) -> Result<Nat, TransferError> {
+        match fee {
+            Some(fee) if *fee != self.metadata.fee => Err(TransferError::BadFee {
+                expected_fee: self.metadata.fee.clone(),
+            }),
+            _ => Ok(self.metadata.fee.clone()),
+        }
+    }
+
+    // Expired approvals count as no allowance at all
+    fn allowance(&self, owner: &Account, spender: &Account, now: u64) -> Allowance {
+        match self.allowances.get(&(owner.clone(), spender.clone())) {
+            Some(allowance) if allowance.expires_at.is_none_or(|expires_at| expires_at > now) => allowance.clone(),
+            _ => Allowance::default(),
+        }
+    }
+
+    pub(crate) fn prune_expired_allowances(&mut self, now: u64) {
+        self.allowances.retain(|_, allowance| {
+            allowance.allowance > 0u64 && allowance.expires_at.is_none_or(|expires_at| expires_at > now)
+        });
+    }
+
+    // Burns are fee-free, and like on the ICRC-1 reference ledger the smallest accepted
+    // burn is the transfer fee (but never zero). `tx.spender` burns out of an allowance.
+    fn burn(&mut self, tx: BlockTx, now: u64) -> Result<u64, TransferError> {
+        if tx.fee.as_ref().is_some_and(|fee| *fee != 0u64) {
+            return Err(TransferError::BadFee { expected_fee: Nat::from(0u64) });
+        }
+        let min_burn_amount = self.metadata.fee.clone().max(Nat::from(1u64));
+        if tx.amount < min_burn_amount {
+            return Err(TransferError::BadBurn { min_burn_amount });
+        }
+        let from = tx.from.clone().expect("burns always have a source account");
+        let allowance = match &tx.spender {
+            Some(spender) => {
+                let allowance = self.allowance(&from, spender, now);
+                if allowance.allowance < tx.amount {
+                    return Err(TransferError::InsufficientAllowance { allowance: allowance.allowance });
+                }
+                Some((spender.clone(), allowance))
+            }
+            None => None,
+        };
+        let balance = self.balances.get(&from).cloned().unwrap_or_else(|| Nat::from(0u64));
+        if balance < tx.amount {
+            return Err(TransferError::InsufficientFunds { balance });
+        }
+        if let Some((spender, allowance)) = allowance {
+            self.allowances.insert(
+                (from.clone(), spender),
+                Allowance { allowance: allowance.allowance - tx.amount.clone(), expires_at: allowance.expires_at },
+            );
+        }
+        self.balances.insert(from.clone(), balance - tx.amount.clone());
+        self.metadata.total_supply -= tx.amount.clone();
+        let amount = tx.amount.clone();
+        let memo = tx.memo.clone();
+        let tx_id = self.append_block(tx, now);
+        let minting_account = self.minting_account.clone();
+        self.record_transaction(tx_id, from, minting_account, amount, memo, now);
+        Ok(tx_id)
+    }
+
+    // Credits the fee collector, or burns the fee out of the total supply when there is none
+    fn collect_fee(&mut self, fee: &Nat) {
+        if *fee == 0u64 {
+            return;
+        }
+        match self.fee_collector.clone() {
+            Some(collector) => {
+                let balance = self.balances.get(&collector).cloned().unwrap_or_else(|| Nat::from(0u64));
+                self.balances.insert(collector, balance + fee.clone());
+            }
+            None => self.metadata.total_supply -= fee.clone(),
+        }
+    }
+
+    fn balance_of(&self, account: &Account) -> Nat {
+        self.balances.get(account).cloned().unwrap_or_else(|| Nat::from(0u64))
+    }
+
+    // Legacy transaction list kept next to the block log
+    fn record_transaction(&mut self, id: u64, from: Account, to: Account, amount: Nat, memo: Option<Vec<u8>>, now: u64) {
+        self.transaction_counter += 1;
+        self.transactions.push(Transaction { id, from, to, amount, timestamp: now / 1_000_000_000, memo });
+    }
+
+    // ICRC-1 transfer out of `from`; a transfer to the minting account is a burn
+    fn transfer(&mut self, from: Account, args: &TransferArgs, now: u64) -> Result<u64, TransferError> {
+        self.check_writable()?;
+        let dedup_entry = self.deduplicate("icrc2_transfer", &from, args, args.created_at_time, now)?;
+        let tx_id = if args.to == self.minting_account {
+            let burn = BlockTx {
+                btype: "1burn",
+                from: Some(from),
+                amount: args.amount.clone(),
+                fee: args.fee.clone(),
+                memo: args.memo.clone(),
+                created_at_time: args.created_at_time,
+                ..Default::default()
+            };
+            self.burn(burn, now)?
+        } else {
+            let fee = self.check_fee(&args.fee)?;
+            let from_balance = self.balance_of(&from);
+            let total = args.amount.clone() + fee.clone();
+            if from_balance < total {
+                return Err(TransferError::InsufficientFunds { balance: from_balance });
+            }
+            self.balances.insert(from.clone(), from_balance - total);
+            let recipient_balance = self.balance_of(&args.to);
+            self.balances.insert(args.to.clone(), recipient_balance + args.amount.clone());
+            self.collect_fee(&fee);
+            let tx_id = self.append_block(
+                BlockTx {
+                    btype: "1xfer",
+                    from: Some(from.clone()),
+                    to: Some(args.to.clone()),
+                    amount: args.amount.clone(),
+                    fee: args.fee.clone(),
+                    ledger_fee: if args.fee.is_none() { Some(fee) } else { None },
+                    memo: args.memo.clone(),
+                    created_at_time: args.created_at_time,
+                    ..Default::default()
+                },
+                now,
+            );
+            self.record_transaction(tx_id, from, args.to.clone(), args.amount.clone(), args.memo.clone(), now);
+            tx_id
+        };
+        self.remember_transaction(dedup_entry, tx_id);
+        Ok(tx_id)
+    }
+
+    // Burns out of `from` itself, same as transferring to the minting account
+    fn burn_balance(&mut self, from: Account, args: &BurnArgs, now: u64) -> Result<u64, TransferError> {
+        self.check_writable()?;
+        let dedup_entry = self.deduplicate("icrc2_burn", &from, args, args.created_at_time, now)?;
+        let burn = BlockTx {
+            btype: "1burn",
+            from: Some(from),
+            amount: args.amount.clone(),
+            memo: args.memo.clone(),
+            created_at_time: args.created_at_time,
+            ..Default::default()
+        };
+        let tx_id = self.burn(burn, now)?;
+        self.remember_transaction(dedup_entry, tx_id);
+        Ok(tx_id)
+    }
+
+    fn mint(&mut self, to: Account, amount: Nat, now: u64) -> Result<u64, TransferError> {
+        self.check_writable()?;
+        let recipient_balance = self.balance_of(&to);
+        self.balances.insert(to.clone(), recipient_balance + amount.clone());
+        self.metadata.total_supply += amount.clone();
+        let tx_id = self.append_block(BlockTx { btype: "1mint", to: Some(to.clone()), amount: amount.clone(), ..Default::default() }, now);
+        let minting_account = self.minting_account.clone();
+        self.record_transaction(tx_id, minting_account, to, amount, None, now);
+        Ok(tx_id)
+    }
+
+    // The approver pays the fee
+    fn approve(&mut self, owner: Account, args: &ApproveArgs, now: u64) -> Result<u64, TransferError> {
+        self.check_writable()?;
+        let dedup_entry = self.deduplicate("icrc2_approve", &owner, args, args.created_at_time, now)?;
+        let fee = self.check_fee(&args.fee)?;
+        if let Some(expires_at) = args.expires_at {
+            if expires_at <= now {
+                return Err(TransferError::Expired { ledger_time: now });
+            }
+        }
+        let owner_balance = self.balance_of(&owner);
+        if owner_balance < fee {
+            return Err(TransferError::InsufficientFunds { balance: owner_balance });
+        }
+        let current_allowance = self.allowance(&owner, &args.spender, now).allowance;
+        if let Some(expected) = &args.expected_allowance {
+            if current_allowance != *expected {
+                return Err(TransferError::AllowanceChanged { current_allowance });
+            }
+        }
+        self.balances.insert(owner.clone(), owner_balance - fee.clone());
+        self.collect_fee(&fee);
+        self.allowances.insert(
+            (owner.clone(), args.spender.clone()),
+            Allowance { allowance: args.amount.clone(), expires_at: args.expires_at },
+        );
+        let block_index = self.append_block(
+            BlockTx {
+                btype: "2approve",
+                from: Some(owner),
+                spender: Some(args.spender.clone()),
+                amount: args.amount.clone(),
+                fee: args.fee.clone(),
+                ledger_fee: if args.fee.is_none() { Some(fee) } else { None },
+                memo: args.memo.clone(),
+                created_at_time: args.created_at_time,
+                expected_allowance: args.expected_allowance.clone(),
+                expires_at: args.expires_at,
+                ..Default::default()
+            },
+            now,
+        );
+        self.remember_transaction(dedup_entry, block_index);
+        Ok(block_index)
+    }
+
+    // ICRC-2 transfer by `spender` out of an allowance, which has to cover the fee as well
+    fn transfer_from(&mut self, spender: Account, args: &TransferFromArgs, now: u64) -> Result<u64, TransferError> {
+        self.check_writable()?;
+        let dedup_entry = self.deduplicate("icrc2_transfer_from", &spender, args, args.created_at_time, now)?;
+        let tx_id = if args.to == self.minting_account {
+            let burn = BlockTx {
+                btype: "1burn",
+                from: Some(args.from.clone()),
+                spender: Some(spender),
+                amount: args.amount.clone(),
+                fee: args.fee.clone(),
+                memo: args.memo.clone(),
+                created_at_time: args.created_at_time,
+                ..Default::default()
+            };
+            self.burn(burn, now)?
+        } else {
+            let fee = self.check_fee(&args.fee)?;
+            let total = args.amount.clone() + fee.clone();
+            let allowance = self.allowance(&args.from, &spender, now);
+            if allowance.allowance < total {
+                return Err(TransferError::InsufficientAllowance { allowance: allowance.allowance });
+            }
+            let from_balance = self.balance_of(&args.from);
+            if from_balance < total {
+                return Err(TransferError::InsufficientFunds { balance: from_balance });
+            }
+            self.allowances.insert(
+                (args.from.clone(), spender.clone()),
+                Allowance { allowance: allowance.allowance - total.clone(), expires_at: allowance.expires_at },
+            );
+            self.balances.insert(args.from.clone(), from_balance - total);
+            let recipient_balance = self.balance_of(&args.to);
+            self.balances.insert(args.to.clone(), recipient_balance + args.amount.clone());
+            self.collect_fee(&fee);
+            let tx_id = self.append_block(
+                BlockTx {
+                    btype: "2xfer",
+                    from: Some(args.from.clone()),
+                    to: Some(args.to.clone()),
+                    spender: Some(spender),
+                    amount: args.amount.clone(),
+                    fee: args.fee.clone(),
+                    ledger_fee: if args.fee.is_none() { Some(fee) } else { None },
+                    memo: args.memo.clone(),
+                    created_at_time: args.created_at_time,
+                    ..Default::default()
+                },
+                now,
+            );
+            self.record_transaction(tx_id, args.from.clone(), args.to.clone(), args.amount.clone(), args.memo.clone(), now);
+            tx_id
+        };
+        self.remember_transaction(dedup_entry, tx_id);
+        Ok(tx_id)
+    }
+
+    // Appends a block chained to the current tip and returns its index
+    pub fn append_block(&mut self, tx: BlockTx, ts: u64) -> u64 {
+        let btype = tx.btype;
+        let fee = tx.ledger_fee.clone();
+        self.append_raw_block(btype, tx.into_fields(), fee, ts)
+    }
+
+    fn append_raw_block(&mut self, btype: &str, tx: Vec<(String, ICRC3Value)>, fee: Option<Nat>, ts: u64) -> u64 {
+        let phash = self.blocks.last().map(hash_value);
+        self.blocks.push(block_value(btype, tx, fee, phash, ts));
+        (self.blocks.len() - 1) as u64
+    }
+
+    // Certified subtree for this ledger: last_block_hash and last_block_index
+    fn tip_tree(&self) -> Option<HashTree> {
+        let last = self.blocks.last()?;
+        let index = Nat::from(self.blocks.len() as u64 - 1);
+        Some(HashTree::fork(
+            HashTree::labeled("last_block_hash", HashTree::Leaf(hash_value(last).to_vec())),
+            HashTree::labeled("last_block_index", HashTree::Leaf(leb128(&index))),
+        ))
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, CandidType)]
@@ -131,17 +862,117 @@ pub struct AccountBalance {
     pub balance: Nat,
 }
 
-#[derive(CandidType,Deserialize,Serialize)]
-#[serde(tag ="type" , content = "content")]
-pub enum APIResponse{
-    Text(String),
-    PairList(Vec<(String,String)>)
-}
 thread_local! {
-   pub static TOKEN_STATE: RefCell<HashMap<String,TokenState>> = RefCell::new(HashMap::new());
+   pub static TOKEN_STATE: RefCell<TokenRegistry> = RefCell::new(TokenRegistry::default());
+}
+
+pub(crate) fn take_stable_state() -> TokenRegistry {
+    TOKEN_STATE.with(|token_state| std::mem::take(&mut *token_state.borrow_mut()))
+}
+
+// `legacy` is the single ICRC-1 token of snapshots written before every token lived in
+// the registry. Returns the symbol it was registered under.
+// Tokens created before burn support minted from the owner's default account, which
+// would turn every payment to the owner into a burn. Only v1 snapshots have them.
+pub(crate) fn migrate_v1_minting_accounts(tokens: &mut TokenRegistry) {
+    for token in tokens.values_mut() {
+        if token.minting_account.subaccount.is_none() {
+            token.minting_account.subaccount = Some(MINTING_SUBACCOUNT);
+        }
+    }
+}
+
+pub(crate) fn restore_stable_state(mut state: TokenRegistry, legacy: Option<LegacyTokenState>) -> Option<String> {
+    let legacy_symbol = legacy.map(|legacy| {
+        let symbol = migrate_legacy_token(&mut state, legacy, time())
+            .unwrap_or_else(|e| ic_cdk::trap(format!("Failed to migrate the legacy token: {}", e)));
+        debug_print(format!("Migrated the legacy token to {}", symbol));
+        symbol
+    });
+    TOKEN_STATE.with(|token_state| *token_state.borrow_mut() = state);
+    update_certified_tips();
+    legacy_symbol
+}
+
+// Ledger of the removed single-token module, as found in old snapshots
+#[derive(Deserialize)]
+pub struct LegacyTokenState {
+    metadata: Metadata,
+    balances: HashMap<Account, Nat>,
+    transactions: Vec<Transaction>,
+    transaction_counter: u64,
+}
+
+// Registers the legacy token under its own symbol, or LEGACY-<n> when that one is
+// invalid or taken by now
+pub(crate) fn migrate_legacy_token(tokens: &mut TokenRegistry, legacy: LegacyTokenState, now: u64) -> Result<String, ApiError> {
+    let mut metadata = legacy.metadata;
+    if tokens.check_symbol(&metadata.symbol).is_err() {
+        metadata.symbol = (1u64..)
+            .map(|n| format!("LEGACY-{}", n))
+            .find(|symbol| tokens.check_symbol(symbol).is_ok())
+            .expect("some LEGACY-<n> symbol is free");
+    }
+    let symbol = metadata.symbol.clone();
+    let mut state = TokenState {
+        minting_account: Account { owner: metadata.owner, subaccount: Some(MINTING_SUBACCOUNT) },
+        metadata,
+        balances: legacy.balances,
+        transactions: legacy.transactions,
+        transaction_counter: legacy.transaction_counter,
+        ..Default::default()
+    };
+    // It kept no block log, so the log starts with a mint of every balance
+    let mut balances: Vec<(Account, Nat)> = state
+        .balances
+        .iter()
+        .filter(|(_, balance)| **balance > 0u64)
+        .map(|(account, balance)| (account.clone(), balance.clone()))
+        .collect();
+    balances.sort_by_key(|(account, _)| (account.owner, account.subaccount));
+    for (account, balance) in balances {
+        state.append_block(BlockTx { btype: "1mint", to: Some(account), amount: balance, ..Default::default() }, now);
+    }
+    tokens.register(state)?;
+    Ok(symbol)
+}
+
+// Drops expired approvals and dedup entries that fell out of the window on every ledger
+pub(crate) fn start_ledger_pruning() {
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(ALLOWANCE_PRUNING_INTERVAL_SECS), || {
+        let now = time();
+        TOKEN_STATE.with(|token_state| {
+            for state in token_state.borrow_mut().values_mut() {
+                state.prune_expired_allowances(now);
+                let window = state.dedup.tx_window.saturating_add(state.dedup.permitted_drift);
+                state.recent_transactions.prune(now.saturating_sub(window));
+            }
+        });
+    });
+}
+
+// Hash tree over the tips of every ledger, labeled by symbol
+fn tips_tree(tokens: &TokenRegistry, keep: Option<&[u8]>) -> HashTree {
+    let mut entries: Vec<(Vec<u8>, HashTree)> = tokens
+        .iter()
+        .filter_map(|(symbol, state)| state.tip_tree().map(|tree| (symbol.as_bytes().to_vec(), tree)))
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    labeled_forest(&entries, keep)
+}
+
+fn token_not_found(symbol: &str) -> ApiError {
+    ApiError::NotFound(format!("Token {} not found", symbol))
+}
+
+// Must run after every update that appends blocks; failed ones append none
+fn update_certified_tips() {
+    let root = TOKEN_STATE.with(|token_state| tips_tree(&token_state.borrow(), None).digest());
+    ic_cdk::api::certified_data_set(root);
 }
 
 #[update]
+#[allow(clippy::too_many_arguments)]
 pub fn icrc2_init(
     name: String,
     symbol: String,
@@ -151,136 +982,114 @@ pub fn icrc2_init(
     initial_supply: Nat,
     owner : Principal,
     fee: Nat,
-) -> APIResponse {
-    //let caller = msg_caller();
-    debug_print(format!("Initializing ICRC-2 token: {}", name));
-    let minting_account = Account {
-        owner: owner,
-        subaccount: None,
-    };
-    let default_account = Account {
-        owner: owner,
-        subaccount: None,
-    };
-    let symbol_clone = symbol.clone();
-
+) -> Result<u64, ApiError> {
     let metadata = Metadata {
         name,
         symbol,
         decimals,
         description,
         logo,
-        total_supply: initial_supply.clone(),
-        owner: owner,
+        total_supply: initial_supply,
+        owner,
         fee,
     };
+    init_token(msg_caller(), metadata)
+}
+
+// icrc2_init on behalf of `caller`; `metadata.total_supply` is the initial supply
+pub(crate) fn init_token(caller: Principal, metadata: Metadata) -> Result<u64, ApiError> {
+    let owner = metadata.owner;
+    // `owner` becomes the minting account, so only it or its delegate may create the token
+    if !is_authorized(owner, caller, DelegationScope::TokenInit, Some(&metadata.symbol)) {
+        return Err(ApiError::Unauthorized(format!("Not authorized to initialize tokens for {}", owner)));
+    }
+    TOKEN_STATE.with(|token_state| token_state.borrow().check_symbol(&metadata.symbol))?;
+    debug_print(format!("Initializing ICRC-2 token: {}", metadata.name));
+    let minting_account = Account {
+        owner,
+        subaccount: Some(MINTING_SUBACCOUNT),
+    };
+    let default_account = Account {
+        owner,
+        subaccount: None,
+    };
+    let initial_supply = metadata.total_supply.clone();
     let mut balances = HashMap::new();
     balances.insert(default_account.clone(), initial_supply.clone());
-    let state = TokenState {
+    let mut state = TokenState {
         metadata,
         balances,
         allowances: HashMap::new(),
         transactions: Vec::new(),
         transaction_counter: 0,
         minting_account,
+        blocks: Vec::new(),
+        dedup: DedupConfig::default(),
+        recent_transactions: RecentTransactions::default(),
+        fee_collector: None,
+        ledger_canister: None,
+        spawning: false,
+        pending_ledger: None,
+        status: TokenStatus::Active,
     };
+    if initial_supply > 0u64 {
+        state.append_block(
+            BlockTx { btype: "1mint", to: Some(default_account), amount: initial_supply, ..Default::default() },
+            time(),
+        );
+    }
     let state_clone = state.clone();
-    TOKEN_STATE.with(|token_state| {
-        token_state.borrow_mut().insert(symbol_clone, state);
-    });
-    debug_print(format!("ICRC-2 token initialized successfully with data: {:?}", state_clone));
-    let output = format!(
-        "ICRC-2 token initialized with name: {}, symbol: {}, decimals: {}, total_supply: {}, fee: {}",
-        state_clone.metadata.name,
-        state_clone.metadata.symbol,
-        state_clone.metadata.decimals,
-        state_clone.metadata.total_supply,
-        state_clone.metadata.fee
-    );
-    APIResponse::Text(output)
+    let token_id = TOKEN_STATE.with(|token_state| token_state.borrow_mut().register(state))?;
+    update_certified_tips();
+    debug_print(format!("ICRC-2 token {} initialized with data: {:?}", token_id, state_clone));
+    Ok(token_id)
 }
 
 // ICRC-1 compatible queries
 #[query]
-pub fn icrc2_name(symbol : String) -> String {
-    TOKEN_STATE.with(|token_state| {
-        token_state
-            .borrow()
-            .get(&symbol)
-            .map(|state| state.metadata.name.clone())
-            .unwrap_or_default()
-    })
+pub fn icrc2_name(symbol : String) -> Result<String, ApiError> {
+    TOKEN_STATE.with(|token_state| token_state.borrow().require(&symbol).map(|state| state.metadata.name.clone()))
 }
 
 #[query]
-pub fn icrc2_symbol(name : String) -> String {
-    TOKEN_STATE.with(|token_state| {
-        token_state
-            .borrow()
-            .get(&name)
-            .map(|state| state.metadata.symbol.clone())
-            .unwrap_or_default()
-    })
+pub fn icrc2_symbol(name : String) -> Result<String, ApiError> {
+    TOKEN_STATE.with(|token_state| token_state.borrow().require(&name).map(|state| state.metadata.symbol.clone()))
 }
 
 #[query]
-pub fn icrc2_decimals(symbol : String) -> u8 {
-    TOKEN_STATE.with(|token_state| {
-        token_state
-            .borrow()
-            .get(&symbol)
-            .map(|state| state.metadata.decimals)
-            .unwrap_or(8)
-    })
+pub fn icrc2_decimals(symbol : String) -> Result<u8, ApiError> {
+    TOKEN_STATE.with(|token_state| token_state.borrow().require(&symbol).map(|state| state.metadata.decimals))
 }
 
 #[query]
-pub fn icrc2_fee(symbol : String) -> Nat {
-    TOKEN_STATE.with(|token_state| {
-        token_state
-            .borrow()
-            .get(&symbol)
-            .map(|state| state.metadata.fee.clone())
-            .unwrap_or_else(|| Nat::from(0u64))
-    })
+pub fn icrc2_fee(symbol : String) -> Result<Nat, ApiError> {
+    TOKEN_STATE.with(|token_state| token_state.borrow().require(&symbol).map(|state| state.metadata.fee.clone()))
 }
 
 #[query]
-pub fn icrc2_total_supply(symbol : String) -> Nat {
-    TOKEN_STATE.with(|token_state| {
-        token_state
-            .borrow()
-            .get(&symbol)
-            .map(|state| state.metadata.total_supply.clone())
-            .unwrap_or_else(|| Nat::from(0u64))
-    })
+pub fn icrc2_total_supply(symbol : String) -> Result<Nat, ApiError> {
+    TOKEN_STATE.with(|token_state| token_state.borrow().require_local(&symbol).map(|state| state.metadata.total_supply.clone()))
 }
 
 #[query]
-pub fn icrc2_minting_account(symbol : String) -> Option<Account> {
-    TOKEN_STATE.with(|token_state| {
-        token_state
-            .borrow()
-            .get(&symbol)
-            .map(|state| state.minting_account.clone())
-    })
+pub fn icrc2_minting_account(symbol : String) -> Result<Account, ApiError> {
+    TOKEN_STATE.with(|token_state| token_state.borrow().require(&symbol).map(|state| state.minting_account.clone()))
 }
 
 #[query]
-pub fn icrc2_balance_of(account: Account,symbol : String) -> Nat {
+pub fn icrc2_balance_of(account: Account,symbol : String) -> Result<Nat, ApiError> {
     TOKEN_STATE.with(|token_state| {
         token_state
             .borrow()
-            .get(&symbol)
+            .require_local(&symbol)
             .map(|state| state.balances.get(&account).cloned().unwrap_or_else(|| Nat::from(0u64)))
-            .unwrap_or_else(|| Nat::from(0u64))
     })
 }
 
 #[query]
-pub fn icrc2_metadata(symbol : String) -> APIResponse {
+pub fn icrc2_metadata(symbol : String) -> Result<Vec<(String, String)>, ApiError> {
     TOKEN_STATE.with(|token_state| {
-        token_state.borrow().get(&symbol).map(|state| {
+        token_state.borrow().require(&symbol).map(|state| {
             let mut meta = vec![
                 ("name".to_string(), state.metadata.name.clone()),
                 ("symbol".to_string(), state.metadata.symbol.clone()),
@@ -295,216 +1104,298 @@ pub fn icrc2_metadata(symbol : String) -> APIResponse {
             if let Some(logo) = &state.metadata.logo {
                 meta.push(("logo".to_string(), logo.clone()));
             }
-            APIResponse::PairList(meta)
-        }).unwrap_or_else(|| APIResponse::Text("Token not found".to_string()))
+            meta
+        })
     })
 }
 
 #[update]
-pub fn icrc2_transfer(symbol : String,args: TransferArgs) -> TransferResult {
-    let caller_principal = msg_caller();
+pub fn icrc2_transfer(symbol : String,args: TransferArgs) -> Result<Nat, ApiError> {
+    transfer_as(msg_caller(), symbol, args)
+}
 
-    TOKEN_STATE.with(|token_state| {
+// icrc2_transfer on behalf of `caller_principal`, e.g. an agent task running for its owner
+pub(crate) fn transfer_as(caller_principal: Principal, symbol: String, args: TransferArgs) -> Result<Nat, ApiError> {
+    let from_account = Account { owner: caller_principal, subaccount: args.from_subaccount };
+    let result = TOKEN_STATE.with(|token_state| {
+        token_state.borrow_mut().require_local_mut(&symbol)?.transfer(from_account.clone(), &args, time()).map_err(ApiError::from)
+    });
+    let tx_id = result?;
+    update_certified_tips();
+    debug_print(format!("Transferred {} {} from {} to {}", args.amount, symbol, from_account.owner, args.to.owner));
+    Ok(Nat::from(tx_id))
+}
+
+#[update]
+pub fn icrc2_mint(to: Account, amount: Nat,symbol : String) -> Result<Nat, ApiError> {
+    mint_as(msg_caller(), to, amount, symbol)
+}
+
+pub(crate) fn mint_as(caller_principal: Principal, to: Account, amount: Nat, symbol: String) -> Result<Nat, ApiError> {
+    let result = TOKEN_STATE.with(|token_state| {
         let mut tokens = token_state.borrow_mut();
-        if let Some(state) = tokens.get_mut(&symbol) {
-            let from_account = Account {
-                owner: caller_principal,
-                subaccount: args.from_subaccount,
-            };
-            let from_balance = state.balances.get(&from_account).cloned().unwrap_or_else(|| Nat::from(0u64));
-            let fee = args.fee.clone().unwrap_or_else(|| state.metadata.fee.clone());
-            let total = args.amount.clone() + fee.clone();
-            if from_balance < total {
-                return TransferResult::Err(TransferError::InsufficientFunds { balance: from_balance });
-            }
-            // Deduct from sender
-            let new_balance = from_balance - total.clone();
-            state.balances.insert(from_account.clone(), new_balance);
-            // Credit recipient
-            let recipient_balance = state.balances.get(&args.to).cloned().unwrap_or_else(|| Nat::from(0u64));
-            let new_recipient_balance = recipient_balance + args.amount.clone();
-            state.balances.insert(args.to.clone(), new_recipient_balance);
-            // Record transaction
-            let tx_id = state.transaction_counter;
-            state.transaction_counter += 1;
-            let now = time() / 1_000_000_000;
-            let transaction = Transaction {
-                id: tx_id,
-                from: from_account.clone(),
-                to: args.to.clone(),
-                amount: args.amount.clone(),
-                timestamp: now,
-                memo: args.memo.clone(),
-            };
-            state.transactions.push(transaction);
-            debug_print(format!("Transferred {} tokens from {} to {}", args.amount, from_account.owner.to_string(), args.to.owner.to_string()));
-            TransferResult::Ok(Nat::from(tx_id))
-        } else {
-            ic_cdk::trap("Token not initialized");
+        let state = tokens.require_local_mut(&symbol)?;
+        if !is_authorized(state.minting_account.owner, caller_principal, DelegationScope::Mint, Some(&symbol)) {
+            return Err(ApiError::Unauthorized("Not authorized to mint tokens".to_string()));
         }
+        state.mint(to.clone(), amount.clone(), time()).map_err(ApiError::from)
+    });
+    let tx_id = result?;
+    update_certified_tips();
+    debug_print(format!("Minted {} {} to {}", amount, symbol, to.owner));
+    Ok(Nat::from(tx_id))
+}
+
+// ICRC2-specific methods
+#[update]
+pub fn icrc2_approve(args: ApproveArgs,symbol : String) -> Result<Nat, ApiError> {
+    approve_as(msg_caller(), symbol, args)
+}
+
+fn approve_as(caller_principal: Principal, symbol: String, args: ApproveArgs) -> Result<Nat, ApiError> {
+    let owner_account = Account { owner: caller_principal, subaccount: args.from_subaccount };
+    let result = TOKEN_STATE.with(|token_state| {
+        token_state.borrow_mut().require_local_mut(&symbol)?.approve(owner_account.clone(), &args, time()).map_err(ApiError::from)
+    });
+    let block_index = result?;
+    update_certified_tips();
+    debug_print(format!("Approved {} {} for {} by {}", args.amount, symbol, args.spender.owner, owner_account.owner));
+    Ok(Nat::from(block_index))
+}
+
+#[query]
+pub fn icrc2_allowance(args: AllowanceArgs,symbol : String) -> Result<Allowance, ApiError> {
+    TOKEN_STATE.with(|token_state| {
+        token_state
+            .borrow()
+            .require_local(&symbol)
+            .map(|state| state.allowance(&args.account, &args.spender, time()))
     })
 }
 
 #[update]
-pub fn icrc2_mint(to: Account, amount: Nat,symbol : String , owner : Principal) -> APIResponse {
-    //let caller_principal = msg_caller();
+pub fn icrc2_transfer_from(args: TransferFromArgs,symbol: String) -> Result<Nat, ApiError> {
+    transfer_from_as(msg_caller(), symbol, args)
+}
+
+fn transfer_from_as(caller_principal: Principal, symbol: String, args: TransferFromArgs) -> Result<Nat, ApiError> {
+    let spender_account = Account { owner: caller_principal, subaccount: args.spender_subaccount };
+    let result = TOKEN_STATE.with(|token_state| {
+        token_state
+            .borrow_mut()
+            .require_local_mut(&symbol)?
+            .transfer_from(spender_account.clone(), &args, time())
+            .map_err(ApiError::from)
+    });
+    let tx_id = result?;
+    update_certified_tips();
+    debug_print(format!("TransferFrom: {} {} from {} to {} by {}", args.amount, symbol, args.from.owner, args.to.owner, spender_account.owner));
+    Ok(Nat::from(tx_id))
+}
+
+// Burns the caller's own tokens, same as transferring them to the minting account
+#[update]
+pub fn icrc2_burn(symbol: String, args: BurnArgs) -> Result<Nat, ApiError> {
+    burn_as(msg_caller(), symbol, args)
+}
+
+pub(crate) fn burn_as(caller_principal: Principal, symbol: String, args: BurnArgs) -> Result<Nat, ApiError> {
+    let from_account = Account { owner: caller_principal, subaccount: args.from_subaccount };
+    let result = TOKEN_STATE.with(|token_state| {
+        token_state.borrow_mut().require_local_mut(&symbol)?.burn_balance(from_account.clone(), &args, time()).map_err(ApiError::from)
+    });
+    let tx_id = result?;
+    update_certified_tips();
+    debug_print(format!("Burned {} {} from {}", args.amount, symbol, from_account.owner));
+    Ok(Nat::from(tx_id))
+}
+
+#[query]
+pub fn icrc2_get_transactions(limit: u64,symbol : String) -> Result<Vec<Transaction>, ApiError> {
     TOKEN_STATE.with(|token_state| {
-        let mut tokens = token_state.borrow_mut();
-        if let Some(state) = tokens.get_mut(&symbol) {
-            if state.minting_account.owner != owner {
-                return APIResponse::Text("Not authorized to mint tokens".to_string());
-            }
-            let recipient_balance = state.balances.get(&to).cloned().unwrap_or_else(|| Nat::from(0u64));
-            let new_recipient_balance = recipient_balance + amount.clone();
-            state.balances.insert(to.clone(), new_recipient_balance);
-            state.metadata.total_supply += amount.clone();
-            let tx_id = state.transaction_counter;
-            state.transaction_counter += 1;
-            let now = time() / 1_000_000_000;
-            let transaction = Transaction {
-                id: tx_id,
-                from: state.minting_account.clone(),
-                to: to.clone(),
-                amount: amount.clone(),
-                timestamp: now,
-                memo: None,
-            };
-            state.transactions.push(transaction);
-            debug_print(format!("Minted {} tokens to {}", amount, to.owner.to_string()));
-            APIResponse::Text(format!("Minted {} tokens to {}", amount, to.owner.to_string()))
+        let tokens = token_state.borrow();
+        let state = tokens.require_local(&symbol)?;
+        if limit == 0 || limit > state.transactions.len() as u64 {
+            Ok(state.transactions.clone())
         } else {
-            ic_cdk::trap("Token not initialized");
+            Ok(state.transactions.iter()
+                .rev()
+                .take(limit as usize)
+                .cloned()
+                .collect())
         }
     })
 }
 
-// ICRC2-specific methods
+// ICRC-107: only the token owner can route fees to a collector account (or back to burning)
 #[update]
-pub fn icrc2_approve(args: ApproveArgs,symbol : String) -> TransferResult {
-    let caller_principal = msg_caller();
-    TOKEN_STATE.with(|token_state| {
+pub fn icrc107_set_fee_collector(symbol: String, args: SetFeeCollectorArgs) -> Result<Nat, ApiError> {
+    set_fee_collector_as(msg_caller(), symbol, args)
+}
+
+fn set_fee_collector_as(caller: Principal, symbol: String, args: SetFeeCollectorArgs) -> Result<Nat, ApiError> {
+    let result = TOKEN_STATE.with(|token_state| {
         let mut tokens = token_state.borrow_mut();
-        if let Some(state) = tokens.get_mut(&symbol) {
-            let owner_account = Account {
-                owner: caller_principal,
-                subaccount: args.from_subaccount,
-            };
-            let current_allowance = state.allowances.get(&(owner_account.clone(), args.spender.clone())).cloned().unwrap_or_else(|| Nat::from(0u64));
-            if let Some(expected) = args.expected_allowance {
-                if current_allowance != expected {
-                    return TransferResult::Err(TransferError::GenericError {
-                        error_code: Nat::from(2u64),
-                        message: "Allowance mismatch".to_string(),
-                    });
-                }
-            }
-            state.allowances.insert((owner_account.clone(), args.spender.clone()), args.amount.clone());
-            debug_print(format!("Approved {} tokens for {} by {}", args.amount, args.spender.owner.to_string(), owner_account.owner.to_string()));
-            TransferResult::Ok(Nat::from(0u64))
-        } else {
-            ic_cdk::trap("Token not initialized");
+        let state = tokens.require_local_mut(&symbol)?;
+        if state.metadata.owner != caller {
+            return Err(ApiError::Unauthorized("Only the token owner can set the fee collector".to_string()));
         }
-    })
+        if let Err(err) = state.check_writable() {
+            return Err(err.into());
+        }
+        let caller_account = Account { owner: caller, subaccount: None };
+        let dedup_entry = match state.deduplicate("icrc107_set_fee_collector", &caller_account, &args, args.created_at_time, time()) {
+            Ok(entry) => entry,
+            Err(err) => return Err(err.into()),
+        };
+        let mut tx = vec![
+            ("mthd".to_string(), ICRC3Value::Text("107set_fee_collector".to_string())),
+            ("caller".to_string(), ICRC3Value::Blob(caller.as_slice().to_vec())),
+        ];
+        if let Some(collector) = &args.fee_collector {
+            tx.push(("fee_collector".to_string(), account_value(collector)));
+        }
+        if let Some(created_at_time) = args.created_at_time {
+            tx.push(("ts".to_string(), ICRC3Value::Nat(Nat::from(created_at_time))));
+        }
+        state.fee_collector = args.fee_collector.clone();
+        let block_index = state.append_raw_block("107feecol", tx, None, time());
+        state.remember_transaction(dedup_entry, block_index);
+        debug_print(format!("Fee collector for {} set to {:?}", symbol, state.fee_collector));
+        Ok(Nat::from(block_index))
+    });
+    let block_index = result?;
+    update_certified_tips();
+    Ok(block_index)
 }
 
 #[query]
-pub fn icrc2_allowance(args: AllowanceArgs,symbol : String) -> Nat {
+pub fn icrc107_get_fee_collector(symbol: String) -> Result<Option<Account>, ApiError> {
     TOKEN_STATE.with(|token_state| {
-        token_state
-            .borrow()
-            .get(&symbol)
-            .map(|state| state.allowances.get(&(args.account.clone(), args.spender.clone())).cloned().unwrap_or_else(|| Nat::from(0u64)))
-            .unwrap_or_else(|| Nat::from(0u64))
+        token_state.borrow().require(&symbol).map(|state| state.fee_collector.clone())
     })
 }
 
+// Only the token owner can tune the dedup window of its ledger
 #[update]
-pub fn icrc2_transfer_from(args: TransferFromArgs,symbol: String) -> TransferResult {
-    let caller_principal = msg_caller();
+pub fn icrc2_set_dedup_config(symbol: String, config: DedupConfig) -> Result<(), ApiError> {
+    set_dedup_config_as(msg_caller(), symbol, config)
+}
+
+fn set_dedup_config_as(caller: Principal, symbol: String, config: DedupConfig) -> Result<(), ApiError> {
+    config.validate().map_err(ApiError::InvalidArgument)?;
     TOKEN_STATE.with(|token_state| {
         let mut tokens = token_state.borrow_mut();
-        if let Some(state) = tokens.get_mut(&symbol) {
-            let spender_account = Account {
-                owner: caller_principal,
-                subaccount: args.spender_subaccount,
-            };
-            let allowance = state.allowances.get(&(args.from.clone(), spender_account.clone())).cloned().unwrap_or_else(|| Nat::from(0u64));
-            if allowance < args.amount {
-                return TransferResult::Err(TransferError::InsufficientAllowance { allowance });
-            }
-            let from_balance = state.balances.get(&args.from).cloned().unwrap_or_else(|| Nat::from(0u64));
-            let fee = args.fee.clone().unwrap_or_else(|| state.metadata.fee.clone());
-            let total = args.amount.clone() + fee.clone();
-            if from_balance < total {
-                return TransferResult::Err(TransferError::InsufficientFunds { balance: from_balance });
-            }
-            // Deduct from allowance
-            state.allowances.insert((args.from.clone(), spender_account.clone()), allowance - args.amount.clone());
-            // Deduct from sender
-            state.balances.insert(args.from.clone(), from_balance - total.clone());
-            // Credit recipient
-            let recipient_balance = state.balances.get(&args.to).cloned().unwrap_or_else(|| Nat::from(0u64));
-            state.balances.insert(args.to.clone(), recipient_balance + args.amount.clone());
-            // Record transaction
-            let tx_id = state.transaction_counter;
-            state.transaction_counter += 1;
-            let now = time() / 1_000_000_000;
-            let transaction = Transaction {
-                id: tx_id,
-                from: args.from.clone(),
-                to: args.to.clone(),
-                amount: args.amount.clone(),
-                timestamp: now,
-                memo: args.memo.clone(),
-            };
-            state.transactions.push(transaction);
-            debug_print(format!("TransferFrom: {} tokens from {} to {} by {}", args.amount, args.from.owner.to_string(), args.to.owner.to_string(), spender_account.owner.to_string()));
-            TransferResult::Ok(Nat::from(tx_id))
-        } else {
-            ic_cdk::trap("Token not initialized");
+        let state = tokens.require_mut(&symbol)?;
+        if state.metadata.owner != caller {
+            return Err(ApiError::Unauthorized("Not authorized to configure this token".to_string()));
         }
+        state.dedup = config;
+        debug_print(format!(
+            "Dedup window for {} set to tx_window: {}ns, permitted_drift: {}ns",
+            symbol, state.dedup.tx_window, state.dedup.permitted_drift
+        ));
+        Ok(())
     })
 }
 
 #[query]
-pub fn icrc2_get_transactions(limit: u64,symbol : String) -> Vec<Transaction> {
+pub fn icrc2_dedup_config(symbol: String) -> Result<DedupConfig, ApiError> {
+    TOKEN_STATE.with(|token_state| token_state.borrow().require(&symbol).map(|state| state.dedup.clone()))
+}
+
+// Owner-only lifecycle changes. Balances, transactions and blocks stay queryable in every state.
+fn set_token_status(caller: Principal, symbol: &str, status: TokenStatus) -> Result<(), ApiError> {
     TOKEN_STATE.with(|token_state| {
-        let tokens = token_state.borrow();
-        if let Some(state) =tokens.get(&symbol) {
-            if limit == 0 || limit > state.transactions.len() as u64 {
-                state.transactions.clone()
-            } else {
-                state.transactions.iter()
-                    .rev()
-                    .take(limit as usize)
-                    .cloned()
-                    .collect()
-            }
-        } else {
-            Vec::new()
+        let mut tokens = token_state.borrow_mut();
+        let state = tokens.require_mut(symbol)?;
+        if state.metadata.owner != caller {
+            return Err(ApiError::Unauthorized("Only the token owner can change its status".to_string()));
         }
+        state.set_status(status)?;
+        debug_print(format!("Token {} is now {:?}", symbol, state.status));
+        Ok(())
+    })
+}
+
+#[update]
+pub fn icrc2_freeze_token(symbol: String) -> Result<(), ApiError> {
+    set_token_status(msg_caller(), &symbol, TokenStatus::Frozen)
+}
+
+#[update]
+pub fn icrc2_unfreeze_token(symbol: String) -> Result<(), ApiError> {
+    set_token_status(msg_caller(), &symbol, TokenStatus::Active)
+}
+
+// Permanent: the token can never be written to again and its symbol stays taken
+#[update]
+pub fn icrc2_retire_token(symbol: String) -> Result<(), ApiError> {
+    set_token_status(msg_caller(), &symbol, TokenStatus::Retired)
+}
+
+#[query]
+pub fn icrc2_token_id(symbol: String) -> Result<u64, ApiError> {
+    TOKEN_STATE.with(|token_state| token_state.borrow().id_of(&symbol)).ok_or_else(|| token_not_found(&symbol))
+}
+
+#[query]
+pub fn icrc2_token_by_id(id: u64) -> Result<TokenInfo, ApiError> {
+    TOKEN_STATE
+        .with(|token_state| token_state.borrow().info(id))
+        .ok_or_else(|| ApiError::NotFound(format!("No token with id {}", id)))
+}
+
+// ICRC-3 block log, one per symbol
+#[query]
+pub fn icrc3_get_blocks(symbol: String, args: Vec<GetBlocksArgs>) -> Result<GetBlocksResult, ApiError> {
+    TOKEN_STATE.with(|token_state| {
+        let tokens = token_state.borrow();
+        Ok(get_blocks(&tokens.require(&symbol)?.blocks, args, MAX_BLOCKS_PER_REQUEST))
     })
 }
 
+// None until the token has a block, or when called as an update (no certificate then)
 #[query]
-pub fn icrc2_get_all_accounts(symbol: String) -> Vec<AccountBalance> {
+pub fn icrc3_get_tip_certificate(symbol: String) -> Result<Option<ICRC3DataCertificate>, ApiError> {
     TOKEN_STATE.with(|token_state| {
         let tokens = token_state.borrow();
-        if let Some(state) = tokens.get(&symbol) {
-            state.balances.iter()
-                .map(|(account, balance)| AccountBalance {
-                    account: account.clone(),
-                    balance: balance.clone(),
-                })
-                .collect()
-        } else {
-            Vec::new()
+        if tokens.require(&symbol)?.blocks.is_empty() {
+            return Ok(None);
         }
+        Ok(ic_cdk::api::data_certificate().map(|certificate| ICRC3DataCertificate {
+            certificate,
+            hash_tree: tips_tree(&tokens, Some(symbol.as_bytes())).serialize(),
+        }))
     })
 }
 
 #[query]
-pub fn icrc2_get_all_records()->APIResponse{
+pub fn icrc3_supported_block_types(symbol: String) -> Result<Vec<SupportedBlockType>, ApiError> {
+    if !TOKEN_STATE.with(|token_state| token_state.borrow().contains_key(&symbol)) {
+        return Err(token_not_found(&symbol));
+    }
+    Ok(SUPPORTED_BLOCK_TYPES
+        .iter()
+        .map(|btype| SupportedBlockType { block_type: btype.to_string(), url: ICRC3_BLOCK_SCHEMA_URL.to_string() })
+        .collect())
+}
+
+#[query]
+pub fn icrc2_get_all_accounts(symbol: String) -> Result<Vec<AccountBalance>, ApiError> {
+    TOKEN_STATE.with(|token_state| {
+        let tokens = token_state.borrow();
+        Ok(tokens.require_local(&symbol)?.balances.iter()
+            .map(|(account, balance)| AccountBalance {
+                account: account.clone(),
+                balance: balance.clone(),
+            })
+            .collect())
+    })
+}
+
+#[query]
+pub fn icrc2_get_all_records()->Vec<(String, String)>{
    let records = TOKEN_STATE.with(|t|{
         t.borrow()
         .iter()
@@ -513,12 +1404,12 @@ pub fn icrc2_get_all_records()->APIResponse{
         )
         .collect()
     });
-    APIResponse::PairList(records)
+    records
 }
 
 
 #[query]
-pub fn my_tokens (owner : Principal) -> APIResponse{
+pub fn my_tokens (owner : Principal) -> Vec<(String, String)>{
     //let caller = msg_caller();
     let tokens = TOKEN_STATE.with(|t|{
         t.borrow()
@@ -534,5 +1425,459 @@ pub fn my_tokens (owner : Principal) -> APIResponse{
         )
         .collect()
     });
-    APIResponse::PairList(tokens)
-}
\ No newline at end of file
+    tokens
+}
+// Unit tests run outside a canister, so the endpoints read a fixed clock there
+#[cfg(test)]
+fn time() -> u64 {
+    tests::NOW
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(super) const NOW: u64 = 1_700_000_000_000_000_000;
+
+    fn account(id: u8) -> Account {
+        Account { owner: Principal::from_slice(&[id]), subaccount: None }
+    }
+
+    // Ledger as init_token leaves it: the owner's default account holds the supply
+    fn ledger(fee: u64, fee_collector: Option<Account>) -> TokenState {
+        let owner = account(1);
+        let mut state = TokenState {
+            metadata: Metadata {
+                symbol: "TST".to_string(),
+                owner: owner.owner,
+                total_supply: Nat::from(1_000u64),
+                fee: Nat::from(fee),
+                ..Default::default()
+            },
+            minting_account: Account { owner: owner.owner, subaccount: Some(MINTING_SUBACCOUNT) },
+            fee_collector,
+            ..Default::default()
+        };
+        state.balances.insert(owner, Nat::from(1_000u64));
+        state
+    }
+
+    fn assert_supply_matches_balances(state: &TokenState) {
+        let sum = state.balances.values().fold(Nat::from(0u64), |sum, balance| sum + balance.clone());
+        assert_eq!(sum, state.metadata.total_supply);
+    }
+
+    fn transfer(to: Account, amount: u64) -> TransferArgs {
+        TransferArgs { from_subaccount: None, to, amount: Nat::from(amount), fee: None, memo: None, created_at_time: None }
+    }
+
+    fn approve(spender: Account, amount: u64) -> ApproveArgs {
+        ApproveArgs {
+            from_subaccount: None,
+            spender,
+            amount: Nat::from(amount),
+            expected_allowance: None,
+            expires_at: None,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    fn transfer_from(from: Account, to: Account, amount: u64) -> TransferFromArgs {
+        TransferFromArgs {
+            spender_subaccount: None,
+            from,
+            to,
+            amount: Nat::from(amount),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    fn burn(amount: u64) -> BurnArgs {
+        BurnArgs { from_subaccount: None, amount: Nat::from(amount), memo: None, created_at_time: None }
+    }
+
+    // Every kind of balance change, each followed by the invariant check
+    fn exercise(state: &mut TokenState) {
+        let (owner, alice, spender, bob) = (account(1), account(2), account(3), account(4));
+        let minting = state.minting_account.clone();
+
+        state.transfer(owner.clone(), &transfer(alice.clone(), 100), NOW).unwrap();
+        assert_supply_matches_balances(state);
+        state.approve(owner.clone(), &approve(spender.clone(), 300), NOW).unwrap();
+        assert_supply_matches_balances(state);
+        state.transfer_from(spender.clone(), &transfer_from(owner.clone(), bob.clone(), 50), NOW).unwrap();
+        assert_supply_matches_balances(state);
+        state.burn_balance(alice.clone(), &burn(20), NOW).unwrap();
+        assert_supply_matches_balances(state);
+        state.transfer(bob.clone(), &transfer(minting.clone(), 30), NOW).unwrap();
+        assert_supply_matches_balances(state);
+        state.transfer_from(spender.clone(), &transfer_from(owner.clone(), minting, 40), NOW).unwrap();
+        assert_supply_matches_balances(state);
+        state.mint(alice.clone(), Nat::from(500u64), NOW).unwrap();
+        assert_supply_matches_balances(state);
+
+        // Failed operations leave every balance alone
+        let before = state.balances.clone();
+        assert!(matches!(
+            state.transfer(bob.clone(), &transfer(alice.clone(), 1_000_000), NOW),
+            Err(TransferError::InsufficientFunds { .. })
+        ));
+        assert!(matches!(
+            state.transfer_from(spender.clone(), &transfer_from(owner.clone(), bob, 1_000), NOW),
+            Err(TransferError::InsufficientAllowance { .. })
+        ));
+        assert!(matches!(state.burn_balance(spender, &burn(0), NOW), Err(TransferError::BadBurn { .. })));
+        assert_eq!(state.balances, before);
+        assert_supply_matches_balances(state);
+    }
+
+    #[test]
+    fn fees_are_burned_without_a_fee_collector() {
+        let mut state = ledger(10, None);
+        exercise(&mut state);
+        // transfer, approve and transfer_from paid a fee each; burns are fee-free
+        assert_eq!(state.metadata.total_supply, Nat::from(1_000u64 - 30 - 20 - 30 - 40 + 500));
+    }
+
+    #[test]
+    fn fees_go_to_the_fee_collector() {
+        let collector = account(9);
+        let mut state = ledger(10, Some(collector.clone()));
+        exercise(&mut state);
+        assert_eq!(state.balance_of(&collector), Nat::from(30u64));
+        assert_eq!(state.metadata.total_supply, Nat::from(1_000u64 - 20 - 30 - 40 + 500));
+    }
+
+    #[test]
+    fn zero_fee_ledgers_keep_the_invariant() {
+        let mut state = ledger(0, None);
+        exercise(&mut state);
+        assert_eq!(state.metadata.total_supply, Nat::from(1_000u64 - 20 - 30 - 40 + 500));
+    }
+
+    #[test]
+    fn approve_fee_alone_keeps_the_invariant() {
+        for fee_collector in [None, Some(account(9))] {
+            let mut state = ledger(10, fee_collector);
+            for amount in [1, 1_000, 0] {
+                state.approve(account(1), &approve(account(3), amount), NOW).unwrap();
+                assert_supply_matches_balances(&state);
+            }
+            assert_eq!(state.balance_of(&account(1)), Nat::from(970u64));
+        }
+    }
+
+    fn dedup_transfer(to: Account, amount: u64, created_at_time: u64) -> TransferArgs {
+        TransferArgs { created_at_time: Some(created_at_time), ..transfer(to, amount) }
+    }
+
+    #[test]
+    fn duplicates_point_at_the_original_block() {
+        let mut state = ledger(0, None);
+        let args = dedup_transfer(account(2), 10, NOW);
+        let block = state.transfer(account(1), &args, NOW).unwrap();
+        state.transfer(account(1), &transfer(account(3), 10), NOW).unwrap();
+        assert_eq!(state.transfer(account(1), &args, NOW + 1), Err(TransferError::Duplicate { duplicate_of: Nat::from(block) }));
+        // Without created_at_time nothing is deduplicated
+        assert!(state.transfer(account(1), &transfer(account(2), 10), NOW).is_ok());
+        assert!(state.transfer(account(1), &transfer(account(2), 10), NOW).is_ok());
+        assert_eq!(state.balance_of(&account(2)), Nat::from(30u64));
+    }
+
+    #[test]
+    fn created_at_time_must_be_inside_the_window() {
+        let mut state = ledger(0, None);
+        let DedupConfig { tx_window, permitted_drift } = state.dedup.clone();
+        let oldest = NOW - tx_window - permitted_drift;
+        assert_eq!(state.transfer(account(1), &dedup_transfer(account(2), 1, oldest - 1), NOW), Err(TransferError::TooOld));
+        assert!(state.transfer(account(1), &dedup_transfer(account(2), 1, oldest), NOW).is_ok());
+        let newest = NOW + permitted_drift;
+        assert!(state.transfer(account(1), &dedup_transfer(account(2), 1, newest), NOW).is_ok());
+        assert_eq!(
+            state.transfer(account(1), &dedup_transfer(account(2), 1, newest + 1), NOW),
+            Err(TransferError::CreatedInFuture { ledger_time: NOW })
+        );
+    }
+
+    #[test]
+    fn failed_transfers_are_not_remembered() {
+        let mut state = ledger(0, None);
+        let args = dedup_transfer(account(3), 50, NOW);
+        assert!(matches!(state.transfer(account(2), &args, NOW), Err(TransferError::InsufficientFunds { .. })));
+        assert!(state.recent_transactions.by_hash.is_empty());
+        state.transfer(account(1), &transfer(account(2), 50), NOW).unwrap();
+        assert!(state.transfer(account(2), &args, NOW).is_ok());
+    }
+
+    #[test]
+    fn expired_dedup_entries_are_pruned() {
+        let mut recent = RecentTransactions::default();
+        for (time, byte) in [(10, 1), (20, 2), (30, 3)] {
+            recent.insert([byte; 32], time, time);
+        }
+        recent.prune(20);
+        assert_eq!(recent.by_time.iter().map(|(time, _)| *time).collect::<Vec<_>>(), vec![20, 30]);
+        assert!(!recent.by_hash.contains_key(&[1; 32]));
+        assert_eq!(recent.by_hash.len(), 2);
+
+        // Ledgers prune on every deduplicated operation
+        let mut state = ledger(0, None);
+        state.transfer(account(1), &dedup_transfer(account(2), 1, NOW), NOW).unwrap();
+        let window = state.dedup.tx_window + state.dedup.permitted_drift;
+        state.transfer(account(1), &dedup_transfer(account(2), 1, NOW + window), NOW + window + 1).unwrap();
+        assert_eq!(state.recent_transactions.by_hash.len(), 1);
+    }
+
+    #[test]
+    fn validates_dedup_windows() {
+        assert!(DedupConfig::default().validate().is_ok());
+        assert!(DedupConfig { tx_window: 0, permitted_drift: 0 }.validate().is_err());
+        assert!(DedupConfig { tx_window: MAX_DEDUP_WINDOW, permitted_drift: 0 }.validate().is_ok());
+        assert!(DedupConfig { tx_window: MAX_DEDUP_WINDOW, permitted_drift: 1 }.validate().is_err());
+        assert!(DedupConfig { tx_window: u64::MAX, permitted_drift: u64::MAX }.validate().is_err());
+    }
+
+    fn expiring_approve(spender: Account, amount: u64, expires_at: u64) -> ApproveArgs {
+        ApproveArgs { expires_at: Some(expires_at), ..approve(spender, amount) }
+    }
+
+    #[test]
+    fn approvals_cannot_expire_in_the_past() {
+        let mut state = ledger(0, None);
+        for expires_at in [NOW - 1, NOW] {
+            let result = state.approve(account(1), &expiring_approve(account(3), 10, expires_at), NOW);
+            assert_eq!(result, Err(TransferError::Expired { ledger_time: NOW }));
+        }
+        assert!(state.allowances.is_empty());
+        assert!(state.blocks.is_empty());
+    }
+
+    #[test]
+    fn expected_allowance_has_to_match() {
+        let mut state = ledger(0, None);
+        state.approve(account(1), &expiring_approve(account(3), 100, NOW + 10), NOW).unwrap();
+        let expecting = |expected: u64| ApproveArgs { expected_allowance: Some(Nat::from(expected)), ..approve(account(3), 5) };
+        assert_eq!(
+            state.approve(account(1), &expecting(99), NOW),
+            Err(TransferError::AllowanceChanged { current_allowance: Nat::from(100u64) })
+        );
+        // Once expired, the allowance counts as zero
+        assert!(state.approve(account(1), &expecting(100), NOW + 10).is_err());
+        assert!(state.approve(account(1), &expecting(0), NOW + 10).is_ok());
+        assert_eq!(state.allowance(&account(1), &account(3), NOW + 10).allowance, Nat::from(5u64));
+    }
+
+    #[test]
+    fn expired_allowances_cannot_be_spent() {
+        let mut state = ledger(0, None);
+        state.approve(account(1), &expiring_approve(account(3), 100, NOW + 10), NOW).unwrap();
+        state.transfer_from(account(3), &transfer_from(account(1), account(4), 40), NOW + 9).unwrap();
+        assert_eq!(
+            state.transfer_from(account(3), &transfer_from(account(1), account(4), 40), NOW + 10),
+            Err(TransferError::InsufficientAllowance { allowance: Nat::from(0u64) })
+        );
+        assert_eq!(state.balance_of(&account(4)), Nat::from(40u64));
+    }
+
+    #[test]
+    fn pruning_drops_expired_and_spent_allowances() {
+        let mut state = ledger(0, None);
+        state.approve(account(1), &expiring_approve(account(3), 100, NOW + 10), NOW).unwrap();
+        state.approve(account(1), &expiring_approve(account(4), 100, NOW + 20), NOW).unwrap();
+        state.approve(account(1), &approve(account(5), 100), NOW).unwrap();
+        state.approve(account(1), &approve(account(6), 0), NOW).unwrap();
+        state.prune_expired_allowances(NOW + 10);
+        let spenders: Vec<Account> = state.allowances.keys().map(|(_, spender)| spender.clone()).collect();
+        assert_eq!(spenders.len(), 2);
+        assert!(spenders.contains(&account(4)) && spenders.contains(&account(5)));
+    }
+
+    #[test]
+    fn approvals_are_recorded_as_blocks() {
+        let mut state = ledger(10, None);
+        let index = state.approve(account(1), &expiring_approve(account(3), 100, NOW + 10), NOW).unwrap();
+        let block = &state.blocks[index as usize];
+        assert_eq!(field(block, "btype"), Some(&ICRC3Value::Text("2approve".to_string())));
+        assert_eq!(field(block, "fee"), Some(&ICRC3Value::Nat(Nat::from(10u64))));
+        let tx = field(block, "tx").unwrap();
+        assert_eq!(field(tx, "from"), Some(&account_value(&account(1))));
+        assert_eq!(field(tx, "spender"), Some(&account_value(&account(3))));
+        assert_eq!(field(tx, "amt"), Some(&ICRC3Value::Nat(Nat::from(100u64))));
+        assert_eq!(field(tx, "expires_at"), Some(&ICRC3Value::Nat(Nat::from(NOW + 10))));
+    }
+
+    fn field<'a>(block: &'a ICRC3Value, key: &str) -> Option<&'a ICRC3Value> {
+        match block {
+            ICRC3Value::Map(entries) => entries.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn every_block_links_to_the_hash_of_the_previous_one() {
+        let mut state = ledger(10, None);
+        exercise(&mut state);
+        assert!(state.blocks.len() > 5);
+        assert_eq!(field(&state.blocks[0], "phash"), None);
+        for pair in state.blocks.windows(2) {
+            assert_eq!(field(&pair[1], "phash"), Some(&ICRC3Value::Blob(hash_value(&pair[0]).to_vec())));
+        }
+    }
+
+    // Token `ABC` of account(1), registered the way init_token does it
+    fn register_token() {
+        TOKEN_STATE.with(|token_state| {
+            let mut state = ledger(0, None);
+            state.metadata.symbol = "ABC".to_string();
+            token_state.borrow_mut().register(state).unwrap();
+        });
+    }
+
+    #[test]
+    fn only_the_owner_initializes_a_token() {
+        let metadata = |owner: Principal| Metadata {
+            symbol: "NEW".to_string(),
+            owner,
+            total_supply: Nat::from(10u64),
+            ..Default::default()
+        };
+        let stranger = account(2).owner;
+        assert!(matches!(init_token(stranger, metadata(account(1).owner)), Err(ApiError::Unauthorized(_))));
+        let anonymous = Principal::anonymous();
+        assert!(matches!(init_token(anonymous, metadata(anonymous)), Err(ApiError::Unauthorized(_))));
+        assert!(TOKEN_STATE.with(|token_state| !token_state.borrow().contains_key("NEW")));
+    }
+
+    #[test]
+    fn only_the_minting_account_owner_mints() {
+        register_token();
+        for caller in [account(2).owner, Principal::anonymous()] {
+            let result = mint_as(caller, account(2), Nat::from(5u64), "ABC".to_string());
+            assert!(matches!(result, Err(ApiError::Unauthorized(_))));
+        }
+        let supply = TOKEN_STATE.with(|token_state| token_state.borrow().get("ABC").unwrap().metadata.total_supply.clone());
+        assert_eq!(supply, Nat::from(1_000u64));
+        assert!(matches!(mint_as(account(1).owner, account(2), Nat::from(5u64), "NOPE".to_string()), Err(ApiError::NotFound(_))));
+    }
+    #[test]
+    fn writes_to_a_moved_token_point_at_its_ledger() {
+        register_token();
+        let ledger_id = Principal::from_slice(&[42]);
+        TOKEN_STATE.with(|token_state| token_state.borrow_mut().get_mut("ABC").unwrap().ledger_canister = Some(ledger_id));
+        let owner = account(1).owner;
+        assert_eq!(transfer_as(owner, "ABC".to_string(), transfer(account(2), 10)), Err(ApiError::Moved(ledger_id)));
+        assert_eq!(mint_as(owner, account(2), Nat::from(5u64), "ABC".to_string()), Err(ApiError::Moved(ledger_id)));
+        assert_eq!(burn_as(owner, "ABC".to_string(), burn(10)), Err(ApiError::Moved(ledger_id)));
+        let balance = TOKEN_STATE.with(|token_state| token_state.borrow().get("ABC").unwrap().balance_of(&account(1)));
+        assert_eq!(balance, Nat::from(1_000u64));
+    }
+
+    #[test]
+    fn strangers_cannot_move_the_owners_tokens() {
+        register_token();
+        // With a fee even an approval has to be paid for
+        TOKEN_STATE.with(|token_state| token_state.borrow_mut().get_mut("ABC").unwrap().metadata.fee = Nat::from(10u64));
+        let symbol = || "ABC".to_string();
+        let insufficient_funds = |result| matches!(result, Err(ApiError::Transfer(TransferError::InsufficientFunds { .. })));
+        for caller in [account(2).owner, Principal::anonymous()] {
+            assert!(insufficient_funds(transfer_as(caller, symbol(), transfer(account(2), 10))));
+            assert!(insufficient_funds(burn_as(caller, symbol(), burn(10))));
+            assert!(insufficient_funds(approve_as(caller, symbol(), approve(account(2), 10))));
+            let result = transfer_from_as(caller, symbol(), transfer_from(account(1), account(2), 10));
+            assert!(matches!(result, Err(ApiError::Transfer(TransferError::InsufficientAllowance { .. }))));
+        }
+        TOKEN_STATE.with(|token_state| {
+            let tokens = token_state.borrow();
+            let state = tokens.get("ABC").unwrap();
+            assert_eq!(state.balance_of(&account(1)), Nat::from(1_000u64));
+            assert!(state.allowances.is_empty());
+            assert!(state.blocks.is_empty());
+        });
+    }
+
+    #[test]
+    fn only_the_owner_administers_a_token() {
+        register_token();
+        let symbol = || "ABC".to_string();
+        let unauthorized = |result: Result<(), ApiError>| matches!(result, Err(ApiError::Unauthorized(_)));
+        for caller in [account(2).owner, Principal::anonymous()] {
+            let args = SetFeeCollectorArgs { fee_collector: Some(account(2)), created_at_time: None };
+            assert!(unauthorized(set_fee_collector_as(caller, symbol(), args).map(|_| ())));
+            let config = DedupConfig { tx_window: 1, permitted_drift: 1 };
+            assert!(unauthorized(set_dedup_config_as(caller, symbol(), config)));
+            for status in [TokenStatus::Frozen, TokenStatus::Active, TokenStatus::Retired] {
+                assert!(unauthorized(set_token_status(caller, "ABC", status)));
+            }
+        }
+        TOKEN_STATE.with(|token_state| {
+            let tokens = token_state.borrow();
+            let state = tokens.get("ABC").unwrap();
+            assert_eq!(state.fee_collector, None);
+            assert_eq!(state.dedup.tx_window, DedupConfig::default().tx_window);
+            assert_eq!(state.status, TokenStatus::Active);
+            assert!(state.blocks.is_empty());
+        });
+    }
+
+    #[test]
+    fn symbols_are_unique_regardless_of_case() {
+        let mut tokens = TokenRegistry::default();
+        let mut state = ledger(0, None);
+        state.metadata.symbol = "abc".to_string();
+        tokens.register(state).unwrap();
+        for symbol in ["abc", "ABC", "aBc"] {
+            assert!(matches!(tokens.check_symbol(symbol), Err(ApiError::AlreadyExists(_))));
+        }
+        assert_eq!(tokens.check_symbol("abcd"), Ok(()));
+    }
+
+    #[test]
+    fn reserved_and_malformed_symbols_are_rejected() {
+        let tokens = TokenRegistry::default();
+        for symbol in ["ICP", "icp", "ckBTC", "Cycles", "", "A B", "ABC!", &"X".repeat(MAX_SYMBOL_LEN + 1)] {
+            assert!(matches!(tokens.check_symbol(symbol), Err(ApiError::InvalidArgument(_))), "{:?}", symbol);
+        }
+        for symbol in ["ICPX", "my-token_2.0", &"X".repeat(MAX_SYMBOL_LEN)] {
+            assert_eq!(tokens.check_symbol(symbol), Ok(()), "{:?}", symbol);
+        }
+    }
+
+    #[test]
+    fn status_transitions_must_change_something() {
+        let mut state = ledger(0, None);
+        assert!(matches!(state.set_status(TokenStatus::Active), Err(ApiError::InvalidState(_))));
+        state.set_status(TokenStatus::Frozen).unwrap();
+        assert!(matches!(state.set_status(TokenStatus::Frozen), Err(ApiError::InvalidState(_))));
+        state.set_status(TokenStatus::Active).unwrap();
+        state.set_status(TokenStatus::Retired).unwrap();
+        for status in [TokenStatus::Active, TokenStatus::Frozen, TokenStatus::Retired] {
+            assert!(matches!(state.set_status(status), Err(ApiError::InvalidState(_))));
+        }
+        assert_eq!(state.status, TokenStatus::Retired);
+
+        let mut state = ledger(0, None);
+        state.ledger_canister = Some(account(9).owner);
+        assert!(matches!(state.set_status(TokenStatus::Frozen), Err(ApiError::InvalidState(_))));
+    }
+
+    #[test]
+    fn retired_tokens_refuse_every_write() {
+        let mut state = ledger(10, None);
+        state.approve(account(1), &approve(account(3), 100), NOW).unwrap();
+        state.set_status(TokenStatus::Retired).unwrap();
+        let retired = |result: Result<u64, TransferError>| {
+            matches!(result, Err(TransferError::GenericError { error_code, .. }) if error_code == 5u64)
+        };
+        assert!(retired(state.transfer(account(1), &transfer(account(2), 10), NOW)));
+        assert!(retired(state.approve(account(1), &approve(account(3), 10), NOW)));
+        assert!(retired(state.transfer_from(account(3), &transfer_from(account(1), account(2), 10), NOW)));
+        assert!(retired(state.burn_balance(account(1), &burn(10), NOW)));
+        assert!(retired(state.mint(account(2), Nat::from(10u64), NOW)));
+        assert_eq!(state.balance_of(&account(1)), Nat::from(990u64));
+        assert_eq!(state.blocks.len(), 1);
+    }
+}
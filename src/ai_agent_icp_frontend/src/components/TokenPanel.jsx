@@ -2,8 +2,18 @@ import { useState, useEffect } from 'react';
 import { ai_agent_icp_backend } from '../../../declarations/ai_agent_icp_backend';
 import { Principal } from '@dfinity/principal';
 
+// Canister endpoints return { Ok } or { Err: { <kind>: detail } }; plain values pass through
+const unwrap = (result) => {
+    if (result && typeof result === 'object' && 'Err' in result) {
+        const [kind, detail] = Object.entries(result.Err)[0];
+        throw new Error(typeof detail === 'string' ? `${kind}: ${detail}` : kind);
+    }
+    return result && typeof result === 'object' && 'Ok' in result ? result.Ok : result;
+};
+
 function TokenPanel() {
-    const [tokenStandard, setTokenStandard] = useState('ICRC1'); // 'ICRC1' or 'ICRC2'
+    // Every token lives in the canister's ICRC-2 ledger under its own symbol
+    const [selectedSymbol, setSelectedSymbol] = useState('');
 
     const [loading, setLoading] = useState(false);
     const [error, setError] = useState(null);
@@ -15,9 +25,9 @@ function TokenPanel() {
         console.log("Available methods:", Object.keys(ai_agent_icp_backend));
     }, []);
 
-    // Token standard selection
-    const handleStandardChange = (e) => {
-        setTokenStandard(e.target.value);
+    // Token selection
+    const handleSymbolChange = (e) => {
+        setSelectedSymbol(e.target.value);
     };
 
     // Token creation form state
@@ -30,14 +40,14 @@ function TokenPanel() {
         fee: '10000' // 0.0001 tokens with 8 decimals
     });
 
-    // Approve form state (ICRC2 only)
+    // Approve form state
     const [approveForm, setApproveForm] = useState({
         spender: '',
         amount: '',
         memo: ''
     });
 
-    // TransferFrom form state (ICRC2 only)
+    // TransferFrom form state
     const [transferFromForm, setTransferFromForm] = useState({
         from: '',
         to: '',
@@ -52,7 +62,7 @@ function TokenPanel() {
         memo: ''
     });
 
-    // Approve tokens (ICRC2 only)
+    // Approve tokens
     const handleApproveFormChange = (e) => {
         const { name, value } = e.target;
         setApproveForm({
@@ -81,7 +91,7 @@ function TokenPanel() {
                 memo: approveForm.memo ? [Array.from(new TextEncoder().encode(approveForm.memo))] : [],
                 created_at_time: []
             };
-            const result = await ai_agent_icp_backend.icrc2_approve(approveArgs);
+            unwrap(await ai_agent_icp_backend.icrc2_approve(approveArgs, tokenInfo.symbol));
             setSuccessMessage('Approved successfully!');
         } catch (err) {
             setError('Failed to approve: ' + (err.message || err.toString()));
@@ -90,7 +100,7 @@ function TokenPanel() {
         }
     };
 
-    // Transfer from (ICRC2 only)
+    // Transfer from
     const handleTransferFromFormChange = (e) => {
         const { name, value } = e.target;
         setTransferFromForm({
@@ -122,7 +132,7 @@ function TokenPanel() {
                 memo: transferFromForm.memo ? [Array.from(new TextEncoder().encode(transferFromForm.memo))] : [],
                 created_at_time: []
             };
-            const result = await ai_agent_icp_backend.icrc2_transfer_from(transferFromArgs);
+            unwrap(await ai_agent_icp_backend.icrc2_transfer_from(transferFromArgs, tokenInfo.symbol));
             setSuccessMessage('Transfer from successful!');
         } catch (err) {
             setError('Failed to transfer from: ' + (err.message || err.toString()));
@@ -147,28 +157,21 @@ function TokenPanel() {
         balance: '0'
     });
 
-    // Load token info on component mount
-    useEffect(() => {
-        checkTokenStatus();
-    }, []);
-
-    const checkTokenStatus = async () => {
-        // Use correct backend methods based on selected standard
-        const prefix = tokenStandard === 'ICRC2' ? 'icrc2_' : 'icrc1_';
+    const checkTokenStatus = async (tokenSymbol = selectedSymbol) => {
         try {
             setLoading(true);
             setError(null);
 
-            // Try to get the token name to check if it's initialized
-            const name = await ai_agent_icp_backend[`${prefix}name`]();
+            // Try to get the token name to check if it exists
+            const name = unwrap(await ai_agent_icp_backend.icrc2_name(tokenSymbol));
 
             // If we get here, token is initialized
-            const [symbol, decimals, totalSupply, fee] = await Promise.all([
-                ai_agent_icp_backend[`${prefix}symbol`](),
-                ai_agent_icp_backend[`${prefix}decimals`](),
-                ai_agent_icp_backend[`${prefix}total_supply`](),
-                ai_agent_icp_backend[`${prefix}fee`](),
-            ]);
+            const [symbol, decimals, totalSupply, fee] = (await Promise.all([
+                ai_agent_icp_backend.icrc2_symbol(tokenSymbol),
+                ai_agent_icp_backend.icrc2_decimals(tokenSymbol),
+                ai_agent_icp_backend.icrc2_total_supply(tokenSymbol),
+                ai_agent_icp_backend.icrc2_fee(tokenSymbol),
+            ])).map(unwrap);
 
             setTokenInfo({
                 initialized: true,
@@ -188,7 +191,7 @@ function TokenPanel() {
                         principal: principal.toString()
                     });
                     // Get balance for this principal
-                    await getBalance(principal);
+                    await getBalance(principal, symbol);
                 }
             } catch (err) {
                 console.error("Could not get principal:", err);
@@ -210,15 +213,14 @@ function TokenPanel() {
         return Principal.fromText('2vxsx-fae'); // Anonymous principal
     };
 
-    const getBalance = async (principal) => {
-        const prefix = tokenStandard === 'ICRC2' ? 'icrc2_' : 'icrc1_';
+    const getBalance = async (principal, tokenSymbol = tokenInfo.symbol) => {
         try {
             const account = {
                 owner: principal,
                 subaccount: []
             };
 
-            const balance = await ai_agent_icp_backend[`${prefix}balance_of`](account);
+            const balance = unwrap(await ai_agent_icp_backend.icrc2_balance_of(account, tokenSymbol));
             setAccount({
                 ...account,
                 balance: balance.toString()
@@ -245,43 +247,40 @@ function TokenPanel() {
     };
 
     const initializeToken = async (e) => {
-        // Use correct init method
-        const prefix = tokenStandard === 'ICRC2' ? 'icrc2_' : 'icrc1_';
         e.preventDefault();
 
         try {
             setLoading(true);
             setError(null);
 
-            const result = await ai_agent_icp_backend[`${prefix}init`] (
+            const symbol = tokenForm.symbol;
+            unwrap(await ai_agent_icp_backend.icrc2_init(
                 tokenForm.name,
-                tokenForm.symbol,
+                symbol,
                 Number(tokenForm.decimals),
                 [tokenForm.description], // Opt<String>
                 tokenForm.logo ? [tokenForm.logo] : [],
                 BigInt(tokenForm.initialSupply),
+                await getPrincipal(), // owner
                 BigInt(tokenForm.fee)
-            );
-
-            if (result) {
-                setSuccessMessage(`Token "${tokenForm.name}" (${tokenForm.symbol}) created successfully!`);
-                // Clear success message after 5 seconds
-                setTimeout(() => setSuccessMessage(''), 5000);
-
-                // Reset form and refresh token info
-                setTokenForm({
-                    name: '',
-                    symbol: '',
-                    decimals: 8,
-                    description: '',
-                    initialSupply: '10000000000000',
-                    fee: '10000'
-                });
+            ));
+
+            setSuccessMessage(`Token "${tokenForm.name}" (${symbol}) created successfully!`);
+            // Clear success message after 5 seconds
+            setTimeout(() => setSuccessMessage(''), 5000);
+
+            // Reset form and show the new token
+            setTokenForm({
+                name: '',
+                symbol: '',
+                decimals: 8,
+                description: '',
+                initialSupply: '10000000000000',
+                fee: '10000'
+            });
 
-                await checkTokenStatus();
-            } else {
-                setError("Failed to initialize token");
-            }
+            setSelectedSymbol(symbol);
+            await checkTokenStatus(symbol);
         } catch (err) {
             setError(`Failed to initialize token: ${err.message || err.toString()}`);
             console.error(err);
@@ -291,7 +290,6 @@ function TokenPanel() {
     };
 
     const transferTokens = async (e) => {
-        const prefix = tokenStandard === 'ICRC2' ? 'icrc2_' : 'icrc1_';
         e.preventDefault();
 
         try {
@@ -316,11 +314,12 @@ function TokenPanel() {
                 amount: BigInt(transferForm.amount),
                 fee: [BigInt(tokenInfo.fee)],
                 memo: transferForm.memo ? [Array.from(new TextEncoder().encode(transferForm.memo))] : [],
-                created_at_time: [BigInt(Math.floor(Date.now() / 1000))]
+                // The ledger deduplicates on nanoseconds
+                created_at_time: [BigInt(Date.now()) * 1_000_000n]
             };
 
             // Execute transfer
-            const result = await ai_agent_icp_backend[`${prefix}transfer`](transferArgs);
+            const result = await ai_agent_icp_backend.icrc2_transfer(tokenInfo.symbol, transferArgs);
 
             if ('Ok' in result) {
                 setSuccessMessage(`Transfer successful! Transaction ID: ${result.Ok}`);
@@ -373,11 +372,15 @@ function TokenPanel() {
             <h1>ICRC Token Management</h1>
 
             <div className="token-standard">
-                <label>Token Standard:</label>
-                <select value={tokenStandard} onChange={handleStandardChange}>
-                    <option value="ICRC1">ICRC-1</option>
-                    <option value="ICRC2">ICRC-2</option>
-                </select>
+                <label htmlFor="selectedSymbol">Token:</label>
+                <input
+                    type="text"
+                    id="selectedSymbol"
+                    value={selectedSymbol}
+                    onChange={handleSymbolChange}
+                    placeholder="TKN"
+                />
+                <button onClick={() => checkTokenStatus()} disabled={loading || !selectedSymbol}>Load</button>
             </div>
 
             {error && <div className="error">{error}</div>}
@@ -504,7 +507,7 @@ function TokenPanel() {
                         <h2>Your Account</h2>
                         <p><strong>Principal:</strong> {account.principal}</p>
                         <p><strong>Balance:</strong> {formatTokenAmount(account.balance, tokenInfo.decimals)} {tokenInfo.symbol}</p>
-                        <button onClick={checkTokenStatus}>Refresh</button>
+                        <button onClick={() => checkTokenStatus(tokenInfo.symbol)}>Refresh</button>
                     </div>
 
                     <div className="token-transfer">
@@ -553,114 +556,112 @@ function TokenPanel() {
                         </form>
                     </div>
 
-                    {tokenStandard === 'ICRC2' && (
-                        <div>
-                            <div className="approve-tokens">
-                                <h2>Approve Tokens</h2>
-                                <form onSubmit={approveTokens}>
-                                    <div>
-                                        <label htmlFor="spender">Spender Principal:</label>
-                                        <input
-                                            type="text"
-                                            id="spender"
-                                            name="spender"
-                                            value={approveForm.spender}
-                                            onChange={handleApproveFormChange}
-                                            placeholder="aaaaa-bbbbb-ccccc"
-                                            required
-                                        />
-                                    </div>
-
-                                    <div>
-                                        <label htmlFor="amount">Amount (raw):</label>
-                                        <input
-                                            type="text"
-                                            id="amount"
-                                            name="amount"
-                                            value={approveForm.amount}
-                                            onChange={handleApproveFormChange}
-                                            placeholder="100000000"
-                                            required
-                                        />
-                                        <small>Enter the raw amount (100000000 = 1 token with 8 decimals)</small>
-                                    </div>
-
-                                    <div>
-                                        <label htmlFor="memo">Memo (Optional):</label>
-                                        <input
-                                            type="text"
-                                            id="memo"
-                                            name="memo"
-                                            value={approveForm.memo}
-                                            onChange={handleApproveFormChange}
-                                            placeholder="Payment for services"
-                                        />
-                                    </div>
-
-                                    <button type="submit" disabled={loading}>Approve</button>
-                                </form>
-                            </div>
+                    <div>
+                        <div className="approve-tokens">
+                            <h2>Approve Tokens</h2>
+                            <form onSubmit={approveTokens}>
+                                <div>
+                                    <label htmlFor="spender">Spender Principal:</label>
+                                    <input
+                                        type="text"
+                                        id="spender"
+                                        name="spender"
+                                        value={approveForm.spender}
+                                        onChange={handleApproveFormChange}
+                                        placeholder="aaaaa-bbbbb-ccccc"
+                                        required
+                                    />
+                                </div>
+
+                                <div>
+                                    <label htmlFor="amount">Amount (raw):</label>
+                                    <input
+                                        type="text"
+                                        id="amount"
+                                        name="amount"
+                                        value={approveForm.amount}
+                                        onChange={handleApproveFormChange}
+                                        placeholder="100000000"
+                                        required
+                                    />
+                                    <small>Enter the raw amount (100000000 = 1 token with 8 decimals)</small>
+                                </div>
+
+                                <div>
+                                    <label htmlFor="memo">Memo (Optional):</label>
+                                    <input
+                                        type="text"
+                                        id="memo"
+                                        name="memo"
+                                        value={approveForm.memo}
+                                        onChange={handleApproveFormChange}
+                                        placeholder="Payment for services"
+                                    />
+                                </div>
+
+                                <button type="submit" disabled={loading}>Approve</button>
+                            </form>
+                        </div>
 
-                            <div className="transfer-from-tokens">
-                                <h2>Transfer From Tokens</h2>
-                                <form onSubmit={transferFromTokens}>
-                                    <div>
-                                        <label htmlFor="from">From Principal:</label>
-                                        <input
-                                            type="text"
-                                            id="from"
-                                            name="from"
-                                            value={transferFromForm.from}
-                                            onChange={handleTransferFromFormChange}
-                                            placeholder="aaaaa-bbbbb-ccccc"
-                                            required
-                                        />
-                                    </div>
-
-                                    <div>
-                                        <label htmlFor="to">To Principal:</label>
-                                        <input
-                                            type="text"
-                                            id="to"
-                                            name="to"
-                                            value={transferFromForm.to}
-                                            onChange={handleTransferFromFormChange}
-                                            placeholder="aaaaa-bbbbb-ccccc"
-                                            required
-                                        />
-                                    </div>
-
-                                    <div>
-                                        <label htmlFor="amount">Amount (raw):</label>
-                                        <input
-                                            type="text"
-                                            id="amount"
-                                            name="amount"
-                                            value={transferFromForm.amount}
-                                            onChange={handleTransferFromFormChange}
-                                            placeholder="100000000"
-                                            required
-                                        />
-                                        <small>Enter the raw amount (100000000 = 1 token with 8 decimals)</small>
-                                    </div>
-
-                                    <div>
-                                        <label htmlFor="memo">Memo (Optional):</label>
-                                        <input
-                                            type="text"
-                                            id="memo"
-                                            name="memo"
-                                            value={transferFromForm.memo}
-                                            onChange={handleTransferFromFormChange}
-                                            placeholder="Payment for services"
-                                        />
-                                    </div>
-
-                                    <button type="submit" disabled={loading}>Transfer From</button>
-                                </form>
-                            </div>
+                        <div className="transfer-from-tokens">
+                            <h2>Transfer From Tokens</h2>
+                            <form onSubmit={transferFromTokens}>
+                                <div>
+                                    <label htmlFor="from">From Principal:</label>
+                                    <input
+                                        type="text"
+                                        id="from"
+                                        name="from"
+                                        value={transferFromForm.from}
+                                        onChange={handleTransferFromFormChange}
+                                        placeholder="aaaaa-bbbbb-ccccc"
+                                        required
+                                    />
+                                </div>
+
+                                <div>
+                                    <label htmlFor="to">To Principal:</label>
+                                    <input
+                                        type="text"
+                                        id="to"
+                                        name="to"
+                                        value={transferFromForm.to}
+                                        onChange={handleTransferFromFormChange}
+                                        placeholder="aaaaa-bbbbb-ccccc"
+                                        required
+                                    />
+                                </div>
+
+                                <div>
+                                    <label htmlFor="amount">Amount (raw):</label>
+                                    <input
+                                        type="text"
+                                        id="amount"
+                                        name="amount"
+                                        value={transferFromForm.amount}
+                                        onChange={handleTransferFromFormChange}
+                                        placeholder="100000000"
+                                        required
+                                    />
+                                    <small>Enter the raw amount (100000000 = 1 token with 8 decimals)</small>
+                                </div>
+
+                                <div>
+                                    <label htmlFor="memo">Memo (Optional):</label>
+                                    <input
+                                        type="text"
+                                        id="memo"
+                                        name="memo"
+                                        value={transferFromForm.memo}
+                                        onChange={handleTransferFromFormChange}
+                                        placeholder="Payment for services"
+                                    />
+                                </div>
+
+                                <button type="submit" disabled={loading}>Transfer From</button>
+                            </form>
                         </div>
-                    )}
+                    </div>
                 </div>
             )}
         </div>
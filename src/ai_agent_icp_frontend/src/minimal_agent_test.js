@@ -3,13 +3,28 @@ import { ai_agent_icp_backend } from 'declarations/ai_agent_icp_backend';
 
 let agent;
 
+// Tasks live on a per-owner agent, created with create_agent and initialize_agent
+function agentId() {
+  return BigInt(document.getElementById("agent-id").value || 0);
+}
+
+// Endpoints return { Ok } or { Err: { <kind>: message } }
+function unwrap(result) {
+  if ("Err" in result) {
+    const [kind, message] = Object.entries(result.Err)[0];
+    throw new Error(typeof message === "string" ? `${kind}: ${message}` : kind);
+  }
+  return result.Ok;
+}
+
 async function initAgent() {
   agent = createActor(process.env.CANISTER_ID_AI_AGENT_ICP_BACKEND);
 }
 
-async function createTask(id, data, frequency) {
+// The canister picks the task ID; the ID argument is ignored
+async function createTask(data, frequency) {
   try {
-    await agent.create_task(BigInt(id), data, BigInt(frequency));
+    const id = unwrap(await agent.create_task(agentId(), 0n, data, BigInt(frequency)));
     console.log(`Task created: ID ${id}, Data: ${data}, Frequency: ${frequency}`);
     await listTasks();
   } catch (error) {
@@ -19,7 +34,7 @@ async function createTask(id, data, frequency) {
 
 async function listTasks() {
   try {
-    const tasks = await agent.get_tasks();
+    const tasks = unwrap(await agent.get_tasks(agentId()));
     const list = document.getElementById("task-list");
     list.innerHTML = "";
 
@@ -32,7 +47,7 @@ async function listTasks() {
 
     tasks.forEach((t) => {
       const li = document.createElement("li");
-      li.textContent = `ID: ${t.id.toString()}, Data: ${t.data}, Frequency: ${t.frequency.toString()} seconds, Last Run: ${t.last_run.toString()}`;
+      li.textContent = `ID: ${t.id.toString()}, Action: ${Object.keys(t.action)[0]}, Frequency: ${t.frequency.toString()} seconds, Last Run: ${t.last_run.toString()}`;
 
       // Add delete button
       const deleteBtn = document.createElement("button");
@@ -51,7 +66,7 @@ async function listTasks() {
 
 async function deleteTask(id) {
   try {
-    await agent.delete_task(id);
+    unwrap(await agent.delete_task(id));
     console.log(`Task deleted: ID ${id}`);
     await listTasks();
   } catch (error) {
@@ -61,17 +76,18 @@ async function deleteTask(id) {
 
 async function getTaskById(id) {
   try {
-    const task = await agent.get_task(BigInt(id));
-    if (task) {
-      console.log(`Task found: ID ${id}, Data: ${task.data}, Frequency: ${task.frequency.toString()} seconds, Last Run: ${task.last_run.toString()}`);
+    const result = await agent.get_task(BigInt(id));
+    if ("Ok" in result) {
+      const task = result.Ok;
+      console.log(`Task found: ID ${id}, Action: ${Object.keys(task.action)[0]}, Frequency: ${task.frequency.toString()} seconds, Last Run: ${task.last_run.toString()}`);
       // You can also display this task in the UI if needed
       const list = document.getElementById("task-list");
       list.innerHTML = "";  // Clear the existing list
       const li = document.createElement("li");
-      li.textContent = `ID: ${task.id.toString()}, Data: ${task.data}, Frequency: ${task.frequency.toString()} seconds, Last Run: ${task.last_run.toString()}`;
+      li.textContent = `ID: ${task.id.toString()}, Action: ${Object.keys(task.action)[0]}, Frequency: ${task.frequency.toString()} seconds, Last Run: ${task.last_run.toString()}`;
       list.appendChild(li);
     } else {
-      console.log(`Task with ID ${id} not found.`);
+      console.log(`Task with ID ${id}: ${Object.values(result.Err)[0]}`);
     }
   } catch (error) {
     console.error("Error fetching task:", error);
@@ -84,16 +100,15 @@ document.addEventListener("DOMContentLoaded", async () => {
   await listTasks();
 
   document.getElementById("create-task-btn").onclick = async () => {
-    const id = document.getElementById("task-id").value;
     const data = document.getElementById("task-data").value;
     const freq = document.getElementById("task-freq").value;
 
-    if (!id || !data || !freq) {
-      alert("Please fill in all fields");
+    if (!data || !freq) {
+      alert("Please fill in the task data and frequency");
       return;
     }
 
-    await createTask(id, data, freq);
+    await createTask(data, freq);
   };
 
   document.getElementById("refresh-tasks-btn").onclick = listTasks;
@@ -61,14 +61,18 @@ const User = () => {
 
     try {
       const aiResponse = await sendPrompt(prompt, principal?.toText?.());
-      type AIResponseItem = { Text?: string; PairList?: [string, string][] };
+      // Tool results are canister `Result`s: { Ok: value } or { Err: { <kind>: message } }
+      type AIResponseItem = { Ok?: unknown; Err?: Record<string, unknown> };
       const firstItem = aiResponse ? (aiResponse[0] as AIResponseItem) : null;
 
       let reply = "";
-      if (firstItem?.Text) {
-        reply = firstItem.Text;
-      } else if (firstItem?.PairList) {
-        reply = firstItem.PairList.map(([k, v]) => `${k}: ${v}`).join("\n");
+      if (firstItem?.Err) {
+        const [kind, detail] = Object.entries(firstItem.Err)[0] ?? ["Error", ""];
+        reply = `${kind}: ${typeof detail === "string" ? detail : JSON.stringify(detail)}`;
+      } else if (Array.isArray(firstItem?.Ok)) {
+        reply = (firstItem.Ok as [string, string][]).map(([k, v]) => `${k}: ${v}`).join("\n");
+      } else if (firstItem && "Ok" in firstItem) {
+        reply = firstItem.Ok === null ? "Done" : String(firstItem.Ok);
       } else {
         reply = JSON.stringify(aiResponse);
       }